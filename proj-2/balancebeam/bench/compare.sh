@@ -0,0 +1,78 @@
+#!/usr/bin/env bash
+# Compares the proxy's throughput and latency at the working tree against another revision, using
+# `balancebeam bench` from the working tree to drive both. See bench/README.md.
+#
+# Usage: bench/compare.sh <base revision> [runs per scenario]
+set -euo pipefail
+
+if [ $# -lt 1 ]; then
+    echo "Usage: $0 <base revision> [runs per scenario]" >&2
+    exit 1
+fi
+base=$1
+runs=${2:-5}
+crate=$(cd "$(dirname "$0")/.." && pwd)
+repo=$(git -C "$crate" rev-parse --show-toplevel)
+crate_in_repo=${crate#"$repo"/}
+work=$(mktemp -d)
+trap 'git -C "$repo" worktree remove --force "$work/base" >/dev/null 2>&1 || true; rm -rf "$work"' EXIT
+
+echo "Building $base and the working tree (release)" >&2
+git -C "$repo" worktree add --detach "$work/base" "$base" >/dev/null
+cargo build --release --quiet --manifest-path "$work/base/$crate_in_repo/Cargo.toml" \
+    --target-dir "$crate/target/compare-base"
+cargo build --release --quiet --manifest-path "$crate/Cargo.toml"
+base_binary=$crate/target/compare-base/release/balancebeam
+bench=$crate/target/release/balancebeam
+
+echo '{"outlier_detection": {"latency_factor": 3, "slow_start_ms": 2000}}' > "$work/outlier.json"
+scenarios=(
+    "keep-alive|"
+    "close|--close"
+    "outlier detection|--close -- --config $work/outlier.json"
+    "flapping|--close --flapping-upstreams 2 --health-check-delay-ms 500 -- --active-health-check-interval 1"
+)
+
+# Prints a field of each of the lines of bench's JSON output on stdin
+field() {
+    sed -n "s/.*\"$1\": *\([0-9.]*\).*/\1/p"
+}
+
+# Prints the median of the numbers on stdin
+median() {
+    sort -g | awk '{ values[NR] = $1 } END { print values[int((NR + 1) / 2)] }'
+}
+
+# Runs bench against one of the two builds, printing its JSON results. The proxy's own log lines
+# (e.g. about flapping upstreams) go to a file, so that they don't get mixed into the table.
+run() {
+    local build=$1
+    shift
+    if [ "$build" = base ]; then
+        "$bench" bench --json --proxy-binary "$base_binary" "$@" 2>> "$work/proxy.log"
+    else
+        "$bench" bench --json "$@" 2>> "$work/proxy.log"
+    fi
+}
+
+printf '%-18s %-5s %12s %10s %8s\n' scenario build requests/s p99_us errors
+for scenario in "${scenarios[@]}"; do
+    name=${scenario%%|*}
+    read -r -a args <<< "${scenario#*|}"
+    : > "$work/base.json"
+    : > "$work/head.json"
+    # Alternate between the builds, taking turns to go first, so that both see the same drift in
+    # the machine's load (and the same leftovers, such as sockets in TIME_WAIT, of the run before)
+    for i in $(seq "$runs"); do
+        if [ $((i % 2)) = 1 ]; then order="base head"; else order="head base"; fi
+        for build in $order; do
+            run "$build" "${args[@]}" >> "$work/$build.json"
+        done
+    done
+    for build in base head; do
+        printf '%-18s %-5s %12.0f %10s %8s\n' "$name" "$build" \
+            "$(field requests_per_sec < "$work/$build.json" | median)" \
+            "$(field p99 < "$work/$build.json" | median)" \
+            "$(field errors < "$work/$build.json" | median)"
+    done
+done
@@ -0,0 +1,192 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Written in a list of clients to mean clients connected over a Unix domain socket
+const UNIX: &str = "unix";
+
+/// Who a connection comes from: a client at an IP address, or a process on this machine that
+/// connected over a Unix domain socket, which has no IP address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Client {
+    Ip(IpAddr),
+    Unix,
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Ip(ip) => ip.fmt(f),
+            Client::Unix => f.write_str(UNIX),
+        }
+    }
+}
+
+/// A network in CIDR notation (e.g. "10.0.0.0/8" or "2001:db8::/32"). A bare address such as
+/// "192.0.2.1" is treated as a network containing just that address.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr(IpNet);
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(net) = value.parse::<IpNet>() {
+            Ok(Cidr(net.trunc()))
+        } else if let Ok(addr) = value.parse::<IpAddr>() {
+            Ok(Cidr(IpNet::from(addr)))
+        } else {
+            Err(format!("invalid CIDR network \"{}\"", value))
+        }
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients connecting over IPv4 to a dual-stack socket show up as IPv4-mapped IPv6
+        // addresses; compare those as the IPv4 addresses they are
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+/// An entry in a list of clients: a network, or "unix" for clients connected over a Unix domain
+/// socket. Those aren't in any network (not even 127.0.0.0/8), so a list has to name them to
+/// include them.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Source {
+    Network(Cidr),
+    Unix,
+}
+
+impl TryFrom<String> for Source {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value == UNIX {
+            Ok(Source::Unix)
+        } else {
+            Cidr::try_from(value).map(Source::Network)
+        }
+    }
+}
+
+impl Source {
+    pub fn matches(&self, client: Client) -> bool {
+        match (self, client) {
+            (Source::Network(net), Client::Ip(ip)) => net.contains(ip),
+            (Source::Unix, Client::Unix) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Lists of clients that may or may not use the proxy.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessList {
+    /// If non-empty, only these clients are let through
+    pub allow: Vec<Source>,
+    /// These clients are turned away, even if they are also allowed
+    pub deny: Vec<Source>,
+}
+
+impl AccessList {
+    /// Returns whether the given client passes this access list
+    pub fn permits(&self, client: Client) -> bool {
+        if self.deny.iter().any(|source| source.matches(client)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|source| source.matches(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::try_from(value.to_string()).unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn whole_address_space() {
+        let v4 = cidr("0.0.0.0/0");
+        assert!(v4.contains(ip("0.0.0.0")));
+        assert!(v4.contains(ip("255.255.255.255")));
+        assert!(!v4.contains(ip("::1")));
+        let v6 = cidr("::/0");
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn single_address() {
+        for net in [cidr("192.0.2.1/32"), cidr("192.0.2.1")] {
+            assert!(net.contains(ip("192.0.2.1")));
+            assert!(!net.contains(ip("192.0.2.0")));
+            assert!(!net.contains(ip("192.0.2.2")));
+        }
+        let net = cidr("2001:db8::1");
+        assert!(net.contains(ip("2001:db8::1")));
+        assert!(!net.contains(ip("2001:db8::2")));
+    }
+
+    /// Host bits are dropped, so "10.1.2.3/8" means all of 10.0.0.0/8
+    #[test]
+    fn host_bits() {
+        assert_eq!(cidr("10.1.2.3/8"), cidr("10.0.0.0/8"));
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+    }
+
+    /// IPv4 clients on a dual-stack socket are matched against IPv4 networks
+    #[test]
+    fn ipv4_mapped_ipv6() {
+        let net = cidr("192.0.2.0/24");
+        assert!(net.contains(ip("::ffff:192.0.2.7")));
+        assert!(!net.contains(ip("::ffff:198.51.100.7")));
+        assert!(cidr("0.0.0.0/0").contains(ip("::ffff:192.0.2.7")));
+    }
+
+    #[test]
+    fn invalid() {
+        for value in ["", "unix", "10.0.0.0/33", "::/129", "10.0.0", "example.com"] {
+            assert!(Cidr::try_from(value.to_string()).is_err(), "{}", value);
+        }
+    }
+
+    /// Unix domain socket clients only match "unix", and no network does
+    #[test]
+    fn sources() {
+        let unix = Source::try_from("unix".to_string()).unwrap();
+        let loopback = Source::try_from("127.0.0.0/8".to_string()).unwrap();
+        let everything = Source::try_from("0.0.0.0/0".to_string()).unwrap();
+        assert!(unix.matches(Client::Unix));
+        assert!(!unix.matches(Client::Ip(ip("127.0.0.1"))));
+        assert!(loopback.matches(Client::Ip(ip("127.0.0.1"))));
+        assert!(!loopback.matches(Client::Unix));
+        assert!(!everything.matches(Client::Unix));
+    }
+
+    #[test]
+    fn access_list() {
+        let list: AccessList =
+            serde_json::from_str(r#"{"allow": ["10.0.0.0/8", "unix"], "deny": ["10.0.0.1"]}"#)
+                .unwrap();
+        assert!(list.permits(Client::Ip(ip("10.0.0.2"))));
+        assert!(list.permits(Client::Unix));
+        assert!(!list.permits(Client::Ip(ip("10.0.0.1"))));
+        assert!(!list.permits(Client::Ip(ip("192.0.2.1"))));
+
+        let deny_only: AccessList = serde_json::from_str(r#"{"deny": ["10.0.0.0/8"]}"#).unwrap();
+        assert!(deny_only.permits(Client::Ip(ip("192.0.2.1"))));
+        assert!(deny_only.permits(Client::Unix));
+        assert!(!deny_only.permits(Client::Ip(ip("10.0.0.1"))));
+    }
+}
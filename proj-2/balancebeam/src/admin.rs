@@ -0,0 +1,153 @@
+use crate::limits::MessageLimits;
+use crate::stream::{Listener, Stream};
+use crate::{request, response, ProxyState};
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::BufReader;
+
+/// Serves the admin interface on its own listener (--admin-bind), apart from proxied traffic:
+///
+/// * GET /metrics: per-upstream metrics, in the Prometheus text format
+/// * GET /maintenance: whether maintenance mode is on, as {"enabled": true/false}
+/// * PUT /maintenance, DELETE /maintenance: switch maintenance mode on and off
+///
+/// Stops accepting connections when balancebeam shuts down.
+pub async fn serve(listener: Listener, state: Arc<ProxyState>) {
+    let mut shutdown = state.shutdown.subscribe();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait_for(|shutting_down| *shutting_down) => return,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &state).await;
+                });
+            }
+            Err(err) => log::warn!("Failed to accept admin connection: {}", err),
+        }
+    }
+}
+
+async fn handle_connection(stream: Stream, state: &ProxyState) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let request =
+            match request::read_from_stream(&mut stream, &MessageLimits::default(), |_| {
+                MessageLimits::default()
+            })
+            .await
+            {
+                Ok(request) => request,
+                Err(request::Error::IncompleteRequest(0)) => return,
+                Err(error) => {
+                    log::debug!("Error reading admin request: {:?}", error);
+                    let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                    let _ = response::write_to_stream(&response, &mut stream).await;
+                    return;
+                }
+            };
+        let response = match (request.method(), request.uri().path()) {
+            (&http::Method::GET, "/metrics") => text_response(render_metrics(state).await),
+            (_, "/metrics") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+            (&http::Method::GET, "/maintenance") => maintenance_response(state),
+            (&http::Method::PUT, "/maintenance") => {
+                log::warn!("Maintenance mode switched on through the admin interface");
+                state.maintenance.store(true, Ordering::SeqCst);
+                maintenance_response(state)
+            }
+            (&http::Method::DELETE, "/maintenance") => {
+                log::warn!("Maintenance mode switched off through the admin interface");
+                state.maintenance.store(false, Ordering::SeqCst);
+                maintenance_response(state)
+            }
+            (_, "/maintenance") => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+            _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+        };
+        if let Err(error) = response::write_to_stream(&response, &mut stream).await {
+            log::debug!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+fn maintenance_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = serde_json::json!({"enabled": state.maintenance.load(Ordering::SeqCst)});
+    response::make_response(
+        http::StatusCode::OK,
+        "application/json",
+        body.to_string().into_bytes(),
+    )
+}
+
+fn text_response(body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+async fn render_metrics(state: &ProxyState) -> String {
+    let upstreams = state.upstreams.snapshot();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn Fn(usize) -> String| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (i, upstream) in upstreams.iter().enumerate() {
+            writeln!(
+                out,
+                "{}{{upstream=\"{}\",group=\"{}\"}} {}",
+                name,
+                upstream.address(),
+                upstream.group(),
+                value(i)
+            )
+            .unwrap();
+        }
+    };
+    metric(
+        "balancebeam_upstream_healthy",
+        "gauge",
+        "Whether the upstream is in the pool (1) or has failed (0)",
+        &|i| (upstreams[i].is_healthy() as u8).to_string(),
+    );
+    metric(
+        "balancebeam_upstream_active_connections",
+        "gauge",
+        "Client connections currently proxied to the upstream",
+        &|i| upstreams[i].active_connections().to_string(),
+    );
+    metric(
+        "balancebeam_upstream_max_connections",
+        "gauge",
+        "Most connections allowed to the upstream at once (0 = unlimited)",
+        &|i| upstreams[i].max_connections.to_string(),
+    );
+    metric(
+        "balancebeam_upstream_queue_depth",
+        "gauge",
+        "Client connections waiting for a free connection slot on the upstream",
+        &|i| upstreams[i].queued().to_string(),
+    );
+    metric(
+        "balancebeam_upstream_queue_rejections_total",
+        "counter",
+        "Client connections turned away because the upstream's queue was full",
+        &|i| upstreams[i].queue_rejections().to_string(),
+    );
+    metric(
+        "balancebeam_upstream_queue_timeouts_total",
+        "counter",
+        "Client connections that gave up waiting for a free connection slot",
+        &|i| upstreams[i].queue_timeouts().to_string(),
+    );
+    out
+}
@@ -0,0 +1,425 @@
+use crate::request_id;
+use base64::Engine;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The htpasswd file or a key file couldn't be read
+    UnreadableFile(
+        #[allow(dead_code)] String,
+        #[allow(dead_code)] std::io::Error,
+    ),
+    /// A line of the htpasswd file isn't of the form user:hash
+    InvalidHtpasswdLine(#[allow(dead_code)] String),
+    /// A user's password is hashed in a way we can't check (only bcrypt and {SHA} are supported)
+    UnsupportedHash(#[allow(dead_code)] String),
+    /// The basic auth realm contains something other than visible ASCII characters and spaces,
+    /// which can't be put in a WWW-Authenticate header
+    InvalidRealm(#[allow(dead_code)] String),
+    /// JWTs may only be signed with HS256 or RS256
+    UnsupportedAlgorithm(#[allow(dead_code)] Algorithm),
+    /// The JWT settings lack the secret (HS256) or public key (RS256) to check signatures with
+    MissingKey,
+    /// The JWT public key isn't a valid PEM-encoded RSA key
+    InvalidKey(#[allow(dead_code)] jsonwebtoken::errors::Error),
+}
+
+/// Why a request was turned away
+#[derive(Debug)]
+pub enum Rejection {
+    /// The request has no credentials, or ones we couldn't verify (401)
+    Unauthenticated(#[allow(dead_code)] String),
+    /// The credentials are valid, but don't grant access to the route (403)
+    Forbidden(#[allow(dead_code)] String),
+}
+
+impl Rejection {
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Rejection::Unauthenticated(_) => http::StatusCode::UNAUTHORIZED,
+            Rejection::Forbidden(_) => http::StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// How requests to a route must authenticate. A request gets in if it passes any one of the
+/// configured methods.
+///
+/// Whatever credentials we check are removed before the request is forwarded, so upstreams never
+/// see passwords or keys. Headers we forward on a user's behalf (forward_user, forward_claims) are
+/// always removed from incoming requests, so that clients can't set them themselves.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub basic: Option<BasicAuth>,
+    pub api_key: Option<ApiKeyAuth>,
+    pub jwt: Option<JwtAuth>,
+}
+
+/// HTTP basic authentication against an htpasswd file
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BasicAuth {
+    /// Path of the htpasswd file. Passwords must be hashed with bcrypt (htpasswd -B) or SHA-1
+    /// (htpasswd -s).
+    pub htpasswd: String,
+    /// Realm to show in the browser's login prompt. Only visible ASCII characters and spaces
+    /// may be used.
+    pub realm: Option<String>,
+    /// Header to pass the user's name to the upstream in
+    pub forward_user: Option<String>,
+    /// The hashed passwords from the htpasswd file, by user, read when the configuration is loaded
+    #[serde(skip)]
+    users: HashMap<String, String>,
+    /// The slowest of those hashes to check, which passwords for unknown users are checked against
+    /// (and always fail), so that how long a check takes doesn't give away whether a user exists
+    #[serde(skip)]
+    dummy_hash: Option<String>,
+}
+
+/// Static API keys, sent by clients in a header
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeyAuth {
+    /// Header the key is sent in
+    pub header: String,
+    /// The keys that are let in
+    pub keys: Vec<String>,
+}
+
+impl Default for ApiKeyAuth {
+    fn default() -> ApiKeyAuth {
+        ApiKeyAuth {
+            header: "x-api-key".to_string(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+/// JSON web tokens, sent by clients as bearer tokens in the Authorization header. Tokens must have
+/// an expiry time (exp).
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtAuth {
+    /// HS256 or RS256
+    pub algorithm: Algorithm,
+    /// Shared secret that HS256 tokens are signed with
+    pub secret: Option<String>,
+    /// Path of the PEM file with the public key that RS256 tokens are signed with
+    pub public_key: Option<String>,
+    /// Only accept tokens issued by this issuer (iss)
+    pub issuer: Option<String>,
+    /// Only accept tokens meant for this audience (aud)
+    pub audience: Option<String>,
+    /// How far off (in seconds) the token issuer's clock may be from ours
+    pub leeway_secs: u64,
+    /// Claims the token must have, with the values they must have. If a claim in the token is a
+    /// list, it must contain the value. Tokens without them are valid, but forbidden (403).
+    pub required_claims: BTreeMap<String, Value>,
+    /// Claims to pass on to the upstream, by the header to put them in
+    pub forward_claims: BTreeMap<String, String>,
+    /// Checks the token's signature, read when the configuration is loaded
+    #[serde(skip)]
+    key: Option<JwtKey>,
+}
+
+impl Default for JwtAuth {
+    fn default() -> JwtAuth {
+        JwtAuth {
+            algorithm: Algorithm::HS256,
+            secret: None,
+            public_key: None,
+            issuer: None,
+            audience: None,
+            leeway_secs: 60,
+            required_claims: BTreeMap::new(),
+            forward_claims: BTreeMap::new(),
+            key: None,
+        }
+    }
+}
+
+/// DecodingKey doesn't implement Debug, which the configuration needs
+struct JwtKey(DecodingKey);
+
+impl fmt::Debug for JwtKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("JwtKey")
+    }
+}
+
+impl Auth {
+    /// Reads the files the settings refer to. Called once when the configuration is loaded.
+    pub fn load(&mut self) -> Result<(), Error> {
+        if let Some(basic) = &mut self.basic {
+            basic.load()?;
+        }
+        if let Some(jwt) = &mut self.jwt {
+            jwt.load()?;
+        }
+        Ok(())
+    }
+
+    /// Checks the request's credentials. If they are good, the request is made ready to forward
+    /// (see Auth); otherwise, returns why it should be turned away.
+    pub async fn authenticate(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Result<(), Rejection> {
+        let headers = request.headers_mut();
+        let forwarded = self
+            .basic
+            .iter()
+            .filter_map(|basic| basic.forward_user.as_ref())
+            .chain(self.jwt.iter().flat_map(|jwt| jwt.forward_claims.values()));
+        for header in forwarded {
+            headers.remove(header.as_str());
+        }
+
+        let authorization = headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let mut rejection = None;
+        if let (Some(basic), Some(credentials)) = (
+            &self.basic,
+            authorization
+                .as_deref()
+                .and_then(|value| strip_scheme(value, "basic")),
+        ) {
+            match basic.check(credentials).await {
+                Ok(user) => {
+                    headers.remove(http::header::AUTHORIZATION);
+                    if let Some(header) = &basic.forward_user {
+                        set_header(headers, header, &user);
+                    }
+                    return Ok(());
+                }
+                Err(reason) => rejection = Some(Rejection::Unauthenticated(reason)),
+            }
+        }
+        if let Some(api_key) = &self.api_key {
+            if let Some(key) = headers.get(api_key.header.as_str()) {
+                if api_key.check(key.as_bytes()) {
+                    headers.remove(api_key.header.as_str());
+                    return Ok(());
+                }
+                rejection = Some(Rejection::Unauthenticated("unknown API key".to_string()));
+            }
+        }
+        if let (Some(jwt), Some(token)) = (
+            &self.jwt,
+            authorization
+                .as_deref()
+                .and_then(|value| strip_scheme(value, "bearer")),
+        ) {
+            let claims = jwt.check(token)?;
+            headers.remove(http::header::AUTHORIZATION);
+            for (claim, header) in &jwt.forward_claims {
+                match claims.get(claim) {
+                    Some(Value::String(value)) => set_header(headers, header, value),
+                    Some(Value::Null) | None => {}
+                    Some(value) => set_header(headers, header, &value.to_string()),
+                }
+            }
+            return Ok(());
+        }
+        Err(rejection.unwrap_or_else(|| Rejection::Unauthenticated("no credentials".to_string())))
+    }
+
+    /// The WWW-Authenticate header value that tells clients how they may authenticate, if any of
+    /// the methods has a standard way of saying so
+    pub fn challenge(&self) -> Option<String> {
+        let mut challenges = Vec::new();
+        if let Some(basic) = &self.basic {
+            let realm = basic
+                .realm
+                .as_deref()
+                .unwrap_or("balancebeam")
+                .replace('"', "");
+            challenges.push(format!("Basic realm=\"{}\"", realm));
+        }
+        if self.jwt.is_some() {
+            challenges.push("Bearer".to_string());
+        }
+        (!challenges.is_empty()).then(|| challenges.join(", "))
+    }
+}
+
+/// Returns what follows the given authentication scheme (matched case-insensitively) in an
+/// Authorization header value
+fn strip_scheme<'a>(authorization: &'a str, scheme: &str) -> Option<&'a str> {
+    let (found, credentials) = authorization.split_once(' ')?;
+    found
+        .eq_ignore_ascii_case(scheme)
+        .then(|| credentials.trim())
+}
+
+/// Sets a header to a value that came from credentials, leaving it out if the value can't be put
+/// in a header
+fn set_header(headers: &mut http::HeaderMap, name: &str, value: &str) {
+    match (
+        http::HeaderName::from_bytes(name.as_bytes()),
+        http::HeaderValue::from_str(value),
+    ) {
+        (Ok(name), Ok(value)) => {
+            headers.insert(name, value);
+        }
+        _ => log::warn!(
+            "Can't forward {:?} in header {} (request {})",
+            value,
+            name,
+            request_id::of(headers)
+        ),
+    }
+}
+
+impl BasicAuth {
+    fn load(&mut self) -> Result<(), Error> {
+        if let Some(realm) = &self.realm {
+            if !realm.bytes().all(|b| matches!(b, b' '..=b'~')) {
+                return Err(Error::InvalidRealm(realm.clone()));
+            }
+        }
+        let contents = std::fs::read_to_string(&self.htpasswd)
+            .map_err(|error| Error::UnreadableFile(self.htpasswd.clone(), error))?;
+        self.users.clear();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                return Err(Error::InvalidHtpasswdLine(line.to_string()));
+            };
+            if !(hash.starts_with("$2") || hash.starts_with("{SHA}")) {
+                return Err(Error::UnsupportedHash(user.to_string()));
+            }
+            self.users.insert(user.to_string(), hash.to_string());
+        }
+        self.dummy_hash = self
+            .users
+            .values()
+            .max_by_key(|hash| (hash.starts_with("$2"), bcrypt_cost(hash)))
+            .cloned();
+        Ok(())
+    }
+
+    /// Checks base64-encoded user:password credentials, and returns the user if they are right
+    async fn check(&self, credentials: &str) -> Result<String, String> {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .ok_or("malformed basic credentials")?;
+        let (user, password) = decoded
+            .split_once(':')
+            .ok_or("malformed basic credentials")?;
+        let (hash, known_user) = match (self.users.get(user), &self.dummy_hash) {
+            (Some(hash), _) => (hash, true),
+            (None, Some(dummy_hash)) => (dummy_hash, false),
+            (None, None) => return Err(format!("unknown user {:?}", user)),
+        };
+        let matches = match hash.strip_prefix("{SHA}") {
+            Some(expected) => {
+                let digest = base64::engine::general_purpose::STANDARD
+                    .encode(Sha1::digest(password.as_bytes()));
+                constant_time_eq(digest.as_bytes(), expected.as_bytes())
+            }
+            None => {
+                // bcrypt is slow on purpose, too slow to run on the async runtime's threads
+                let (password, hash) = (password.to_string(), hash.clone());
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+                    .await
+                    .map_err(|error| error.to_string())?
+                    .unwrap_or(false)
+            }
+        };
+        if !known_user {
+            return Err(format!("unknown user {:?}", user));
+        }
+        if !matches {
+            return Err(format!("wrong password for user {:?}", user));
+        }
+        Ok(user.to_string())
+    }
+}
+
+/// Returns the cost of a bcrypt hash ($2y$10$... has cost 10), or None for other hashes
+fn bcrypt_cost(hash: &str) -> Option<u32> {
+    hash.strip_prefix("$2")?.split('$').nth(1)?.parse().ok()
+}
+
+impl ApiKeyAuth {
+    fn check(&self, key: &[u8]) -> bool {
+        // Check every key, so that how long this takes doesn't give away which keys are close
+        self.keys.iter().fold(false, |found, known| {
+            constant_time_eq(known.as_bytes(), key) | found
+        })
+    }
+}
+
+impl JwtAuth {
+    fn load(&mut self) -> Result<(), Error> {
+        let key = match self.algorithm {
+            Algorithm::HS256 => {
+                DecodingKey::from_secret(self.secret.as_ref().ok_or(Error::MissingKey)?.as_bytes())
+            }
+            Algorithm::RS256 => {
+                let path = self.public_key.as_ref().ok_or(Error::MissingKey)?;
+                let pem = std::fs::read(path)
+                    .map_err(|error| Error::UnreadableFile(path.clone(), error))?;
+                DecodingKey::from_rsa_pem(&pem).map_err(Error::InvalidKey)?
+            }
+            algorithm => return Err(Error::UnsupportedAlgorithm(algorithm)),
+        };
+        self.key = Some(JwtKey(key));
+        Ok(())
+    }
+
+    /// Verifies the token and returns its claims
+    fn check(&self, token: &str) -> Result<serde_json::Map<String, Value>, Rejection> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.leeway = self.leeway_secs;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let key = &self
+            .key
+            .as_ref()
+            .expect("JWT settings used before being loaded")
+            .0;
+        let claims =
+            jsonwebtoken::decode::<serde_json::Map<String, Value>>(token, key, &validation)
+                .map_err(|error| Rejection::Unauthenticated(format!("invalid token: {}", error)))?
+                .claims;
+        for (claim, required) in &self.required_claims {
+            let satisfied = match claims.get(claim) {
+                Some(Value::Array(values)) => values.contains(required),
+                Some(value) => value == required,
+                None => false,
+            };
+            if !satisfied {
+                return Err(Rejection::Forbidden(format!(
+                    "token's {} claim isn't {}",
+                    claim, required
+                )));
+            }
+        }
+        Ok(claims)
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,673 @@
+use crate::limits::MessageLimits;
+use crate::stream::Stream;
+use crate::upstream::{self, ConnectionLimits, Origin, Upstream, UpstreamSpec};
+use crate::{request, response, upgrade};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::process::{Child, Command};
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// How long we give the proxy to start listening
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long flapping upstreams stay up, and then down
+const FLAP_PERIOD: Duration = Duration::from_secs(1);
+
+/// Options for `balancebeam bench`, which measures how fast balancebeam proxies requests: it starts
+/// a balancebeam in front of local echo upstreams, sends it requests over keep-alive connections
+/// for a while, and reports throughput and latency percentiles.
+#[derive(clap::Args, Debug)]
+pub struct BenchOptions {
+    /// Number of echo upstreams to start
+    #[arg(long, default_value = "4")]
+    upstreams: usize,
+
+    /// Number of extra echo upstreams that go down and come back up every second, so that
+    /// balancebeam keeps taking them out of rotation and putting them back (give it a short
+    /// --active-health-check-interval after --)
+    #[arg(long, default_value = "0")]
+    flapping_upstreams: usize,
+
+    /// How long the echo upstreams take to answer requests for anything other than --path (such
+    /// as balancebeam's health checks), in milliseconds
+    #[arg(long, default_value = "0")]
+    health_check_delay_ms: u64,
+
+    /// Number of keep-alive connections sending requests at once
+    #[arg(long, default_value = "64")]
+    connections: usize,
+
+    /// How long to send requests for (in seconds), not counting the warmup
+    #[arg(long, default_value = "10")]
+    duration: u64,
+
+    /// How long to send requests for before measuring (in seconds)
+    #[arg(long, default_value = "1")]
+    warmup: u64,
+
+    /// Size of the request bodies (0 sends GET requests; anything else, POST requests whose
+    /// bodies are echoed back)
+    #[arg(long, default_value = "0")]
+    body_size: usize,
+
+    /// Open a new connection for every request instead of keeping connections alive, so that
+    /// every request goes through picking an upstream and connecting to it
+    #[arg(long)]
+    close: bool,
+
+    /// Path to request
+    #[arg(long, default_value = "/bench")]
+    path: String,
+
+    /// Print the results as JSON, for comparing runs in scripts
+    #[arg(long)]
+    json: bool,
+
+    /// The balancebeam binary to measure, if not this one (e.g. a build of another revision, to
+    /// compare the two under the same load)
+    #[arg(long)]
+    proxy_binary: Option<std::path::PathBuf>,
+
+    /// Any other options for the balancebeam under test (after --, e.g. -- --config bench.json)
+    #[arg(last = true)]
+    proxy_args: Vec<String>,
+}
+
+/// What one load-generating connection measured
+#[derive(Default)]
+struct Measurements {
+    /// Latency of every request answered during the measured period, in microseconds
+    latencies_us: Vec<u64>,
+    /// Requests that failed (the connection broke, or the response was an error)
+    errors: u64,
+    /// How long each request that was still waiting for a response when the run ended had been
+    /// waiting, in microseconds. Long waits here mean balancebeam stalled.
+    unanswered_us: Vec<u64>,
+}
+
+/// Runs the benchmark and prints the results. Exits with an error if no requests got through.
+pub async fn run(options: BenchOptions) {
+    let echo = Arc::new(EchoSettings {
+        path: options.path.clone(),
+        other_paths_delay: Duration::from_millis(options.health_check_delay_ms),
+    });
+    let mut upstream_addresses = Vec::new();
+    for i in 0..options.upstreams.max(1) + options.flapping_upstreams {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Could not bind an echo upstream");
+        upstream_addresses.push(listener.local_addr().unwrap());
+        if i < options.upstreams.max(1) {
+            tokio::spawn(serve_echo(listener, echo.clone()));
+        } else {
+            tokio::spawn(serve_flapping(listener, echo.clone()));
+        }
+    }
+
+    let address = free_local_address().await;
+    let _proxy = start_proxy(
+        options.proxy_binary.as_deref(),
+        &address,
+        &upstream_addresses,
+        &options.proxy_args,
+    )
+    .await;
+
+    let request = Arc::new(bench_request(
+        &options.path,
+        options.body_size,
+        options.close,
+    ));
+    let warmup_end = Instant::now() + Duration::from_secs(options.warmup);
+    let end = warmup_end + Duration::from_secs(options.duration);
+    let workers: Vec<_> = (0..options.connections.max(1))
+        .map(|_| {
+            let address = address.to_string();
+            let request = request.clone();
+            let close = options.close;
+            tokio::spawn(
+                async move { drive_load(&address, &request, close, warmup_end, end).await },
+            )
+        })
+        .collect();
+    let mut results = Measurements::default();
+    for worker in workers {
+        let measurements = worker.await.expect("Load generator panicked");
+        results.latencies_us.extend(measurements.latencies_us);
+        results.errors += measurements.errors;
+        results.unanswered_us.extend(measurements.unanswered_us);
+    }
+
+    report(&options, &mut results);
+    if results.latencies_us.is_empty() {
+        log::error!("No requests got through the proxy");
+        std::process::exit(1);
+    }
+}
+
+/// How the echo upstreams behave
+struct EchoSettings {
+    /// The path the benchmark requests
+    path: String,
+    /// How long to wait before answering requests for other paths
+    other_paths_delay: Duration,
+}
+
+/// An upstream like the test suite's EchoServer: it answers every request with the request itself
+/// (the request line and headers, then the body), over keep-alive connections
+async fn serve_echo(listener: TcpListener, settings: Arc<EchoSettings>) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let limits = MessageLimits::default();
+            while let Ok(request) =
+                request::read_from_stream(&mut stream, &limits, |_| limits).await
+            {
+                if request.uri().path() != settings.path {
+                    sleep(settings.other_paths_delay).await;
+                }
+                let mut body = format!("{}\n", request::format_request_line(&request));
+                for (name, value) in request.headers() {
+                    body.push_str(&format!("{}: {}\n", name, value.to_str().unwrap_or("")));
+                }
+                body.push('\n');
+                let mut body = body.into_bytes();
+                body.extend_from_slice(request.body());
+                let response = response::make_response(http::StatusCode::OK, "text/plain", body);
+                if response::write_to_stream(&response, &mut stream)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// An echo upstream that stops listening every so often (refusing connections), and then starts
+/// again on the same port
+async fn serve_flapping(listener: TcpListener, settings: Arc<EchoSettings>) {
+    let address = listener.local_addr().unwrap();
+    let mut listener = Some(listener);
+    loop {
+        if let Some(listener) = listener.take() {
+            let _ = timeout(FLAP_PERIOD, serve_echo(listener, settings.clone())).await;
+        }
+        // Keep the port bound (but not listening, so connections are refused) while we are down,
+        // or an outgoing connection may be given it as its local port and connect to itself
+        let placeholder = TcpSocket::new_v4().and_then(|socket| {
+            socket.set_reuseaddr(true)?;
+            socket.bind(address)?;
+            Ok(socket)
+        });
+        sleep(FLAP_PERIOD).await;
+        drop(placeholder);
+        match TcpListener::bind(address).await {
+            Ok(rebound) => listener = Some(rebound),
+            Err(error) => log::warn!(
+                "Could not bring flapping upstream {} back: {}",
+                address,
+                error
+            ),
+        }
+    }
+}
+
+/// Finds a local port nobody is listening on, for the proxy to listen on
+async fn free_local_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not find a free port");
+    listener.local_addr().unwrap()
+}
+
+/// Starts the balancebeam under test (this same binary, unless another one was given) and waits
+/// for it to accept connections. It only logs warnings, since logging every request would be most
+/// of what we measured, and only health checks its upstreams once a minute (unless the extra
+/// arguments say otherwise), since the echo upstreams don't go anywhere.
+async fn start_proxy(
+    binary: Option<&std::path::Path>,
+    address: &SocketAddr,
+    upstreams: &[SocketAddr],
+    extra_args: &[String],
+) -> Child {
+    let mut command = match binary {
+        Some(binary) => Command::new(binary),
+        None => Command::new(upgrade::current_binary()),
+    };
+    command.arg("--bind").arg(address.to_string());
+    for upstream in upstreams {
+        command.arg("--upstream").arg(upstream.to_string());
+    }
+    if !extra_args
+        .iter()
+        .any(|arg| arg.starts_with("--active-health-check-interval"))
+    {
+        command.args(["--active-health-check-interval", "60"]);
+    }
+    command
+        .args(extra_args)
+        .env("RUST_LOG", "warn")
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    let mut proxy = command.spawn().expect("Could not start balancebeam");
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while Stream::connect(&address.to_string()).await.is_err() {
+        if let Ok(Some(status)) = proxy.try_wait() {
+            log::error!(
+                "balancebeam exited before it started listening ({})",
+                status
+            );
+            std::process::exit(1);
+        }
+        if Instant::now() >= deadline {
+            log::error!("balancebeam didn't start listening on {} in time", address);
+            std::process::exit(1);
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    proxy
+}
+
+fn bench_request(path: &str, body_size: usize, close: bool) -> http::Request<Vec<u8>> {
+    let mut request = http::Request::builder()
+        .uri(path)
+        .header("Host", "balancebeam-bench")
+        .version(http::Version::HTTP_11);
+    if close {
+        request = request.header("Connection", "close");
+    }
+    if body_size == 0 {
+        request.method("GET").body(Vec::new()).unwrap()
+    } else {
+        request
+            .method("POST")
+            .header("Content-Length", body_size.to_string())
+            .body(vec![b'x'; body_size])
+            .unwrap()
+    }
+}
+
+/// Sends requests one after another over a keep-alive connection (opening a new one whenever the
+/// proxy closes it, or for every request if `close` is set) until the end, measuring the ones sent
+/// after the warmup
+async fn drive_load(
+    address: &str,
+    request: &http::Request<Vec<u8>>,
+    close: bool,
+    warmup_end: Instant,
+    end: Instant,
+) -> Measurements {
+    let mut measurements = Measurements::default();
+    let limits = MessageLimits {
+        max_body_size: usize::MAX,
+        ..MessageLimits::default()
+    };
+    let mut connection: Option<BufReader<Stream>> = None;
+    while Instant::now() < end {
+        let stream = match &mut connection {
+            Some(stream) => stream,
+            None => match Stream::connect(address).await {
+                Ok(stream) => connection.insert(BufReader::new(stream)),
+                Err(_) => {
+                    measurements.errors += 1;
+                    sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+            },
+        };
+
+        let sent_at = Instant::now();
+        let exchange = async {
+            request::write_to_stream(request, stream).await.ok()?;
+            response::read_from_stream(stream, request.method(), &limits)
+                .await
+                .ok()
+        };
+        let response = timeout(end.saturating_duration_since(sent_at), exchange).await;
+        let latency = sent_at.elapsed();
+        let counted = sent_at >= warmup_end;
+        match response {
+            Ok(Some(response)) => {
+                if counted {
+                    if response.status().is_success() {
+                        measurements.latencies_us.push(latency.as_micros() as u64);
+                    } else {
+                        measurements.errors += 1;
+                    }
+                }
+                let closing = close
+                    || response
+                        .headers()
+                        .get(http::header::CONNECTION)
+                        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"close"));
+                if closing {
+                    connection = None;
+                }
+            }
+            // The run ended with this request still in progress
+            Err(_) => {
+                if counted {
+                    measurements.unanswered_us.push(latency.as_micros() as u64);
+                }
+                break;
+            }
+            Ok(None) => {
+                if counted {
+                    measurements.errors += 1;
+                }
+                connection = None;
+            }
+        }
+    }
+    measurements
+}
+
+/// Returns the latency below which the given percentage of requests finished
+fn percentile(sorted_latencies_us: &[u64], percent: f64) -> u64 {
+    if sorted_latencies_us.is_empty() {
+        return 0;
+    }
+    let rank = (percent / 100.0 * sorted_latencies_us.len() as f64).ceil() as usize;
+    sorted_latencies_us[rank.clamp(1, sorted_latencies_us.len()) - 1]
+}
+
+fn report(options: &BenchOptions, results: &mut Measurements) {
+    results.latencies_us.sort_unstable();
+    let latencies = &results.latencies_us;
+    let requests = latencies.len();
+    let throughput = requests as f64 / options.duration.max(1) as f64;
+    let percentiles = [50.0, 90.0, 99.0, 99.9];
+    let longest_unanswered_us = results.unanswered_us.iter().copied().max().unwrap_or(0);
+
+    if options.json {
+        let mut latency_us = serde_json::Map::new();
+        for percent in percentiles {
+            latency_us.insert(
+                format!("p{}", percent),
+                percentile(latencies, percent).into(),
+            );
+        }
+        latency_us.insert(
+            "max".to_string(),
+            latencies.last().copied().unwrap_or(0).into(),
+        );
+        let summary = serde_json::json!({
+            "upstreams": options.upstreams,
+            "flapping_upstreams": options.flapping_upstreams,
+            "connections": options.connections,
+            "duration_secs": options.duration,
+            "body_size": options.body_size,
+            "close": options.close,
+            "requests": requests,
+            "errors": results.errors,
+            "unanswered": results.unanswered_us.len(),
+            "longest_unanswered_us": longest_unanswered_us,
+            "requests_per_sec": throughput,
+            "latency_us": latency_us,
+        });
+        println!("{}", summary);
+        return;
+    }
+
+    println!(
+        "{} {} to balancebeam in front of {} echo upstreams, for {}s ({}-byte bodies)",
+        options.connections,
+        if options.close {
+            "clients opening a connection per request"
+        } else {
+            "keep-alive connections"
+        },
+        options.upstreams,
+        options.duration,
+        options.body_size
+    );
+    println!("  requests:   {} ({} errors)", requests, results.errors);
+    println!(
+        "  unanswered: {} at the end (waiting for up to {:.3} ms)",
+        results.unanswered_us.len(),
+        longest_unanswered_us as f64 / 1000.0
+    );
+    println!("  throughput: {:.1} requests/s", throughput);
+    println!("  latency:");
+    for percent in percentiles {
+        println!(
+            "    p{:<5} {:>10.3} ms",
+            percent,
+            percentile(latencies, percent) as f64 / 1000.0
+        );
+    }
+    println!(
+        "    max    {:>10.3} ms",
+        latencies.last().copied().unwrap_or(0) as f64 / 1000.0
+    );
+}
+
+/// Options for `balancebeam bench-pool`, which measures how long connections take to pick an
+/// upstream while some of them find theirs down and health checks probe the pool. It runs the
+/// same load against the lock-free pool and against a stand-in for the design it replaced (the
+/// healthy and failed upstreams in two lists behind async RwLocks, with health checks holding a
+/// read lock while they probe). No proxy or network is involved.
+#[derive(clap::Args, Debug)]
+pub struct PoolBenchOptions {
+    /// Number of upstreams in the pool
+    #[arg(long, default_value = "6")]
+    upstreams: usize,
+
+    /// Number of connections picking upstreams at once
+    #[arg(long, default_value = "64")]
+    connections: usize,
+
+    /// How many times a second a connection finds the upstream it picked down
+    #[arg(long, default_value = "5")]
+    failures_per_sec: u32,
+
+    /// How long a health check takes to probe each upstream, in milliseconds
+    #[arg(long, default_value = "100")]
+    probe_ms: u64,
+
+    /// How long to wait between rounds of health checks, in milliseconds
+    #[arg(long, default_value = "1000")]
+    health_check_interval_ms: u64,
+
+    /// How long to run each design for (in seconds)
+    #[arg(long, default_value = "5")]
+    duration: u64,
+}
+
+/// The upstream state before it moved into lock-free snapshots: upstreams that had failed were
+/// moved from one list to the other, under write locks on both
+#[derive(Default)]
+struct LockedLists {
+    upstreams: tokio::sync::RwLock<Vec<Arc<Upstream>>>,
+    failed: tokio::sync::RwLock<Vec<Arc<Upstream>>>,
+}
+
+impl LockedLists {
+    async fn pick(&self) -> Option<Arc<Upstream>> {
+        let upstreams = self.upstreams.read().await;
+        upstream::choose_weighted(&upstreams.iter().collect::<Vec<_>>(), None)
+    }
+
+    async fn fail(&self, upstream: &Arc<Upstream>) {
+        let mut upstreams = self.upstreams.write().await;
+        let mut failed = self.failed.write().await;
+        if let Some(i) = upstreams
+            .iter()
+            .position(|known| Arc::ptr_eq(known, upstream))
+        {
+            failed.push(upstreams.swap_remove(i));
+        }
+    }
+
+    /// Probes every upstream (they all pass) with a read lock held on its list, then moves the
+    /// failed ones back
+    async fn health_check(&self, probe: Duration) {
+        for list in [&self.failed, &self.upstreams] {
+            let probed = list.read().await;
+            for _ in probed.iter() {
+                sleep(probe).await;
+            }
+        }
+        let mut recovered = std::mem::take(&mut *self.failed.write().await);
+        self.upstreams.write().await.append(&mut recovered);
+    }
+}
+
+/// The two designs under test
+enum PoolUnderTest {
+    Locked(LockedLists),
+    Snapshot(upstream::Pool),
+}
+
+impl PoolUnderTest {
+    async fn pick(&self) -> Option<Arc<Upstream>> {
+        match self {
+            PoolUnderTest::Locked(lists) => lists.pick().await,
+            PoolUnderTest::Snapshot(pool) => {
+                let upstreams = pool.load();
+                let healthy: Vec<&Arc<Upstream>> = upstreams
+                    .iter()
+                    .filter(|upstream| upstream.is_healthy())
+                    .collect();
+                upstream::choose_weighted(&healthy, None)
+            }
+        }
+    }
+
+    async fn fail(&self, upstream: &Arc<Upstream>) {
+        match self {
+            PoolUnderTest::Locked(lists) => lists.fail(upstream).await,
+            PoolUnderTest::Snapshot(_) => {
+                upstream.mark_failed();
+            }
+        }
+    }
+
+    async fn health_check(&self, probe: Duration) {
+        match self {
+            PoolUnderTest::Locked(lists) => lists.health_check(probe).await,
+            PoolUnderTest::Snapshot(pool) => {
+                for upstream in pool.snapshot().iter() {
+                    sleep(probe).await;
+                    upstream.mark_healthy();
+                }
+            }
+        }
+    }
+}
+
+/// Runs the pool benchmark against both designs and prints the results
+pub async fn run_pool(options: PoolBenchOptions) {
+    let limits = ConnectionLimits {
+        max_connections: 0,
+        queue_size: 0,
+        queue_timeout: Duration::ZERO,
+        idle_timeout: Duration::ZERO,
+    };
+    let upstreams = || -> Vec<Arc<Upstream>> {
+        (0..options.upstreams.max(1))
+            .map(|i| {
+                let spec = UpstreamSpec::new(format!("127.0.0.1:{}", 10000 + i), String::new());
+                Upstream::new(spec, Origin::CommandLine, &limits)
+            })
+            .collect()
+    };
+    let mut locked = LockedLists::default();
+    *locked.upstreams.get_mut() = upstreams();
+    let snapshot = upstream::Pool::new();
+    snapshot.update(|pool| *pool = upstreams());
+
+    println!(
+        "{} connections picking from {} upstreams for {}s each; {} picks a second fail, and \
+        health checks take {} ms per upstream every {} ms",
+        options.connections,
+        options.upstreams,
+        options.duration,
+        options.failures_per_sec,
+        options.probe_ms,
+        options.health_check_interval_ms
+    );
+    println!(
+        "{:<10} {:>12} {:>10} {:>10} {:>10} {:>10} {:>12}",
+        "design", "picks/s", "p50_us", "p99_us", "p99.9_us", "max_us", "over_10ms"
+    );
+    for (name, pool) in [
+        ("locked", PoolUnderTest::Locked(locked)),
+        ("snapshot", PoolUnderTest::Snapshot(snapshot)),
+    ] {
+        let mut latencies_us = measure_picks(Arc::new(pool), &options).await;
+        latencies_us.sort_unstable();
+        println!(
+            "{:<10} {:>12.0} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            name,
+            latencies_us.len() as f64 / options.duration.max(1) as f64,
+            percentile(&latencies_us, 50.0),
+            percentile(&latencies_us, 99.0),
+            percentile(&latencies_us, 99.9),
+            latencies_us.last().copied().unwrap_or(0),
+            latencies_us.iter().filter(|&&us| us > 10_000).count()
+        );
+    }
+}
+
+/// Has the connections pick upstreams until the run ends, alongside failures and rounds of health
+/// checks, and returns how long every pick took, in microseconds
+async fn measure_picks(pool: Arc<PoolUnderTest>, options: &PoolBenchOptions) -> Vec<u64> {
+    let end = Instant::now() + Duration::from_secs(options.duration);
+    let probe = Duration::from_millis(options.probe_ms);
+    let interval = Duration::from_millis(options.health_check_interval_ms);
+    let health_checks = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            loop {
+                sleep(interval).await;
+                pool.health_check(probe).await;
+            }
+        }
+    });
+    let failures = tokio::spawn({
+        let pool = pool.clone();
+        let every = Duration::from_secs(1) / options.failures_per_sec.max(1);
+        async move {
+            loop {
+                sleep(every).await;
+                if let Some(upstream) = pool.pick().await {
+                    pool.fail(&upstream).await;
+                }
+            }
+        }
+    });
+    let pickers: Vec<_> = (0..options.connections.max(1))
+        .map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let mut latencies_us = Vec::new();
+                while Instant::now() < end {
+                    let started = Instant::now();
+                    pool.pick().await;
+                    latencies_us.push(started.elapsed().as_micros() as u64);
+                    // Give the other connections a turn, as talking to the upstream would
+                    tokio::task::yield_now().await;
+                }
+                latencies_us
+            })
+        })
+        .collect();
+    let mut latencies_us = Vec::new();
+    for picker in pickers {
+        latencies_us.extend(picker.await.expect("Picker panicked"));
+    }
+    health_checks.abort();
+    failures.abort();
+    latencies_us
+}
@@ -0,0 +1,291 @@
+use crate::acl::{AccessList, Client};
+use crate::auth::{self, Auth};
+use crate::error_page::{self, ErrorPages};
+use crate::limits::{Limits, MessageLimits};
+use crate::local_response::{self, LocalResponse};
+use crate::maintenance::Maintenance;
+use crate::outlier::OutlierDetection;
+use crate::request_id::RequestIds;
+use crate::split::Split;
+use crate::tls::{self, Tls};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration file couldn't be read
+    Unreadable(#[allow(dead_code)] std::io::Error),
+    /// The configuration file isn't valid JSON, or doesn't match the expected structure
+    Invalid(#[allow(dead_code)] serde_json::Error),
+    /// The error pages couldn't be loaded
+    BadErrorPages(#[allow(dead_code)] error_page::Error),
+    /// A route's authentication settings couldn't be loaded
+    BadAuth(#[allow(dead_code)] auth::Error),
+    /// A route's local response is misconfigured
+    BadLocalResponse(#[allow(dead_code)] local_response::Error),
+    /// A listener's certificate or key couldn't be loaded
+    BadTls(#[allow(dead_code)] tls::Error),
+}
+
+/// Settings read from the JSON file passed with --config. Unlike the command-line options, these
+/// can be changed while balancebeam is running: it re-reads the file when it receives SIGHUP.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Who may connect to balancebeam at all
+    pub access: AccessList,
+    /// How big requests and responses may be
+    pub limits: Limits,
+    /// Settings that only apply to requests for certain paths
+    pub routes: Vec<Route>,
+    /// How requests are divided between upstream groups. Without a split, every request may go to
+    /// any upstream.
+    pub split: Option<Split>,
+    /// Ejecting misbehaving upstreams and easing them back in. Off unless configured.
+    pub outlier_detection: Option<OutlierDetection>,
+    /// What the error responses we send ourselves look like
+    pub error_pages: ErrorPages,
+    /// Which requests are held back while maintenance mode is on, and what they get instead
+    pub maintenance: Maintenance,
+    /// Whose X-Request-Id headers we keep
+    pub request_ids: RequestIds,
+    /// Settings for each of the --bind listeners, by name (or by address, for listeners without
+    /// one)
+    pub listeners: BTreeMap<String, Listener>,
+}
+
+/// Settings for the connections accepted by one listener, on top of the global ones. This is how
+/// e.g. a public and an internal listener get different routes.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listener {
+    /// Who may connect to this listener, in addition to the global access list
+    pub access: AccessList,
+    /// Overrides for the global message limits. Routes' limits apply on top of these.
+    pub limits: Limits,
+    /// Replaces the global routes for requests to this listener
+    pub routes: Option<Vec<Route>>,
+    /// Overrides the global error response format
+    pub error_format: Option<error_page::Format>,
+    /// Terminates TLS on this listener's connections. Without this, they are plain HTTP (or TCP).
+    pub tls: Option<Tls>,
+}
+
+/// The configuration as it applies to one listener
+pub struct ListenerConfig<'a> {
+    config: &'a Config,
+    listener: Option<&'a Listener>,
+}
+
+/// A set of settings for requests whose path starts with a given prefix.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Route {
+    /// Path prefix this route applies to. "/api" matches "/api" and "/api/users", but not
+    /// "/apis".
+    pub prefix: String,
+    /// Who may send requests to this route, in addition to the global access list
+    pub access: AccessList,
+    /// Overrides for the global message limits
+    pub limits: Limits,
+    /// Overrides the format of error responses (e.g. JSON for API routes), when the client doesn't
+    /// ask for a particular one
+    pub error_format: Option<error_page::Format>,
+    /// How requests to this route must authenticate. Without this, no credentials are needed.
+    pub auth: Option<Auth>,
+    /// Answers requests to this route ourselves, instead of forwarding them to an upstream
+    pub respond: Option<LocalResponse>,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at the given path
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path).map_err(Error::Unreadable)?;
+        let mut config: Config = serde_json::from_str(&contents).map_err(Error::Invalid)?;
+        config
+            .error_pages
+            .load_templates()
+            .map_err(Error::BadErrorPages)?;
+        config
+            .maintenance
+            .load_template()
+            .map_err(Error::BadErrorPages)?;
+        for tls in config
+            .listeners
+            .values_mut()
+            .filter_map(|listener| listener.tls.as_mut())
+        {
+            tls.load().map_err(Error::BadTls)?;
+        }
+        let listener_routes = config
+            .listeners
+            .values_mut()
+            .filter_map(|listener| listener.routes.as_mut());
+        for route in config.routes.iter_mut().chain(listener_routes.flatten()) {
+            if let Some(auth) = &mut route.auth {
+                auth.load().map_err(Error::BadAuth)?;
+            }
+            if let Some(respond) = &route.respond {
+                respond.validate().map_err(Error::BadLocalResponse)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the configuration for connections accepted by the given listener
+    pub fn listener(&self, name: &str) -> ListenerConfig<'_> {
+        ListenerConfig {
+            config: self,
+            listener: self.listeners.get(name),
+        }
+    }
+}
+
+impl ListenerConfig<'_> {
+    /// Returns what performs TLS handshakes for the listener, if it terminates TLS
+    pub fn tls_acceptor(&self) -> Option<tokio_rustls::TlsAcceptor> {
+        self.listener?.tls.as_ref().map(Tls::acceptor)
+    }
+
+    /// Returns whether the given client may connect to the listener
+    pub fn permits(&self, client: Client) -> bool {
+        self.config.access.permits(client)
+            && self
+                .listener
+                .is_none_or(|listener| listener.access.permits(client))
+    }
+
+    fn routes(&self) -> &[Route] {
+        self.listener
+            .and_then(|listener| listener.routes.as_deref())
+            .unwrap_or(&self.config.routes)
+    }
+
+    fn request_limits_for_listener(&self) -> MessageLimits {
+        let global = self
+            .config
+            .limits
+            .request
+            .apply_to(MessageLimits::default());
+        match self.listener {
+            Some(listener) => listener.limits.request.apply_to(global),
+            None => global,
+        }
+    }
+
+    fn response_limits_for_listener(&self) -> MessageLimits {
+        let global = self
+            .config
+            .limits
+            .response
+            .apply_to(MessageLimits::default());
+        match self.listener {
+            Some(listener) => listener.limits.response.apply_to(global),
+            None => global,
+        }
+    }
+
+    /// The format of error responses when neither the client nor the route asks for a particular
+    /// one
+    pub fn error_format(&self) -> error_page::Format {
+        self.listener
+            .and_then(|listener| listener.error_format)
+            .unwrap_or(self.config.error_pages.format)
+    }
+
+    /// Returns the route with the longest prefix matching the given request path, if any
+    pub fn route_for(&self, path: &str) -> Option<&Route> {
+        self.routes()
+            .iter()
+            .filter(|route| route.matches(path))
+            .max_by_key(|route| route.prefix.len())
+    }
+
+    /// Returns the limits for requests to the given path
+    pub fn request_limits(&self, path: &str) -> MessageLimits {
+        let listener = self.request_limits_for_listener();
+        match self.route_for(path) {
+            Some(route) => route.limits.request.apply_to(listener),
+            None => listener,
+        }
+    }
+
+    /// Returns the limits for responses to requests for the given path
+    pub fn response_limits(&self, path: &str) -> MessageLimits {
+        let listener = self.response_limits_for_listener();
+        match self.route_for(path) {
+            Some(route) => route.limits.response.apply_to(listener),
+            None => listener,
+        }
+    }
+
+    /// Builds the error response to send for the given request, in the format the client asks for
+    /// (or the request's route is set up to use)
+    pub fn error_response(
+        &self,
+        status: http::StatusCode,
+        request: &http::Request<Vec<u8>>,
+        request_id: &str,
+    ) -> http::Response<Vec<u8>> {
+        let format = self.error_format_for(request);
+        self.config.error_pages.render(status, format, request_id)
+    }
+
+    /// The format of error responses to a request: whatever the client asks for, or else the
+    /// route's format
+    fn error_format_for(&self, request: &http::Request<Vec<u8>>) -> error_page::Format {
+        let default_format = self
+            .route_for(request.uri().path())
+            .and_then(|route| route.error_format)
+            .unwrap_or_else(|| self.error_format());
+        error_page::negotiate(request.headers().get(http::header::ACCEPT), default_format)
+    }
+
+    /// Builds the response for a request held back by maintenance mode: the maintenance page (or,
+    /// for clients that want another format, the usual 503 error), telling the client when to try
+    /// again
+    pub fn maintenance_response(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        request_id: &str,
+    ) -> http::Response<Vec<u8>> {
+        let status = http::StatusCode::SERVICE_UNAVAILABLE;
+        let maintenance = &self.config.maintenance;
+        let mut response = match maintenance.template() {
+            Some(template) if self.error_format_for(request) == error_page::Format::Html => {
+                error_page::render_template(template, status, request_id)
+            }
+            _ => self.error_response(status, request, request_id),
+        };
+        response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from_str(&maintenance.retry_after.to_string()).unwrap(),
+        );
+        response
+    }
+
+    /// Returns limits that let through any request that some route accepts. We don't know which
+    /// route a request is for until its headers have been read, so this is what the headers are
+    /// read under; the route's own limits are checked afterwards.
+    pub fn largest_request_limits(&self) -> MessageLimits {
+        let listener = self.request_limits_for_listener();
+        self.routes().iter().fold(listener, |largest, route| {
+            largest.max(&route.limits.request.apply_to(listener))
+        })
+    }
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> bool {
+        path_has_prefix(path, &self.prefix)
+    }
+}
+
+/// Returns whether a path falls under a prefix. "/api" covers "/api" and "/api/users", but not
+/// "/apis".
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
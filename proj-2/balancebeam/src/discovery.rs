@@ -0,0 +1,120 @@
+use crate::upstream::{self, UpstreamSpec};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The upstream file couldn't be read
+    Unreadable(#[allow(dead_code)] std::io::Error),
+    /// The upstream file looks like JSON, but isn't a valid list of upstreams
+    InvalidJson(#[allow(dead_code)] serde_json::Error),
+    /// The upstream file lists an address that is neither host:port nor unix:/path
+    InvalidAddress(#[allow(dead_code)] String),
+    /// The plain-text upstream file doesn't end in a newline, so its last line may be cut short
+    Truncated,
+    /// The upstream file lists no upstreams at all, without being an explicitly empty JSON list
+    Empty,
+}
+
+/// A file listing upstreams, written by some other tool (e.g. an orchestrator) and watched by
+/// balancebeam.
+///
+/// The file is either a plain list of addresses, one per line, optionally prefixed with a group as
+/// in "canary=10.0.0.1:80" (blank lines and lines starting with # are ignored), or a JSON array of
+/// objects like {"address": "10.0.0.1:80", "weight": 2, "tags": ["v2"], "group": "canary"}.
+/// Addresses are host:port or unix:/path/to.sock.
+///
+/// Whoever writes the file should write a new one and rename it into place, so that we never see it
+/// half-written. Since not every tool does, we also turn down files that look cut short: plain
+/// lists whose last line has no newline, and lists whose addresses aren't all complete. Once the
+/// file has listed upstreams, it can only take them all away by being an empty JSON list ("[]"); an
+/// empty plain list is more likely to be a file caught just after it was truncated.
+pub struct UpstreamFile {
+    path: String,
+    last_contents: Option<String>,
+}
+
+impl UpstreamFile {
+    pub fn new(path: String) -> UpstreamFile {
+        UpstreamFile {
+            path,
+            last_contents: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Reads the file, returning Ok(Some(upstreams)) if it changed since the last successful read
+    /// and Ok(None) if it didn't. If the file can't be read or parsed, the last good version is
+    /// kept, so a half-written file doesn't empty the pool.
+    pub async fn poll(&mut self) -> Result<Option<Vec<UpstreamSpec>>, Error> {
+        let contents = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(Error::Unreadable)?;
+        if self.last_contents.as_ref() == Some(&contents) {
+            return Ok(None);
+        }
+        let upstreams = parse(&contents)?;
+        if upstreams.is_empty() && self.last_contents.is_some() && !is_json(&contents) {
+            return Err(Error::Empty);
+        }
+        self.last_contents = Some(contents);
+        Ok(Some(upstreams))
+    }
+}
+
+fn is_json(contents: &str) -> bool {
+    contents.trim_start().starts_with('[')
+}
+
+fn parse(contents: &str) -> Result<Vec<UpstreamSpec>, Error> {
+    let upstreams: Vec<UpstreamSpec> = if is_json(contents) {
+        serde_json::from_str(contents).map_err(Error::InvalidJson)?
+    } else {
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            return Err(Error::Truncated);
+        }
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (group, address) = upstream::parse_grouped_address(line);
+                UpstreamSpec::new(address.to_string(), group)
+            })
+            .collect()
+    };
+    match upstreams
+        .iter()
+        .find(|upstream| !is_valid_address(&upstream.address))
+    {
+        Some(upstream) => Err(Error::InvalidAddress(upstream.address.clone())),
+        None => Ok(upstreams),
+    }
+}
+
+/// Returns whether an address is unix:/path, or host:port with a hostname, IPv4 address or
+/// bracketed IPv6 address and a port
+fn is_valid_address(address: &str) -> bool {
+    if let Some(path) = address.strip_prefix("unix:") {
+        return !path.is_empty();
+    }
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return false;
+    };
+    let valid_host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6
+            .strip_suffix(']')
+            .is_some_and(|ipv6| ipv6.parse::<std::net::Ipv6Addr>().is_ok()),
+        None => {
+            !host.is_empty()
+                && host.split('.').all(|label| {
+                    !label.is_empty()
+                        && label.bytes().all(|byte| {
+                            byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'
+                        })
+                })
+        }
+    };
+    valid_host && port.parse::<u16>().is_ok_and(|port| port > 0)
+}
@@ -0,0 +1,281 @@
+use crate::response;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An HTML template couldn't be read
+    UnreadableTemplate(
+        #[allow(dead_code)] String,
+        #[allow(dead_code)] std::io::Error,
+    ),
+    /// A template is listed under something other than a status code (e.g. "503"), a class of
+    /// statuses ("5xx") or "default"
+    InvalidKey(#[allow(dead_code)] String),
+}
+
+/// How the body of an error response is written
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A line of plain text, e.g. "HTTP 502 Bad Gateway"
+    #[default]
+    Text,
+    /// An HTML page, from a template if one is configured for the status
+    Html,
+    /// A JSON object, for API clients
+    Json,
+}
+
+/// Settings for the error responses balancebeam sends itself (when a request is denied, the
+/// upstream can't be reached, and so on). Responses from upstreams are passed on untouched.
+///
+/// HTML templates may contain {{status}}, {{reason}} and {{request_id}}, which are filled in when
+/// the page is sent.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorPages {
+    /// The format to use when the client's Accept header doesn't ask for a particular one. Routes
+    /// may override this.
+    pub format: Format,
+    /// Paths of HTML templates, by status code ("503"), class of statuses ("5xx") or "default"
+    pub html: BTreeMap<String, String>,
+    /// The contents of the templates in `html`, read when the configuration is loaded
+    #[serde(skip)]
+    templates: HashMap<String, String>,
+}
+
+impl ErrorPages {
+    /// Reads the HTML templates. Called once when the configuration is loaded, so that pages don't
+    /// have to be read from disk while serving errors.
+    pub fn load_templates(&mut self) -> Result<(), Error> {
+        for (key, path) in &self.html {
+            let valid_key = key == "default"
+                || key
+                    .parse::<u16>()
+                    .is_ok_and(|status| (400..600).contains(&status))
+                || matches!(key.as_str(), "4xx" | "5xx");
+            if !valid_key {
+                return Err(Error::InvalidKey(key.clone()));
+            }
+            let template = std::fs::read_to_string(path)
+                .map_err(|error| Error::UnreadableTemplate(path.clone(), error))?;
+            self.templates.insert(key.clone(), template);
+        }
+        Ok(())
+    }
+
+    /// Builds an error response with the given status, in the given format
+    pub fn render(
+        &self,
+        status: http::StatusCode,
+        format: Format,
+        request_id: &str,
+    ) -> http::Response<Vec<u8>> {
+        let reason = status.canonical_reason().unwrap_or("");
+        match format {
+            Format::Text => response::make_response(
+                status,
+                "text/plain",
+                format!(
+                    "HTTP {} {}\nRequest ID: {}\n",
+                    status.as_u16(),
+                    reason,
+                    request_id
+                )
+                .into_bytes(),
+            ),
+            Format::Json => response::make_response(
+                status,
+                "application/json",
+                serde_json::json!({
+                    "error": {
+                        "status": status.as_u16(),
+                        "message": reason,
+                        "request_id": request_id,
+                    }
+                })
+                .to_string()
+                .into_bytes(),
+            ),
+            Format::Html => {
+                let template = self
+                    .templates
+                    .get(status.as_str())
+                    .or_else(|| self.templates.get(&format!("{}xx", status.as_u16() / 100)))
+                    .or_else(|| self.templates.get("default"))
+                    .map_or(DEFAULT_HTML_TEMPLATE, String::as_str);
+                render_template(template, status, request_id)
+            }
+        }
+    }
+}
+
+/// Builds an HTML response from a template, filling in its placeholders. The request ID may have
+/// come from a (trusted) client, so it is escaped rather than trusted to be free of markup.
+pub fn render_template(
+    template: &str,
+    status: http::StatusCode,
+    request_id: &str,
+) -> http::Response<Vec<u8>> {
+    let page = template
+        .replace("{{status}}", status.as_str())
+        .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+        .replace("{{request_id}}", &escape_html(request_id));
+    response::make_response(status, "text/html; charset=utf-8", page.into_bytes())
+}
+
+/// Escapes the characters that are special in HTML text and attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const DEFAULT_HTML_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>Request ID: {{request_id}}</p>
+</body>
+</html>
+";
+
+/// Picks the format the client's Accept header asks for. Only formats the client names outright
+/// count (so that "*/*" doesn't turn every error into an HTML page); if it names none of them,
+/// the format is `default`.
+pub fn negotiate(accept: Option<&http::HeaderValue>, default: Format) -> Format {
+    let Some(accept) = accept.and_then(|accept| accept.to_str().ok()) else {
+        return default;
+    };
+    let mut best: Option<(Format, f32)> = None;
+    for media_range in accept.split(',') {
+        let mut params = media_range.split(';');
+        let format = match params
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "text/plain" => Format::Text,
+            "text/html" | "application/xhtml+xml" => Format::Html,
+            "application/json" => Format::Json,
+            _ => continue,
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        // On a tie, the first one listed wins
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((format, quality));
+        }
+    }
+    best.map_or(default, |(format, _)| format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate_str(accept: &str) -> Format {
+        negotiate(
+            Some(&http::HeaderValue::from_str(accept).unwrap()),
+            Format::Text,
+        )
+    }
+
+    #[test]
+    fn no_preference() {
+        assert_eq!(negotiate(None, Format::Json), Format::Json);
+        assert_eq!(negotiate_str(""), Format::Text);
+        assert_eq!(negotiate_str("*/*"), Format::Text);
+        assert_eq!(negotiate_str("image/png, text/*"), Format::Text);
+    }
+
+    #[test]
+    fn named_formats() {
+        assert_eq!(negotiate_str("application/json"), Format::Json);
+        assert_eq!(negotiate_str("Text/HTML"), Format::Html);
+        assert_eq!(negotiate_str("application/xhtml+xml;level=1"), Format::Html);
+        assert_eq!(
+            negotiate_str("image/png, */*;q=0.8, application/json"),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn q_values() {
+        assert_eq!(
+            negotiate_str("text/html;q=0.5, application/json;q=0.9"),
+            Format::Json
+        );
+        assert_eq!(
+            negotiate_str("text/html, application/json;q=0.9"),
+            Format::Html
+        );
+        assert_eq!(
+            negotiate_str("text/html ; q=0.2 , text/plain ; q=0.3"),
+            Format::Text
+        );
+        // q=0 means "not this one"
+        assert_eq!(negotiate_str("text/html;q=0"), Format::Text);
+        assert_eq!(
+            negotiate_str("application/json;q=0, text/html;q=0.1"),
+            Format::Html
+        );
+        // A q value that doesn't parse counts as 1
+        assert_eq!(
+            negotiate_str("text/html;q=high, application/json;q=0.9"),
+            Format::Html
+        );
+    }
+
+    /// On a tie, the first format listed wins
+    #[test]
+    fn ties() {
+        assert_eq!(negotiate_str("application/json, text/html"), Format::Json);
+        assert_eq!(
+            negotiate_str("text/html;q=0.5, application/json;q=0.5"),
+            Format::Html
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_html("abc-123"), "abc-123");
+        assert_eq!(
+            escape_html(r#"<a href="x" title='y'>&</a>"#),
+            "&lt;a href=&quot;x&quot; title=&#39;y&#39;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    /// Templates are looked up by status, then class of status, then "default"
+    #[test]
+    fn template_lookup() {
+        let mut pages = ErrorPages::default();
+        for key in ["503", "5xx", "default"] {
+            pages
+                .templates
+                .insert(key.to_string(), format!("{} {{{{status}}}}", key));
+        }
+        let body = |status| {
+            let response = pages.render(status, Format::Html, "id");
+            String::from_utf8(response.into_body()).unwrap()
+        };
+        assert_eq!(body(http::StatusCode::SERVICE_UNAVAILABLE), "503 503");
+        assert_eq!(body(http::StatusCode::BAD_GATEWAY), "5xx 502");
+        assert_eq!(body(http::StatusCode::FORBIDDEN), "default 403");
+    }
+}
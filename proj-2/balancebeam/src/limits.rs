@@ -0,0 +1,68 @@
+use serde::Deserialize;
+
+const DEFAULT_MAX_HEADERS_SIZE: usize = 8000;
+const DEFAULT_MAX_BODY_SIZE: usize = 10000000;
+const DEFAULT_MAX_NUM_HEADERS: usize = 32;
+
+/// Limits on the size of an HTTP message (a request or a response) that balancebeam is willing to
+/// read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Maximum number of bytes in the request/status line plus headers
+    pub max_headers_size: usize,
+    /// Maximum number of headers
+    pub max_num_headers: usize,
+    /// Maximum number of bytes in the body
+    pub max_body_size: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_headers_size: DEFAULT_MAX_HEADERS_SIZE,
+            max_num_headers: DEFAULT_MAX_NUM_HEADERS,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+impl MessageLimits {
+    /// Returns limits that are at least as generous as both self and other
+    pub fn max(&self, other: &MessageLimits) -> MessageLimits {
+        MessageLimits {
+            max_headers_size: self.max_headers_size.max(other.max_headers_size),
+            max_num_headers: self.max_num_headers.max(other.max_num_headers),
+            max_body_size: self.max_body_size.max(other.max_body_size),
+        }
+    }
+}
+
+/// Message limits as written in the configuration file. Any limit that is left out is inherited
+/// from the level above (the listener's limits for a route, the global limits for a listener, the
+/// built-in defaults for the global limits).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_headers_size: Option<usize>,
+    pub max_num_headers: Option<usize>,
+    pub max_body_size: Option<usize>,
+}
+
+impl LimitSettings {
+    /// Returns the given limits, with any limits set here replacing the corresponding ones
+    pub fn apply_to(&self, base: MessageLimits) -> MessageLimits {
+        MessageLimits {
+            max_headers_size: self.max_headers_size.unwrap_or(base.max_headers_size),
+            max_num_headers: self.max_num_headers.unwrap_or(base.max_num_headers),
+            max_body_size: self.max_body_size.unwrap_or(base.max_body_size),
+        }
+    }
+}
+
+/// Limits for requests (read from clients) and responses (read from upstreams)
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub request: LimitSettings,
+    pub response: LimitSettings,
+}
@@ -0,0 +1,392 @@
+use crate::request_id;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An inline response's status isn't a valid status code
+    InvalidStatus(#[allow(dead_code)] u16),
+    /// An inline response has a header with an invalid name or value
+    InvalidHeader(#[allow(dead_code)] String),
+    /// The directory to serve files from doesn't exist, or isn't a directory
+    BadRoot(#[allow(dead_code)] String),
+}
+
+/// Why a request to a route we answer ourselves gets an error response instead, along with any
+/// headers the error response needs (e.g. Allow for 405 Method Not Allowed)
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: http::StatusCode,
+    pub headers: http::HeaderMap,
+}
+
+impl From<http::StatusCode> for Rejection {
+    fn from(status: http::StatusCode) -> Rejection {
+        Rejection {
+            status,
+            headers: http::HeaderMap::new(),
+        }
+    }
+}
+
+/// A response balancebeam sends itself for requests to a route, instead of forwarding them to an
+/// upstream (e.g. for health endpoints and maintenance pages)
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum LocalResponse {
+    /// The same response to every request
+    Inline(InlineResponse),
+    /// Files from a directory
+    Files(StaticFiles),
+}
+
+/// A fixed response, given in full in the configuration
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct InlineResponse {
+    pub status: u16,
+    pub content_type: String,
+    /// Any other headers to send
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl Default for InlineResponse {
+    fn default() -> InlineResponse {
+        InlineResponse {
+            status: 200,
+            content_type: "text/plain; charset=utf-8".to_string(),
+            headers: BTreeMap::new(),
+            body: String::new(),
+        }
+    }
+}
+
+/// Files served from a directory. The part of the request's path after the route's prefix is
+/// looked up in the directory, so with the prefix "/static", /static/app.js is root/app.js.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFiles {
+    pub root: String,
+    /// File to serve for requests for a directory
+    #[serde(default = "default_index")]
+    pub index: String,
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+impl LocalResponse {
+    /// Checks the settings, so that mistakes show up when the configuration is loaded rather than
+    /// when requests come in
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            LocalResponse::Inline(inline) => {
+                http::StatusCode::from_u16(inline.status)
+                    .or(Err(Error::InvalidStatus(inline.status)))?;
+                let headers = std::iter::once(("Content-Type", &inline.content_type)).chain(
+                    inline
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value)),
+                );
+                for (name, value) in headers {
+                    if http::HeaderName::from_bytes(name.as_bytes()).is_err()
+                        || http::HeaderValue::from_str(value).is_err()
+                    {
+                        return Err(Error::InvalidHeader(name.to_string()));
+                    }
+                }
+                Ok(())
+            }
+            LocalResponse::Files(files) => {
+                if Path::new(&files.root).is_dir() {
+                    Ok(())
+                } else {
+                    Err(Error::BadRoot(files.root.clone()))
+                }
+            }
+        }
+    }
+
+    /// Builds the response to a request to the route with the given prefix. A file to send is
+    /// returned alongside the response (whose body is then empty), to be sent after it.
+    pub async fn respond(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        prefix: &str,
+    ) -> Result<(http::Response<Vec<u8>>, Option<FileBody>), Rejection> {
+        let (mut response, file) = match self {
+            LocalResponse::Inline(inline) => (inline.respond(), None),
+            LocalResponse::Files(files) => files.respond(request, prefix).await?,
+        };
+        // HEAD responses say how long the body would be, without sending it
+        if request.method() == http::Method::HEAD {
+            response.body_mut().clear();
+        }
+        Ok((response, file))
+    }
+}
+
+impl InlineResponse {
+    fn respond(&self) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder()
+            .status(self.status)
+            .header("Content-Type", &self.content_type)
+            .header("Content-Length", self.body.len().to_string())
+            .version(http::Version::HTTP_11);
+        for (name, value) in &self.headers {
+            response = response.header(name, value);
+        }
+        response.body(self.body.clone().into_bytes()).unwrap()
+    }
+}
+
+impl StaticFiles {
+    async fn respond(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        prefix: &str,
+    ) -> Result<(http::Response<Vec<u8>>, Option<FileBody>), Rejection> {
+        if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+            let mut rejection = Rejection::from(http::StatusCode::METHOD_NOT_ALLOWED);
+            rejection.headers.insert(
+                http::header::ALLOW,
+                http::HeaderValue::from_static("GET, HEAD"),
+            );
+            return Err(rejection);
+        }
+
+        // Find the file, serving the index file for directories. The size and modification time
+        // come from the file we have open, so that they match what we send even if the file is
+        // replaced in the meantime.
+        let relative_path = &request.uri().path()[prefix.len()..];
+        let mut path = self
+            .file_path(relative_path)
+            .ok_or(http::StatusCode::NOT_FOUND)?;
+        let (mut file, mut metadata) = open_file(&path)
+            .await
+            .map_err(|error| io_error_status(error, request))?;
+        if metadata.is_dir() {
+            path.push(&self.index);
+            (file, metadata) = open_file(&path)
+                .await
+                .map_err(|error| io_error_status(error, request))?;
+        }
+        if !metadata.is_file() {
+            return Err(http::StatusCode::NOT_FOUND.into());
+        }
+        let size = metadata.len();
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let etag = entity_tag(size, modified);
+        let last_modified = httpdate::fmt_http_date(modified);
+
+        let response = http::Response::builder()
+            .version(http::Version::HTTP_11)
+            .header("ETag", &etag)
+            .header("Last-Modified", &last_modified);
+        if is_not_modified(request, &etag, modified) {
+            return Ok((
+                response
+                    .status(http::StatusCode::NOT_MODIFIED)
+                    .body(Vec::new())
+                    .unwrap(),
+                None,
+            ));
+        }
+        let response = response
+            .header(
+                "Content-Type",
+                mime_guess::from_path(&path)
+                    .first_or_octet_stream()
+                    .essence_str(),
+            )
+            .header("Accept-Ranges", "bytes");
+
+        // Send part of the file if the client asked for a range (and, with If-Range, only if the
+        // file hasn't changed since it got the rest)
+        let range = request
+            .headers()
+            .get(http::header::RANGE)
+            .filter(|_| request.method() == http::Method::GET)
+            .filter(|_| {
+                request
+                    .headers()
+                    .get(http::header::IF_RANGE)
+                    .is_none_or(|validator| {
+                        validator == etag.as_str() || validator == last_modified.as_str()
+                    })
+            })
+            .and_then(|range| parse_range(range.to_str().ok()?, size));
+        let (status, start, length) = match range {
+            None => (http::StatusCode::OK, 0, size),
+            Some(Ok((start, end))) => (http::StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+            Some(Err(())) => {
+                let mut rejection = Rejection::from(http::StatusCode::RANGE_NOT_SATISFIABLE);
+                rejection.headers.insert(
+                    http::header::CONTENT_RANGE,
+                    http::HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+                );
+                return Err(rejection);
+            }
+        };
+        let mut response = response
+            .status(status)
+            .header("Content-Length", length.to_string());
+        if status == http::StatusCode::PARTIAL_CONTENT {
+            response = response.header(
+                "Content-Range",
+                format!("bytes {}-{}/{}", start, start + length - 1, size),
+            );
+        }
+
+        let body = if request.method() == http::Method::HEAD {
+            None
+        } else {
+            file.seek(SeekFrom::Start(start))
+                .await
+                .map_err(|error| io_error_status(error, request))?;
+            Some(FileBody { file, length })
+        };
+        Ok((response.body(Vec::new()).unwrap(), body))
+    }
+
+    /// Maps the part of a request's path after the route's prefix to a path under the root. Returns
+    /// None for paths that would leave the root (through "..") or can't be decoded.
+    fn file_path(&self, relative_path: &str) -> Option<PathBuf> {
+        let decoded = percent_encoding::percent_decode_str(relative_path)
+            .decode_utf8()
+            .ok()?;
+        let mut path = PathBuf::from(&self.root);
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                segment if segment.contains('\0') => return None,
+                segment => path.push(segment),
+            }
+        }
+        Some(path)
+    }
+}
+
+/// The entity tag we give a file, made up of its size and modification time (like nginx does), so
+/// that it doesn't take reading the whole file to work out
+fn entity_tag(size: u64, modified: SystemTime) -> String {
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("\"{:x}-{:x}\"", size, modified)
+}
+
+/// Returns whether the client's cached copy (going by If-None-Match, or failing that
+/// If-Modified-Since) is still current
+fn is_not_modified(request: &http::Request<Vec<u8>>, etag: &str, modified: SystemTime) -> bool {
+    let headers = request.headers();
+    if let Some(if_none_match) = headers.get(http::header::IF_NONE_MATCH) {
+        // Entity tags are compared weakly here, so W/"x" matches "x"
+        return if_none_match.to_str().is_ok_and(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        });
+    }
+    headers
+        .get(http::header::IF_MODIFIED_SINCE)
+        .and_then(|since| httpdate::parse_http_date(since.to_str().ok()?).ok())
+        .is_some_and(|since| {
+            // HTTP dates only go down to the second
+            let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+            modified.as_secs() <= since.as_secs()
+        })
+}
+
+/// Parses a Range header for a file of the given size. Returns None if the whole file should be
+/// sent (the header is malformed, or asks for several ranges, which we don't support), Some(Err)
+/// if the range is past the end of the file, or otherwise the first and last byte to send.
+fn parse_range(range: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let (unit, spec) = range.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.trim().split_once('-')?;
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|byte| byte.is_ascii_digit());
+    if first.is_empty() {
+        // A suffix range: the last so many bytes
+        let suffix_length: u64 = is_number(last).then(|| last.parse().ok())??;
+        if suffix_length == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((size.saturating_sub(suffix_length), size - 1)));
+    }
+    let first: u64 = is_number(first).then(|| first.parse().ok())??;
+    let last: Option<u64> = if last.is_empty() {
+        None
+    } else {
+        Some(is_number(last).then(|| last.parse().ok())??)
+    };
+    if last.is_some_and(|last| last < first) {
+        return None;
+    }
+    if first >= size {
+        return Some(Err(()));
+    }
+    Some(Ok((
+        first,
+        last.map_or(size - 1, |last| last.min(size - 1)),
+    )))
+}
+
+async fn open_file(path: &Path) -> std::io::Result<(tokio::fs::File, std::fs::Metadata)> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok((file, metadata))
+}
+
+/// The body of a response for a file, already positioned at the first byte to send. It is copied
+/// to the client a chunk at a time, so serving a file takes no more memory however big it is.
+#[derive(Debug)]
+pub struct FileBody {
+    file: tokio::fs::File,
+    length: u64,
+}
+
+impl FileBody {
+    /// Sends the body, after the response's headers have been sent. If the file has shrunk since
+    /// we opened it, the client has already been promised more bytes than there are to send, so
+    /// this returns an error, after which the connection has to be closed.
+    pub async fn send_to<S: AsyncWrite + Unpin>(self, stream: &mut S) -> std::io::Result<()> {
+        let sent = tokio::io::copy(&mut self.file.take(self.length), stream).await?;
+        if sent < self.length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("file ended after {} of {} bytes", sent, self.length),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn io_error_status(error: std::io::Error, request: &http::Request<Vec<u8>>) -> Rejection {
+    match error.kind() {
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory => {
+            http::StatusCode::NOT_FOUND.into()
+        }
+        std::io::ErrorKind::PermissionDenied => http::StatusCode::FORBIDDEN.into(),
+        _ => {
+            log::error!(
+                "Error reading a file to serve: {} (request {})",
+                error,
+                request_id::of(request.headers())
+            );
+            http::StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
@@ -1,14 +1,46 @@
+mod acl;
+mod admin;
+mod auth;
+mod bench;
+mod config;
+mod discovery;
+mod error_page;
+mod limits;
+mod local_response;
+mod maintenance;
+mod mirror;
+mod outlier;
+mod proxy_protocol;
+mod rate_limit_store;
 mod request;
+mod request_id;
+mod resolver;
 mod response;
+mod split;
+mod stream;
+mod tls;
+mod trace;
+mod upgrade;
+mod upstream;
 
+use acl::{Client, Source};
 use clap::Parser;
-use rand::{Rng, SeedableRng};
-use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use config::Config;
+use discovery::UpstreamFile;
+use mirror::Mirror;
+use rate_limit_store::RateLimitStore;
+use resolver::Resolver;
+use std::collections::{HashMap, HashSet};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::{sleep, Duration, Instant};
+use stream::{Listener, Stream};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use trace::{Destination, Span, SpanKind, Tracer};
+use upstream::{ActiveConnection, ConnectionLimits, Origin, Pool, Upstream, UpstreamSpec};
 // use std::time::Duration;
 // use delay_timer::prelude::{Task, TaskBuilder, TaskError};
 
@@ -17,14 +49,35 @@ use tokio::time::{sleep, Duration, Instant};
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// IP/port to bind to
+    /// IP/port (or unix:/path for a Unix domain socket) to bind to, optionally prefixed with a name
+    /// for the listener (as in internal=10.0.0.5:8080) that its settings in the --config file are
+    /// found under. May be given more than once to listen on several addresses.
     #[arg(short, long, default_value = "0.0.0.0:1100")]
-    bind: String,
+    bind: Vec<String>,
 
-    /// Upstream host to forward requests to
+    /// Upstream host to forward requests to, optionally prefixed with the group it belongs to (as
+    /// in canary=10.0.0.1:80). Hostnames are expanded into every address they resolve to. Upstreams
+    /// listening on Unix domain sockets are given as unix:/path/to.sock.
     #[arg(short, long)]
     upstream: Vec<String>,
 
+    /// Re-resolve upstream hostnames on this interval (in seconds, 0 = never)
+    #[arg(long, default_value = "30")]
+    dns_refresh_interval: usize,
+
+    /// File in /etc/hosts format whose entries take precedence over DNS when resolving upstreams
+    #[arg(long)]
+    hosts_file: Option<String>,
+
+    /// File listing more upstreams (one address per line, or JSON with weights and tags), which is
+    /// watched for changes
+    #[arg(long)]
+    upstream_file: Option<String>,
+
+    /// Check the upstream file for changes on this interval (in seconds)
+    #[arg(long, default_value = "5")]
+    upstream_file_poll_interval: usize,
+
     /// Perform active health checks on this interval (in seconds)
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
@@ -33,9 +86,117 @@ struct CmdOptions {
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
 
+    /// Shadow upstream to send copies of requests to (responses from it are discarded)
+    #[arg(long)]
+    mirror_upstream: Vec<String>,
+
+    /// Percentage of requests to copy to the --mirror-upstream pool
+    #[arg(long, default_value = "100")]
+    mirror_percent: f64,
+
+    /// Most connections to open to each upstream at once (0 = unlimited). The upstream file may
+    /// set a different limit for each upstream.
+    #[arg(long, default_value = "0")]
+    upstream_max_connections: usize,
+
+    /// How many client connections may wait for a free connection slot on each upstream
+    #[arg(long, default_value = "100")]
+    upstream_queue_size: usize,
+
+    /// How long a client connection may wait for a free connection slot (in milliseconds)
+    #[arg(long, default_value = "1000")]
+    upstream_queue_timeout_ms: u64,
+
+    /// How long a keep-alive client may go without sending a request before its upstream
+    /// connection is closed, freeing up the connection slot (in milliseconds). The slot is given up
+    /// straight away if another client is waiting for one.
+    #[arg(long, default_value = "10000")]
+    upstream_idle_timeout_ms: u64,
+
+    /// IP/port to serve the admin interface (e.g. /metrics) on
+    #[arg(long)]
+    admin_bind: Option<String>,
+
+    /// OTLP/HTTP collector endpoint to export spans to as JSON (e.g.
+    /// http://127.0.0.1:4318/v1/traces). Turns on tracing: requests get a traceparent header
+    /// continuing the client's trace, or starting a new one.
+    #[arg(long, conflicts_with = "trace_file")]
+    trace_endpoint: Option<String>,
+
+    /// File to append exported spans to (OTLP/JSON, one export per line). Turns on tracing, like
+    /// --trace-endpoint.
+    #[arg(long)]
+    trace_file: Option<String>,
+
     /// Maximum number of requests to accept per IP per minute (0 = unlimited)
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+
+    /// Redis-protocol server (host:port) to share rate limiting counts with other balancebeam
+    /// instances through. If it can't be reached, each instance limits on its own.
+    #[arg(long)]
+    rate_limit_store: Option<String>,
+
+    /// Whether to proxy HTTP requests or raw TCP connections
+    #[arg(long, value_enum, default_value = "http")]
+    mode: Mode,
+
+    /// Expect connections from --trusted-proxy peers to start with a PROXY protocol (v1 or v2)
+    /// header, and treat the client address in it as the client's address
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+
+    /// A network (in CIDR notation) of proxies whose PROXY protocol headers are believed, for
+    /// --accept-proxy-protocol, or "unix" for proxies connecting over a Unix domain socket (may be
+    /// given several times). Anyone else is taken to be the client itself, and has no say in its
+    /// address.
+    #[arg(long = "trusted-proxy", value_parser = parse_source)]
+    trusted_proxies: Vec<Source>,
+
+    /// Send a PROXY protocol header with the client's address to upstreams (TCP mode only)
+    #[arg(long, value_enum)]
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+
+    /// JSON file with access lists and per-route settings (re-read on SIGHUP)
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Bind listening sockets with SO_REUSEPORT, so that a new balancebeam can be started on the
+    /// same addresses before this one is stopped (with SIGQUIT)
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// After SIGUSR2 or SIGQUIT, how long to wait for open connections to finish before exiting
+    /// (in seconds)
+    #[arg(long, default_value = "30")]
+    drain_timeout: u64,
+
+    /// File to write our process ID to once we are serving (a process started by SIGUSR2 writes
+    /// its own)
+    #[arg(long)]
+    pid_file: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Things balancebeam can do other than proxying
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Measure balancebeam's throughput and latency, proxying to local echo upstreams
+    Bench(bench::BenchOptions),
+    /// Measure how long connections take to pick an upstream while the pool changes, with the
+    /// lock-free pool and with the locked lists it replaced
+    BenchPool(bench::PoolBenchOptions),
+}
+
+/// How balancebeam treats the traffic it forwards.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Parse HTTP requests and responses (with rate limiting, X-Forwarded-For, etc.)
+    Http,
+    /// Splice bytes between the client and an upstream without looking at them (layer 4)
+    Tcp,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -43,6 +204,16 @@ struct CmdOptions {
 ///
 /// You should add fields to this struct in later milestones.
 struct ProxyState {
+    /// Whether we are proxying HTTP or raw TCP
+    mode: Mode,
+
+    /// Peers whose connections start with a PROXY protocol header. Empty unless
+    /// --accept-proxy-protocol is on.
+    trusted_proxies: Vec<Source>,
+
+    /// Which PROXY protocol version (if any) to send to upstreams in TCP mode
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+
     /// How frequently we check whether upstream servers are alive (Milestone 4)
     active_health_check_interval: usize,
 
@@ -52,13 +223,60 @@ struct ProxyState {
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     max_requests_per_minute: usize,
 
-    // DONE: 改用Arc存String，减少clone
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: RwLock<Vec<Arc<String>>>,
+    /// Shares rate limiting counts with other instances, if configured
+    rate_limit_store: Option<RateLimitStore>,
+
+    /// Sends copies of requests to the shadow pool
+    mirror: Mirror,
+
+    /// Records and exports spans for the requests we proxy, if tracing is on
+    tracer: Option<Tracer>,
+
+    /// Upstreams as given on the command line (possibly hostnames)
+    upstream_specs: Vec<String>,
+
+    /// Looks up the addresses behind upstream_specs
+    resolver: Resolver,
 
-    failed_upstream_addresses: RwLock<Vec<Arc<String>>>,
+    /// How often we re-resolve upstream_specs (0 = never)
+    dns_refresh_interval: usize,
+
+    /// The addresses each of upstream_specs resolved to the last time we looked
+    resolved_upstreams: Mutex<HashMap<String, Vec<String>>>,
+
+    /// How often we check the --upstream-file for changes
+    upstream_file_poll_interval: usize,
+
+    /// How many connections we may open to each upstream
+    connection_limits: ConnectionLimits,
+
+    /// Servers that we are proxying to (or would be, if they hadn't failed)
+    upstreams: Pool,
 
     slide_windows: Mutex<HashMap<String, SlideWindow>>,
+
+    /// Where the configuration file lives, if there is one
+    config_path: Option<String>,
+
+    /// The current contents of the configuration file. Replaced wholesale when the file is
+    /// reloaded, so connections hold on to an Arc of whatever version they started with.
+    config: parking_lot::RwLock<Arc<Config>>,
+
+    /// Set to true once we stop accepting connections (because another process has taken over, or
+    /// we were told to quit). Connections close as soon as they have no request in progress.
+    shutdown: watch::Sender<bool>,
+
+    /// How many client connections are open, so that we know when we have finished draining
+    open_connections: AtomicUsize,
+
+    /// Whether maintenance mode is on (see the maintenance section of the configuration)
+    maintenance: AtomicBool,
+}
+
+impl ProxyState {
+    fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
 }
 
 struct SlideWindow {
@@ -69,8 +287,7 @@ struct SlideWindow {
     cur_count: usize,
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
@@ -79,66 +296,435 @@ async fn main() {
     }
     pretty_env_logger::init();
 
+    // If we were started by an upgrade, the process we are replacing handed us its listening
+    // sockets. Pick them up before anything else can open files over them. This clears the
+    // environment variables they were passed in, which is only sound while we have no other
+    // threads, so it has to happen before the runtime starts.
+    let inherited = upgrade::Inherited::from_env();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Could not start the tokio runtime")
+        .block_on(run(inherited));
+}
+
+/// Sets everything up and proxies until we shut down
+async fn run(mut inherited: upgrade::Inherited) {
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    match options.command {
+        Some(Command::Bench(bench_options)) => {
+            bench::run(bench_options).await;
+            return;
+        }
+        Some(Command::BenchPool(pool_options)) => {
+            bench::run_pool(pool_options).await;
+            return;
+        }
+        None => {}
+    }
+    if options.upstream.len() < 1 && options.upstream_file.is_none() {
+        log::error!(
+            "At least one upstream server must be specified using the --upstream or \
+            --upstream-file options."
+        );
+        std::process::exit(1);
+    }
+    if options.accept_proxy_protocol && options.trusted_proxies.is_empty() {
+        log::error!(
+            "--accept-proxy-protocol needs at least one --trusted-proxy to accept headers from."
+        );
+        std::process::exit(1);
+    }
+    if options.send_proxy_protocol.is_some() && options.mode != Mode::Tcp {
+        log::error!("--send-proxy-protocol can only be used together with --mode tcp.");
+        std::process::exit(1);
+    }
+    if !options.mirror_upstream.is_empty() && options.mode != Mode::Http {
+        log::error!("--mirror-upstream can only be used in HTTP mode.");
+        std::process::exit(1);
+    }
+    if !(0.0..=100.0).contains(&options.mirror_percent) {
+        log::error!("--mirror-percent must be between 0 and 100.");
+        std::process::exit(1);
+    }
+    let trace_destination = match (options.trace_endpoint, options.trace_file) {
+        (Some(endpoint), _) => match Destination::collector(&endpoint) {
+            Ok(destination) => Some(destination),
+            Err(err) => {
+                log::error!("Invalid --trace-endpoint: {:?}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(path)) => Some(Destination::File(path)),
+        (None, None) => None,
+    };
+    if trace_destination.is_some() && options.mode != Mode::Http {
+        log::error!("Tracing can only be used in HTTP mode.");
         std::process::exit(1);
     }
 
+    let config = match &options.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("Could not load configuration from {}: {:?}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+
     // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
+    let mut listeners = Vec::new();
+    for spec in &options.bind {
+        let (name, address) = parse_bind_address(spec);
+        match upgrade::bind(address, options.reuse_port, &mut inherited).await {
+            Ok(listener) => {
+                log::info!("Listening for requests on {} (listener {})", address, name);
+                listeners.push((address.to_string(), Arc::<str>::from(name), listener));
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", address, err);
+                std::process::exit(1);
+            }
         }
+    }
+    if let Some(store) = &options.rate_limit_store {
+        log::info!("Sharing rate limiting counts through {}", store);
+    }
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => {
+            match upgrade::bind(admin_bind, options.reuse_port, &mut inherited).await {
+                Ok(listener) => {
+                    log::info!("Serving the admin interface on {}", admin_bind);
+                    Some(listener)
+                }
+                Err(err) => {
+                    log::error!("Could not bind to {}: {}", admin_bind, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
     };
-    log::info!("Listening for requests on {}", options.bind);
 
     // Handle incoming connections
-    let mut streams = Vec::new();
-    for stream in options.upstream {
-        streams.push(Arc::new(stream));
-    }
     let state = Arc::new(ProxyState {
-        upstream_addresses: RwLock::new(streams),
+        mode: options.mode,
+        trusted_proxies: if options.accept_proxy_protocol {
+            options.trusted_proxies
+        } else {
+            Vec::new()
+        },
+        send_proxy_protocol: options.send_proxy_protocol,
+        upstream_specs: options.upstream,
+        resolver: Resolver::new(options.hosts_file),
+        dns_refresh_interval: options.dns_refresh_interval,
+        upstream_file_poll_interval: options.upstream_file_poll_interval,
+        connection_limits: ConnectionLimits {
+            max_connections: options.upstream_max_connections,
+            queue_size: options.upstream_queue_size,
+            queue_timeout: Duration::from_millis(options.upstream_queue_timeout_ms),
+            idle_timeout: Duration::from_millis(options.upstream_idle_timeout_ms),
+        },
+        resolved_upstreams: Mutex::new(HashMap::new()),
+        upstreams: Pool::new(),
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
         max_requests_per_minute: options.max_requests_per_minute,
-        failed_upstream_addresses: RwLock::new(Vec::new()),
+        rate_limit_store: options
+            .rate_limit_store
+            .map(|address| RateLimitStore::new(address.trim_start_matches("redis://").to_string())),
+        mirror: Mirror::new(options.mirror_upstream, options.mirror_percent),
+        tracer: trace_destination.map(Tracer::new),
         slide_windows: Mutex::new(HashMap::new()),
+        config_path: options.config,
+        config: parking_lot::RwLock::new(Arc::new(config)),
+        shutdown: watch::channel(false).0,
+        open_connections: AtomicUsize::new(0),
+        maintenance: AtomicBool::new(false),
     });
 
+    refresh_upstreams(&state).await;
+    let mut upstream_file = options.upstream_file.map(UpstreamFile::new);
+    if let Some(upstream_file) = &mut upstream_file {
+        if !refresh_upstream_file(&state, upstream_file).await {
+            std::process::exit(1);
+        }
+    }
+    if state.upstreams.load().is_empty() {
+        // The upstream file may be filled in later on, but command-line upstreams won't change
+        if upstream_file.is_none() {
+            log::error!("None of the upstream servers could be resolved.");
+            std::process::exit(1);
+        }
+        log::warn!("Starting without any upstream servers");
+    }
+
     let state_clone = state.clone();
     tokio::spawn(async move {
         build_task_active_health_check(&state_clone).await;
     });
+    if state.dns_refresh_interval > 0 {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            build_task_refresh_upstreams(&state_clone).await;
+        });
+    }
+    if let Some(mut upstream_file) = upstream_file {
+        let state_clone = state.clone();
+        tokio::spawn(async move {
+            build_task_watch_upstream_file(&state_clone, &mut upstream_file).await;
+        });
+    }
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        reload_config_on_sighup(&state_clone).await;
+    });
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        toggle_maintenance_on_sigusr1(&state_clone).await;
+    });
+    // The listeners stay open until we shut down, which is after any upgrade has finished with
+    // their file descriptors
+    let mut listener_fds: Vec<_> = listeners
+        .iter()
+        .map(|(address, _, listener)| (address.clone(), listener.as_raw_fd()))
+        .collect();
+    if let (Some(admin_bind), Some(admin_listener)) = (&options.admin_bind, &admin_listener) {
+        listener_fds.push((admin_bind.clone(), admin_listener.as_raw_fd()));
+    }
+    let state_clone = state.clone();
+    tokio::spawn(async move {
+        shut_down_on_signal(&state_clone, &listener_fds).await;
+    });
+    if let Some(admin_listener) = admin_listener {
+        tokio::spawn(admin::serve(admin_listener, state.clone()));
+    }
+
+    // We're ready to serve. If we are taking over from another process, it can stop accepting
+    // connections now.
+    if let Some(pid_file) = &options.pid_file {
+        if let Err(err) = std::fs::write(pid_file, format!("{}\n", std::process::id())) {
+            log::error!("Could not write process ID to {}: {}", pid_file, err);
+        }
+    }
+    inherited.notify_ready();
+
+    let accept_tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|(_, name, listener)| tokio::spawn(accept_connections(state.clone(), listener, name)))
+        .collect();
+    for task in accept_tasks {
+        let _ = task.await;
+    }
+    drain_connections(&state, Duration::from_secs(options.drain_timeout)).await;
+}
+
+/// Splits a --bind address written as name=address into the listener's name and address.
+/// Listeners without a name go by their address.
+fn parse_bind_address(spec: &str) -> (&str, &str) {
+    match spec.split_once('=') {
+        Some((name, address)) => (name, address),
+        None => (spec, spec),
+    }
+}
+
+/// Parses a --trusted-proxy network (or "unix")
+fn parse_source(spec: &str) -> Result<Source, String> {
+    Source::try_from(spec.to_string())
+}
 
+/// Accepts connections on one of our listeners until we shut down
+async fn accept_connections(state: Arc<ProxyState>, listener: Listener, name: Arc<str>) {
+    let mut shutdown = state.shutdown.subscribe();
     loop {
-        if let Ok((socket, _)) = listener.accept().await {
-            let state = state.clone();
-            tokio::spawn(async move {
-                handle_connection(socket, &state).await;
-            });
+        tokio::select! {
+            accepted = listener.accept() => {
+                if let Ok((socket, client)) = accepted {
+                    accept_connection(&state, socket, client, &name);
+                }
+            }
+            _ = shutdown.wait_for(|shutting_down| *shutting_down) => break,
         }
     }
+
+    // A process that took over from us shares our listening socket, but with --reuse-port each
+    // process has its own, and connections still queued on ours would be reset when we close it
+    while let Ok(Ok((socket, client))) = timeout(Duration::ZERO, listener.accept()).await {
+        accept_connection(&state, socket, client, &name);
+    }
 }
 
-pub async fn upstream_active_health_check(path: &str, upstream: &str) -> bool {
+/// Starts handling a newly accepted client connection in the background
+fn accept_connection(state: &Arc<ProxyState>, socket: Stream, client: Client, listener: &Arc<str>) {
+    // Behind another proxy, the peer is that proxy rather than the client. The proxy has to be
+    // permitted too; the client is checked once handle_connection has read the PROXY protocol
+    // header.
+    if !state.config().listener(listener).permits(client) {
+        log::info!("Refusing connection from {}: denied by access list", client);
+        return;
+    }
+    state.open_connections.fetch_add(1, Ordering::SeqCst);
+    let state = state.clone();
+    let listener = listener.clone();
+    tokio::spawn(async move {
+        handle_connection(socket, &state, &listener).await;
+        state.open_connections.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Stops accepting connections when we receive SIGUSR2, once a new process started from our
+/// binary (which may have been replaced with a new version) has taken over our listening
+/// sockets, or straight away when we receive SIGQUIT (for when another process is already
+/// listening alongside us with --reuse-port). If the new process fails to start, we keep serving.
+async fn shut_down_on_signal(state: &ProxyState, listener_fds: &[(String, std::os::fd::RawFd)]) {
+    let (mut upgrades, mut quits) = match (
+        signal(SignalKind::user_defined2()),
+        signal(SignalKind::quit()),
+    ) {
+        (Ok(upgrades), Ok(quits)) => (upgrades, quits),
+        (Err(err), _) | (_, Err(err)) => {
+            log::error!(
+                "Could not listen for SIGUSR2 and SIGQUIT; upgrades are disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            Some(_) = upgrades.recv() => {
+                log::info!("Received SIGUSR2, starting a new process to take over");
+                match upgrade::spawn_replacement(listener_fds).await {
+                    Ok(pid) => {
+                        log::info!("Process {} has taken over; draining connections", pid);
+                        break;
+                    }
+                    Err(err) => log::error!("Upgrade failed, carrying on as we were: {:?}", err),
+                }
+            }
+            Some(_) = quits.recv() => {
+                log::info!("Received SIGQUIT; draining connections");
+                break;
+            }
+        }
+    }
+    state.shutdown.send_replace(true);
+}
+
+/// Waits for open connections to finish (or for the timeout to run out) after we stop accepting
+/// new ones
+async fn drain_connections(state: &ProxyState, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = state.open_connections.load(Ordering::SeqCst);
+        if open == 0 {
+            log::info!("All connections have finished; exiting");
+            return;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("Exiting with {} connections still open", open);
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Re-reads the configuration file whenever we receive SIGHUP. If the new file can't be loaded, we
+/// keep running with the old configuration.
+async fn reload_config_on_sighup(state: &ProxyState) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!(
+                "Could not listen for SIGHUP; config reloading is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let path = match &state.config_path {
+            Some(path) => path,
+            None => {
+                log::warn!("Received SIGHUP, but there is no --config file to reload");
+                continue;
+            }
+        };
+        match Config::load(path) {
+            Ok(config) => {
+                *state.config.write() = Arc::new(config);
+                log::info!("Reloaded configuration from {}", path);
+            }
+            Err(err) => log::error!(
+                "Could not reload configuration from {}, keeping the old one: {:?}",
+                path,
+                err
+            ),
+        }
+    }
+}
+
+/// Switches maintenance mode on or off (whichever it isn't) whenever we receive SIGUSR1
+async fn toggle_maintenance_on_sigusr1(state: &ProxyState) {
+    let mut toggles = match signal(SignalKind::user_defined1()) {
+        Ok(toggles) => toggles,
+        Err(err) => {
+            log::error!(
+                "Could not listen for SIGUSR1; maintenance mode can only be switched through the \
+                admin interface: {}",
+                err
+            );
+            return;
+        }
+    };
+    while toggles.recv().await.is_some() {
+        let was_on = state.maintenance.fetch_xor(true, Ordering::SeqCst);
+        log::warn!(
+            "Received SIGUSR1; maintenance mode is now {}",
+            if was_on { "off" } else { "on" }
+        );
+    }
+}
+
+async fn upstream_active_health_check(mode: Mode, path: &str, upstream: &str) -> bool {
+    // In TCP mode we know nothing about the upstream's protocol, so being able to connect is the
+    // best we can do
+    if mode == Mode::Tcp {
+        return match Stream::connect(upstream).await {
+            Ok(_) => true,
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", upstream, err);
+                false
+            }
+        };
+    }
     let request = http::Request::builder()
         .method(http::Method::GET)
         .uri(path)
-        .header("Host", upstream)
+        // Unix domain sockets don't have a host name, but HTTP/1.1 requests need a Host header
+        .header(
+            "Host",
+            stream::unix_path(upstream).map_or(upstream, |_| "localhost"),
+        )
         .body(Vec::new())
         .unwrap();
-    match TcpStream::connect(upstream).await {
+    match Stream::connect(upstream).await {
         Ok(mut stream) => {
             if let Err(error) = request::write_to_stream(&request, &mut stream).await {
                 log::error!("Failed to send request to upstream {}: {}", upstream, error);
                 return false;
             }
-            let res_status = match response::read_from_stream(&mut stream, request.method()).await {
+            let res_status = match response::read_from_stream(
+                &mut stream,
+                request.method(),
+                &limits::MessageLimits::default(),
+            )
+            .await
+            {
                 Ok(response) => response.status().as_u16(),
                 Err(error) => {
                     log::error!("Error reading response from server: {:?}", error);
@@ -154,50 +740,23 @@ pub async fn upstream_active_health_check(path: &str, upstream: &str) -> bool {
     }
 }
 
-async fn filter_upstream_addresses(
-    upstream_addresses: &RwLock<Vec<Arc<String>>>,
-    path: &str,
-    active_flag: bool,
-) -> Vec<Arc<String>> {
-    let upstream_addresses_rd = upstream_addresses.read().await;
-    let mut ret = Vec::new();
-    let mut remain = Vec::new();
-    for upstream in upstream_addresses_rd.iter() {
-        if upstream_active_health_check(path, upstream).await == active_flag {
-            remain.push(upstream.clone());
-        } else {
-            ret.push(upstream.clone());
+/// Health checks every upstream, taking the ones that fail out of rotation and putting the ones
+/// that pass back in. Connections carry on picking upstreams while the checks are in progress.
+async fn active_health_check(state: &ProxyState) {
+    for upstream in state.upstreams.snapshot().iter() {
+        let alive = upstream_active_health_check(
+            state.mode,
+            &state.active_health_check_path,
+            upstream.address(),
+        )
+        .await;
+        if !alive {
+            upstream.mark_failed();
+        } else if upstream.mark_healthy() {
+            // Upstreams that have just recovered may not be up to a full share of traffic yet
+            upstream.health.readmit();
         }
     }
-    drop(upstream_addresses_rd);
-
-    let mut upstream_addresses_wr = upstream_addresses.write().await;
-    upstream_addresses_wr.clear();
-    upstream_addresses_wr.append(&mut remain);
-
-    ret
-}
-
-async fn active_health_check(state: &ProxyState) {
-    let mut reactived_upstreams = filter_upstream_addresses(
-        &state.failed_upstream_addresses,
-        &state.active_health_check_path,
-        false,
-    )
-    .await;
-
-    let mut refailed_upstreams = filter_upstream_addresses(
-        &state.upstream_addresses,
-        &state.active_health_check_path,
-        true,
-    )
-    .await;
-
-    let mut upstream_addresses_wr = state.upstream_addresses.write().await;
-    upstream_addresses_wr.append(&mut reactived_upstreams);
-    drop(upstream_addresses_wr);
-    let mut failed_upstream_addresses_wr = state.failed_upstream_addresses.write().await;
-    failed_upstream_addresses_wr.append(&mut refailed_upstreams);
 }
 
 async fn build_task_active_health_check(state: &ProxyState) {
@@ -217,23 +776,218 @@ async fn build_task_active_health_check(state: &ProxyState) {
     }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
+/// Re-resolves every upstream given on the command line and updates the pool to match: addresses
+/// that appeared are added, and addresses that went away are removed (whether they were healthy or
+/// not). If a name fails to resolve, we keep whatever addresses it had before, since a DNS hiccup
+/// shouldn't take upstreams out of rotation.
+async fn refresh_upstreams(state: &ProxyState) {
+    let mut resolved_upstreams = state.resolved_upstreams.lock().await;
+    for spec in &state.upstream_specs {
+        let (_group, host) = upstream::parse_grouped_address(spec);
+        match state.resolver.resolve(host).await {
+            Ok(addresses) => {
+                if resolved_upstreams.get(spec) != Some(&addresses) {
+                    log::info!("Upstream {} resolves to {:?}", spec, addresses);
+                    resolved_upstreams.insert(spec.clone(), addresses);
+                }
+            }
+            Err(err) => log::error!("Failed to resolve upstream {}: {:?}", spec, err),
+        }
+    }
+    let wanted: HashSet<(String, &String)> = resolved_upstreams
+        .iter()
+        .flat_map(|(spec, addresses)| {
+            let (group, _host) = upstream::parse_grouped_address(spec);
+            addresses
+                .iter()
+                .map(move |address| (group.clone(), address))
+        })
+        .collect();
+    let wanted: Vec<UpstreamSpec> = wanted
+        .into_iter()
+        .map(|(group, address)| UpstreamSpec::new(address.clone(), group))
+        .collect();
+    reconcile_upstreams(state, Origin::CommandLine, &wanted).await;
+}
+
+/// Makes the pool's upstreams from the given origin match `wanted`. New upstreams start out
+/// healthy. Upstreams that are no longer wanted are taken out of the pool, healthy or not, and
+/// drain: connections that are already using them carry on, but nothing new is sent their way.
+/// Upstreams whose weight or tags changed are replaced the same way.
+async fn reconcile_upstreams(state: &ProxyState, origin: Origin, wanted: &[UpstreamSpec]) {
+    state.upstreams.update(|upstreams| {
+        upstreams.retain(|upstream| {
+            let keep = upstream.origin != origin || wanted.contains(&upstream.spec);
+            if !keep {
+                log::info!("Removing upstream {}", upstream.address());
+                upstream.start_draining();
+            }
+            keep
+        });
+        for spec in wanted {
+            let known = upstreams
+                .iter()
+                .any(|known| known.origin == origin && known.spec == *spec);
+            if !known {
+                log::info!(
+                    "Adding upstream {} (group {}, weight {}, tags {:?})",
+                    spec.address,
+                    spec.group,
+                    spec.weight,
+                    spec.tags
+                );
+                upstreams.push(Upstream::new(
+                    spec.clone(),
+                    origin,
+                    &state.connection_limits,
+                ));
+            }
+        }
+    });
+}
+
+async fn build_task_refresh_upstreams(state: &ProxyState) {
+    loop {
+        sleep(Duration::from_secs(state.dns_refresh_interval as u64)).await;
+        refresh_upstreams(state).await;
+    }
+}
+
+/// Re-reads the upstream file and, if it changed, updates the pool to match. Returns false if the
+/// file couldn't be loaded, in which case the pool is left alone.
+async fn refresh_upstream_file(state: &ProxyState, upstream_file: &mut UpstreamFile) -> bool {
+    match upstream_file.poll().await {
+        Ok(Some(wanted)) => {
+            log::info!("Upstream file {} changed", upstream_file.path());
+            reconcile_upstreams(state, Origin::File, &wanted).await;
+            true
+        }
+        Ok(None) => true,
+        Err(err) => {
+            log::error!(
+                "Could not load upstreams from {}: {:?}",
+                upstream_file.path(),
+                err
+            );
+            false
+        }
+    }
+}
+
+async fn build_task_watch_upstream_file(state: &ProxyState, upstream_file: &mut UpstreamFile) {
     loop {
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_addresses_rd = state.upstream_addresses.read().await;
-        let upstream_idx = rng.gen_range(0..upstream_addresses_rd.len());
-        let upstream_ip = &*upstream_addresses_rd[upstream_idx].clone(); // clone并drop，加速
-        drop(upstream_addresses_rd);
-        match TcpStream::connect(upstream_ip).await {
-            Ok(some) => return Ok(some),
+        sleep(Duration::from_secs(
+            state.upstream_file_poll_interval as u64,
+        ))
+        .await;
+        refresh_upstream_file(state, upstream_file).await;
+    }
+}
+
+/// A connection to an upstream, opened on behalf of one client connection
+struct UpstreamConnection {
+    stream: Stream,
+    /// Counts the connection against the upstream for as long as it is open
+    active: ActiveConnection,
+    /// The group we asked for when connecting. The upstream may be in a different group, if the one
+    /// we asked for had no healthy upstreams.
+    requested_group: Option<String>,
+    ip: String,
+}
+
+/// Why connect_to_upstream failed
+#[derive(Debug)]
+enum ConnectError {
+    /// There are no upstreams we could connect to
+    NoUpstreams,
+    /// The upstream we picked is at its connection limit, and we couldn't wait for a free slot
+    Overloaded(#[allow(dead_code)] upstream::AdmissionError),
+}
+
+/// Connects to an upstream picked at random (according to the upstreams' weights), from the given
+/// group if there is one. If none of the group's upstreams are available, any other upstream will
+/// do: serving a request from the wrong group beats not serving it. Upstreams that are at their
+/// connection limit are only picked if all of them are, in which case we queue for a free slot.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    group: Option<&str>,
+    request_id: Option<&str>,
+) -> Result<UpstreamConnection, ConnectError> {
+    // What we are connecting for, for the log: a request, or (in TCP mode) a whole connection
+    let purpose = request_id.map_or_else(String::new, |id| format!(" (request {})", id));
+    let config = state.config();
+    let outlier_detection = config.outlier_detection.as_ref();
+    let pick = |upstreams: &[&Arc<Upstream>]| {
+        let with_free_slot: Vec<&Arc<Upstream>> = upstreams
+            .iter()
+            .filter(|upstream| upstream.has_free_slot())
+            .copied()
+            .collect();
+        upstream::choose_weighted(&with_free_slot, outlier_detection)
+            .or_else(|| upstream::choose_weighted(upstreams, outlier_detection))
+    };
+    loop {
+        let upstream = {
+            let upstreams = state.upstreams.load();
+            let healthy: Vec<&Arc<Upstream>> = upstreams
+                .iter()
+                .filter(|upstream| upstream.is_healthy())
+                .collect();
+            let in_group: Vec<&Arc<Upstream>> = match group {
+                Some(group) => healthy
+                    .iter()
+                    .filter(|upstream| upstream.group() == group)
+                    .copied()
+                    .collect(),
+                None => Vec::new(),
+            };
+            match pick(&in_group).or_else(|| pick(&healthy)) {
+                Some(upstream) => upstream,
+                None => return Err(ConnectError::NoUpstreams),
+            }
+        };
+        let slot = match upstream.admit(&state.connection_limits).await {
+            Ok(slot) => slot,
+            Err(error) => {
+                log::warn!(
+                    "No free connection slot on upstream {}: {:?}{}",
+                    upstream.address(),
+                    error,
+                    purpose
+                );
+                return Err(ConnectError::Overloaded(error));
+            }
+        };
+        match Stream::connect(upstream.address()).await {
+            Ok(stream) => {
+                return Ok(UpstreamConnection {
+                    ip: match &stream {
+                        Stream::Tcp(tcp_stream) => tcp_stream
+                            .peer_addr()
+                            .map(|addr| addr.ip().to_string())
+                            .unwrap_or_else(|_| upstream.address().to_string()),
+                        Stream::Unix(_) | Stream::Tls(_) => upstream.address().to_string(),
+                    },
+                    stream,
+                    active: upstream.track_connection(slot),
+                    requested_group: group.map(str::to_string),
+                })
+            }
             Err(err) => {
-                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-                let mut upstream_addresses_wr = state.upstream_addresses.write().await;
-                let mut failed_upstream_addresses_wr =
-                    state.failed_upstream_addresses.write().await;
-                failed_upstream_addresses_wr.push(upstream_addresses_wr.swap_remove(upstream_idx));
-                if upstream_addresses_wr.is_empty() {
-                    return Err(Error::new(ErrorKind::Other, "No alive upstream!"));
+                log::error!(
+                    "Failed to connect to upstream {}: {}{}",
+                    upstream.address(),
+                    err,
+                    purpose
+                );
+                upstream.mark_failed();
+                if !state
+                    .upstreams
+                    .load()
+                    .iter()
+                    .any(|known| known.is_healthy())
+                {
+                    return Err(ConnectError::NoUpstreams);
                 }
             }
         }
@@ -241,16 +995,132 @@ async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::E
     // DONE: implement failover (milestone 3)
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Feeds the result of a request into outlier detection: the time the upstream took to respond,
+/// or None if the request failed. Ejects the upstream if it now stands out from the rest of the
+/// pool.
+fn record_outcome(
+    state: &ProxyState,
+    config: &Config,
+    upstream: &Arc<Upstream>,
+    latency: Option<Duration>,
+) {
+    let Some(settings) = &config.outlier_detection else {
+        return;
+    };
+    match latency {
+        Some(latency) => upstream.health.record_success(latency),
+        None => upstream.health.record_error(),
+    }
+
+    let upstreams = state.upstreams.load();
+    let healthy: Vec<&Arc<Upstream>> = upstreams
+        .iter()
+        .filter(|upstream| upstream.is_healthy())
+        .collect();
+    let mut other_latencies = Vec::new();
+    let mut ejected = 0;
+    for other in &healthy {
+        if other.health.is_ejected() {
+            ejected += 1;
+        } else if !Arc::ptr_eq(other, upstream) {
+            other_latencies.extend(other.health.latency_ms());
+        }
+    }
+    let health = &upstream.health;
+    if health.is_ejected() || !settings.is_outlier(health, &other_latencies) {
+        return;
+    }
+    // Responses from different upstreams are judged at the same time, so now and then this lets one
+    // upstream more than max_ejection_percent out; the next ejection check makes up for it
+    if !settings.may_eject(ejected, healthy.len()) {
+        log::warn!(
+            "Upstream {} is an outlier, but too many upstreams are ejected already",
+            upstream.address()
+        );
+        return;
+    }
+    // Another response may have ejected the upstream while we were looking
+    let Some(duration) = health.eject(settings) else {
+        return;
+    };
+    log::warn!(
+        "Ejecting upstream {} for {:?}",
+        upstream.address(),
+        duration
+    );
+}
+
+/// Maps an error reading a request to the status of the error response the client gets
+fn read_error_status(error: &request::Error) -> http::StatusCode {
+    match error {
+        request::Error::IncompleteRequest(_)
+        | request::Error::MalformedRequest(_)
+        | request::Error::InvalidContentLength
+        | request::Error::ContentLengthMismatch
+        | request::Error::ConflictingFraming
+        | request::Error::InvalidTransferEncoding
+        | request::Error::MalformedChunk
+        | request::Error::DuplicateHost => http::StatusCode::BAD_REQUEST,
+        request::Error::UnsupportedTransferCoding => http::StatusCode::NOT_IMPLEMENTED,
+        request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+        request::Error::HeadersTooLarge | request::Error::TooManyHeaders => {
+            http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        }
+        request::Error::UnsupportedExpectation => http::StatusCode::EXPECTATION_FAILED,
+        request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Sends a response turning a request down. If the client is still holding the request's body back
+/// waiting for a 100 Continue, we don't want the body, so we tell the client we're closing the
+/// connection instead of reading it. Returns whether the connection can take more requests.
+async fn reject<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    request_id: &str,
+    mut response: http::Response<Vec<u8>>,
+    span: Option<&mut Span>,
+    awaiting_body: bool,
+) -> bool {
+    if awaiting_body {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    }
+    send_response(client_conn, client_ip, request_id, response, span).await;
+    !awaiting_body
+}
+
+/// Sends a response to the client, recording its status on the request's span (if it is being
+/// traced)
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    request_id: &str,
+    mut response: http::Response<Vec<u8>>,
+    span: Option<&mut Span>,
+) {
+    if let Some(span) = span {
+        span.set_status(response.status());
+    }
+    // The client gets the request's ID whoever answered, so that it can quote it to us
+    response.headers_mut().insert(
+        request_id::HEADER,
+        http::HeaderValue::from_str(request_id).unwrap(),
+    );
     log::info!(
-        "{} <- {}",
+        "{} <- {} (request {})",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(&response),
+        request_id
     );
     if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+        log::warn!(
+            "Failed to send response to client: {} (request {})",
+            error,
+            request_id
+        );
     }
 }
 
@@ -291,26 +1161,215 @@ impl SlideWindow {
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Counts a request from the client, and returns whether it puts the client over
+/// max_requests_per_minute. Counts are shared with other instances through the rate limit store
+/// when it is available, and kept locally otherwise.
+async fn should_rate_limit(state: &ProxyState, client_ip: &str) -> bool {
+    if let Some(store) = &state.rate_limit_store {
+        if let Ok(limited) = store
+            .should_rate_limit(client_ip, state.max_requests_per_minute)
+            .await
+        {
+            return limited;
+        }
+    }
+    let mut slide_windows = state.slide_windows.lock().await;
+    let slide_window = slide_windows
+        .entry(client_ip.to_string())
+        .or_insert(SlideWindow::new(
+            state.max_requests_per_minute,
+            60,
+            Instant::now(),
+            0,
+            0,
+        ));
+    slide_window.should_rate_limiting()
+}
+
+/// Copies bytes in both directions between the client and the upstream until both sides have hung
+/// up. This is all that happens to a connection in TCP mode.
+async fn splice_connections(
+    client_conn: &mut Stream,
+    upstream_conn: &mut Stream,
+    client_ip: &str,
+    upstream_ip: &str,
+) {
+    log::info!("{} <-> {}: splicing TCP connection", client_ip, upstream_ip);
+    match tokio::io::copy_bidirectional(client_conn, upstream_conn).await {
+        Ok((to_upstream, to_client)) => log::debug!(
+            "{} <-> {}: connection closed after {} bytes up, {} bytes down",
+            client_ip,
+            upstream_ip,
+            to_upstream,
+            to_client
+        ),
+        Err(err) => log::info!(
+            "{} <-> {}: error while splicing connection: {}",
+            client_ip,
+            upstream_ip,
+            err
+        ),
+    }
+}
+
+/// Serves a client connection accepted by the named listener
+async fn handle_connection(mut client_conn: Stream, state: &ProxyState, listener: &str) {
+    // If we're behind another proxy, the peer is that proxy, and the real client's address comes
+    // from the PROXY protocol header it sends before anything else. Connections over a Unix
+    // domain socket have no addresses, unless a proxy on the other end tells us the client's.
+    let mut client_addresses = match (client_conn.peer_addr(), client_conn.local_addr()) {
+        (Ok(source), Ok(destination)) => Some(proxy_protocol::Addresses {
+            source,
+            destination,
+        }),
+        _ => None,
+    };
+    let client_of = |addresses: Option<proxy_protocol::Addresses>| {
+        addresses.map_or(Client::Unix, |addresses| Client::Ip(addresses.source.ip()))
+    };
+    let peer = client_of(client_addresses);
+    let behind_proxy = state
+        .trusted_proxies
+        .iter()
+        .any(|source| source.matches(peer));
+    if behind_proxy {
+        match proxy_protocol::read_header(&mut client_conn).await {
+            Ok(Some(addresses)) => client_addresses = Some(addresses),
+            Ok(None) => {}
+            Err(error) => {
+                log::info!(
+                    "Dropping connection from {} without a valid PROXY protocol header: {:?}",
+                    peer,
+                    error
+                );
+                return;
+            }
+        }
+    }
+    let client = client_of(client_addresses);
+    if behind_proxy && !state.config().listener(listener).permits(client) {
+        log::info!("Dropping connection from {}: denied by access list", client);
+        return;
+    }
+    let client_ip = client.to_string();
     log::info!("Connection received from {}", client_ip);
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+    // Listeners with TLS settings decrypt their connections here. Everything after this (even in
+    // TCP mode) sees the plaintext.
+    if let Some(acceptor) = state.config().listener(listener).tls_acceptor() {
+        client_conn = match tls::accept(acceptor, client_conn).await {
+            Ok(stream) => stream,
+            Err(error) => {
+                log::info!("TLS handshake with {} failed: {}", client_ip, error);
+                return;
+            }
+        };
+    }
+
+    if state.mode == Mode::Tcp {
+        // Open a connection to a random destination server. There is no way to report a failure
+        // to a TCP client; just hang up on it.
+        let Ok(mut upstream) = connect_to_upstream(state, None, None).await else {
             return;
+        };
+        if let Some(version) = state.send_proxy_protocol {
+            let header = proxy_protocol::encode_header(version, client_addresses.as_ref());
+            if let Err(error) = upstream.stream.write_all(&header).await {
+                log::error!(
+                    "Failed to send PROXY protocol header to upstream {}: {}",
+                    upstream.ip,
+                    error
+                );
+                return;
+            }
         }
-    };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+        splice_connections(
+            &mut client_conn,
+            &mut upstream.stream,
+            &client_ip,
+            &upstream.ip,
+        )
+        .await;
+        return;
+    }
+
+    // In HTTP mode, the upstream connection is opened once we know what the first request needs,
+    // and replaced whenever a later request needs something else
+    let mut upstream_conn: Option<UpstreamConnection> = None;
+    // Requests without a sticky key go to the split group drawn for their connection
+    let split_key: u64 = rand::random();
+
+    // Requests are read through a buffer, which holds on to whatever the client has sent past the
+    // end of the request being read (i.e. the requests it has pipelined behind it)
+    let mut client_conn = BufReader::new(client_conn);
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut shutdown = state.shutdown.subscribe();
+    let mut first_request = true;
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // When we are shutting down, hang up on clients that are between requests. Every
+        // connection gets to send one request, though, since the client has no way of telling
+        // that we hung up on it before reading it.
+        // Clients that go quiet give up their upstream connection when somebody else needs the
+        // slot, or after a while, and get a new one when they send their next request.
+        if !first_request && client_conn.buffer().is_empty() {
+            loop {
+                let upstream = upstream_conn
+                    .as_ref()
+                    .map(|upstream| upstream.active.upstream().clone());
+                tokio::select! {
+                    biased;
+                    // Reading into the buffer rather than waiting for the socket to be readable
+                    // also finds requests a TLS connection has already decrypted
+                    _ = client_conn.fill_buf() => break,
+                    _ = shutdown.wait_for(|shutting_down| *shutting_down) => {
+                        log::debug!("Shutting down; closing idle connection from {}", client_ip);
+                        return;
+                    }
+                    _ = async {
+                        upstream
+                            .as_ref()
+                            .unwrap()
+                            .wait_until_idle_connection_wanted(&state.connection_limits)
+                            .await
+                    }, if upstream.is_some() => {
+                        log::debug!(
+                            "{} is idle; closing its connection to upstream {}",
+                            client_ip,
+                            upstream.unwrap().address()
+                        );
+                        drop(upstream_conn.take());
+                    }
+                }
+            }
+        }
+        first_request = false;
+
+        // Read a request from the client. Every request is handled with the configuration as it
+        // was when we started reading it.
+        let config = state.config();
+        let settings = config.listener(listener);
+        let request_id = request_id::generate();
+        // Clients that want to know whether we'll take a request before sending its body send
+        // "Expect: 100-continue" and hold the body back. Their requests are checked first, and the
+        // body only read (after telling the client to go ahead) if they pass. Every other request
+        // is read in full straight away.
+        let read_result = async {
+            let mut request = request::read_headers_from_stream(
+                &mut client_conn,
+                &settings.largest_request_limits(),
+                |request| settings.request_limits(request.uri().path()),
+            )
+            .await?;
+            if !request::expects_continue(&request) {
+                let limits = settings.request_limits(request.uri().path());
+                request::read_body_from_stream(&mut client_conn, &mut request, &limits).await?;
+            }
+            Ok(request)
+        }
+        .await;
+        let mut request = match read_result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -323,76 +1382,411 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
-                send_response(&mut client_conn, &response).await;
-                continue;
+                log::debug!(
+                    "Error parsing request: {:?} (request {})",
+                    error,
+                    request_id
+                );
+                let status = read_error_status(&error);
+                let mut response =
+                    config
+                        .error_pages
+                        .render(status, settings.error_format(), &request_id);
+                // We can't tell where a request we failed to read ends, and the rest of it may
+                // still be in the stream. Rather than risk taking that for a request of its own, we
+                // don't read any more requests from this connection.
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+                send_response(&mut client_conn, &client_ip, &request_id, response, None).await;
+                return;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+        // Settle on the ID the request is known by from here on (upstream, too). Trusted clients
+        // may have given it one already.
+        let request_id = config.request_ids.assign(&mut request, client, request_id);
+        let awaiting_body = request::expects_continue(&request);
+
+        // Trace the request from here on, if tracing is on. Every span ends (and is exported) when
+        // it goes out of scope.
+        let mut span = state
+            .tracer
+            .as_ref()
+            .map(|tracer| tracer.start_request_span(&request, &client_ip));
+
+        // The configuration may have been reloaded since the connection was accepted, so check the
+        // global and listener access lists again along with the route's
+        let permitted = settings.permits(client)
+            && settings
+                .route_for(request.uri().path())
+                .is_none_or(|route| route.access.permits(client));
+        if !permitted {
+            log::info!(
+                "{} denied access to {} (request {})",
+                client_ip,
+                request.uri().path(),
+                request_id
+            );
+            let response =
+                settings.error_response(http::StatusCode::FORBIDDEN, &request, &request_id);
+            if reject(
+                &mut client_conn,
+                &client_ip,
+                &request_id,
+                response,
+                span.as_mut(),
+                awaiting_body,
+            )
+            .await
+            {
+                continue;
+            }
+            return;
+        }
+
+        // While we're in maintenance mode, requests it applies to get the maintenance page instead
+        if state.maintenance.load(Ordering::SeqCst)
+            && config.maintenance.applies_to(request.uri().path(), client)
+        {
+            let response = settings.maintenance_response(&request, &request_id);
+            if reject(
+                &mut client_conn,
+                &client_ip,
+                &request_id,
+                response,
+                span.as_mut(),
+                awaiting_body,
+            )
+            .await
+            {
+                continue;
+            }
+            return;
+        }
 
         // DONE: rate limiting here
-        if state.max_requests_per_minute > 0 {
-            let mut slide_windows = state.slide_windows.lock().await;
-            let slide_window = slide_windows
-                .entry(client_ip.clone())
-                .or_insert(SlideWindow::new(
-                    state.max_requests_per_minute,
-                    60,
-                    Instant::now(),
-                    0,
-                    0,
-                ));
-            if slide_window.should_rate_limiting() {
-                let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                send_response(&mut client_conn, &response).await;
+        if state.max_requests_per_minute > 0 && should_rate_limit(state, &client_ip).await {
+            let response =
+                settings.error_response(http::StatusCode::TOO_MANY_REQUESTS, &request, &request_id);
+            if reject(
+                &mut client_conn,
+                &client_ip,
+                &request_id,
+                response,
+                span.as_mut(),
+                awaiting_body,
+            )
+            .await
+            {
                 continue;
             }
+            return;
         }
 
+        // Check the client's credentials, if the route needs any. This comes after rate limiting,
+        // so that clients can't guess passwords any faster than they can send requests.
+        if let Some(auth) = settings
+            .route_for(request.uri().path())
+            .and_then(|route| route.auth.as_ref())
+        {
+            if let Err(rejection) = auth.authenticate(&mut request).await {
+                log::info!(
+                    "{} failed to authenticate for {}: {:?} (request {})",
+                    client_ip,
+                    request.uri().path(),
+                    rejection,
+                    request_id
+                );
+                let mut response =
+                    settings.error_response(rejection.status(), &request, &request_id);
+                if let (auth::Rejection::Unauthenticated(_), Some(challenge)) =
+                    (&rejection, auth.challenge())
+                {
+                    match http::HeaderValue::from_str(&challenge) {
+                        Ok(challenge) => {
+                            response
+                                .headers_mut()
+                                .insert(http::header::WWW_AUTHENTICATE, challenge);
+                        }
+                        Err(_) => log::warn!(
+                            "Can't send {:?} as a WWW-Authenticate header (request {})",
+                            challenge,
+                            request_id
+                        ),
+                    }
+                }
+                if reject(
+                    &mut client_conn,
+                    &client_ip,
+                    &request_id,
+                    response,
+                    span.as_mut(),
+                    awaiting_body,
+                )
+                .await
+                {
+                    continue;
+                }
+                return;
+            }
+        }
+
+        // The request has passed every check, so it's worth having the client send its body now
+        if awaiting_body {
+            let read_result = async {
+                client_conn
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await
+                    .map_err(request::Error::ConnectionError)?;
+                let limits = settings.request_limits(request.uri().path());
+                request::read_body_from_stream(&mut client_conn, &mut request, &limits).await
+            }
+            .await;
+            if let Err(error) = read_result {
+                log::info!(
+                    "Error reading request body from {}: {:?} (request {})",
+                    client_ip,
+                    error,
+                    request_id
+                );
+                if !matches!(error, request::Error::ConnectionError(_)) {
+                    let response =
+                        settings.error_response(read_error_status(&error), &request, &request_id);
+                    send_response(
+                        &mut client_conn,
+                        &client_ip,
+                        &request_id,
+                        response,
+                        span.as_mut(),
+                    )
+                    .await;
+                }
+                return;
+            }
+        }
+
+        // Routes we answer ourselves never get as far as an upstream
+        if let Some(route) = settings.route_for(request.uri().path()) {
+            if let Some(local) = &route.respond {
+                log::info!(
+                    "{} -> local: {} (request {})",
+                    client_ip,
+                    request::format_request_line(&request),
+                    request_id
+                );
+                let (mut response, file) = match local.respond(&request, &route.prefix).await {
+                    Ok(response) => response,
+                    Err(rejection) => {
+                        let mut response =
+                            settings.error_response(rejection.status, &request, &request_id);
+                        response.headers_mut().extend(rejection.headers);
+                        (response, None)
+                    }
+                };
+                if *shutdown.borrow() {
+                    response.headers_mut().insert(
+                        http::header::CONNECTION,
+                        http::HeaderValue::from_static("close"),
+                    );
+                }
+                send_response(
+                    &mut client_conn,
+                    &client_ip,
+                    &request_id,
+                    response,
+                    span.as_mut(),
+                )
+                .await;
+                if let Some(file) = file {
+                    if let Err(error) = file.send_to(&mut client_conn).await {
+                        log::warn!(
+                            "Failed to send file to client: {} (request {})",
+                            error,
+                            request_id
+                        );
+                        return;
+                    }
+                }
+                if *shutdown.borrow() {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        // Keep using the upstream we already have, unless the request should go to a different
+        // group, or the upstream has been removed from the pool. In that case we let the upstream
+        // drain: it has answered everything we sent it, so the rest of this client's requests can
+        // move to an upstream still in the pool.
+        let group = config
+            .split
+            .as_ref()
+            .and_then(|split| split.choose_group(&request, split_key));
+        let reusable = upstream_conn.as_ref().is_some_and(|upstream| {
+            upstream.requested_group.as_deref() == group
+                && !upstream.active.upstream().is_draining()
+        });
+        if !reusable {
+            // Hang up on the old upstream first, so it doesn't wait on us to finish draining
+            drop(upstream_conn.take());
+            let mut connect_span = span
+                .as_ref()
+                .map(|span| span.child("upstream connect", SpanKind::Internal));
+            match connect_to_upstream(state, group, Some(&request_id)).await {
+                Ok(upstream) => {
+                    if let Some(connect_span) = &mut connect_span {
+                        connect_span
+                            .set_attribute("server.address", upstream.active.upstream().address());
+                    }
+                    upstream_conn = Some(upstream)
+                }
+                Err(error) => {
+                    if let Some(connect_span) = &mut connect_span {
+                        connect_span.set_error();
+                    }
+                    drop(connect_span);
+                    let status = match error {
+                        ConnectError::NoUpstreams => http::StatusCode::BAD_GATEWAY,
+                        ConnectError::Overloaded(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                    };
+                    let response = settings.error_response(status, &request, &request_id);
+                    send_response(
+                        &mut client_conn,
+                        &client_ip,
+                        &request_id,
+                        response,
+                        span.as_mut(),
+                    )
+                    .await;
+                    return;
+                }
+            }
+        }
+        let upstream = upstream_conn.as_mut().unwrap();
+
+        log::info!(
+            "{} -> {}: {} (request {})",
+            client_ip,
+            upstream.ip,
+            request::format_request_line(&request),
+            request_id
+        );
+
         // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
         // (We're the ones connecting directly to the upstream server, so without this header, the
         // upstream server will only know our IP, not the client's.)
         request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
 
+        // We have the whole body by now, so there's nothing left for the upstream to agree to
+        request.headers_mut().remove(http::header::EXPECT);
+
+        // Hand the trace on to the upstream, with the span for the upstream's part as its parent
+        let mut upstream_span = span.as_ref().map(|span| {
+            let mut upstream_span = span.child("upstream response", SpanKind::Client);
+            upstream_span.set_attribute("server.address", upstream.active.upstream().address());
+            upstream_span
+        });
+        if let Some(upstream_span) = &upstream_span {
+            request.headers_mut().insert(
+                "traceparent",
+                http::HeaderValue::from_str(&upstream_span.traceparent()).unwrap(),
+            );
+        }
+
+        // Copy the request to the shadow pool, if we have one. This happens in the background, so
+        // it doesn't hold up the real request.
+        state
+            .mirror
+            .maybe_mirror(&request, settings.response_limits(request.uri().path()));
+
         // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+        let sent_at = Instant::now();
+        if let Err(error) = request::write_to_stream(&request, &mut upstream.stream).await {
             log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
+                "Failed to send request to upstream {}: {} (request {})",
+                upstream.ip,
+                error,
+                request_id
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+            record_outcome(state, &config, upstream.active.upstream(), None);
+            if let Some(upstream_span) = &mut upstream_span {
+                upstream_span.set_error();
+            }
+            let response =
+                settings.error_response(http::StatusCode::BAD_GATEWAY, &request, &request_id);
+            send_response(
+                &mut client_conn,
+                &client_ip,
+                &request_id,
+                response,
+                span.as_mut(),
+            )
+            .await;
             return;
         }
-        log::debug!("Forwarded request to server");
+        log::debug!("Forwarded request to server (request {})", request_id);
 
         // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
+        let response = match response::read_from_stream(
+            &mut upstream.stream,
+            request.method(),
+            &settings.response_limits(request.uri().path()),
+        )
+        .await
         {
             Ok(response) => response,
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                log::error!(
+                    "Error reading response from server: {:?} (request {})",
+                    error,
+                    request_id
+                );
+                record_outcome(state, &config, upstream.active.upstream(), None);
+                if let Some(upstream_span) = &mut upstream_span {
+                    upstream_span.set_error();
+                }
+                let response =
+                    settings.error_response(http::StatusCode::BAD_GATEWAY, &request, &request_id);
+                send_response(
+                    &mut client_conn,
+                    &client_ip,
+                    &request_id,
+                    response,
+                    span.as_mut(),
+                )
+                .await;
                 return;
             }
         };
+        let latency = (!response.status().is_server_error()).then(|| sent_at.elapsed());
+        record_outcome(state, &config, upstream.active.upstream(), latency);
+        if let Some(upstream_span) = &mut upstream_span {
+            upstream_span.set_status(response.status());
+        }
+        drop(upstream_span);
+
+        // Don't let the client send any more requests if we are shutting down
+        let mut response = response;
+        if *shutdown.borrow() {
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        }
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        send_response(
+            &mut client_conn,
+            &client_ip,
+            &request_id,
+            response,
+            span.as_mut(),
+        )
+        .await;
+        log::debug!("Forwarded response to client (request {})", request_id);
+        if *shutdown.borrow() {
+            return;
+        }
     }
 }
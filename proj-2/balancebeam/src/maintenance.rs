@@ -0,0 +1,67 @@
+use crate::acl::{Client, Source};
+use crate::config::path_has_prefix;
+use crate::error_page;
+use serde::Deserialize;
+
+/// What balancebeam does while maintenance mode is on. The mode itself is switched on and off at
+/// runtime (through the admin interface, or with SIGUSR1), so that it can be flipped during an
+/// incident without touching the configuration file.
+///
+/// Requests it applies to get a 503 page with a Retry-After header instead of being forwarded.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Maintenance {
+    /// Path prefixes maintenance mode applies to (matched like route prefixes). If empty, it
+    /// applies to every path.
+    pub prefixes: Vec<String>,
+    /// Clients that are let through to the upstreams anyway, e.g. to check on a fix
+    pub allow: Vec<Source>,
+    /// How many seconds clients are told to wait before trying again
+    pub retry_after: u64,
+    /// Path of an HTML template for the maintenance page, with the same placeholders as the error
+    /// page templates. Without one, the page is the usual 503 error page.
+    pub html: Option<String>,
+    /// The contents of the template at `html`, read when the configuration is loaded
+    #[serde(skip)]
+    template: Option<String>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Maintenance {
+        Maintenance {
+            prefixes: Vec::new(),
+            allow: Vec::new(),
+            retry_after: 300,
+            html: None,
+            template: None,
+        }
+    }
+}
+
+impl Maintenance {
+    /// Reads the HTML template, if there is one
+    pub fn load_template(&mut self) -> Result<(), error_page::Error> {
+        if let Some(path) = &self.html {
+            let template = std::fs::read_to_string(path)
+                .map_err(|error| error_page::Error::UnreadableTemplate(path.clone(), error))?;
+            self.template = Some(template);
+        }
+        Ok(())
+    }
+
+    /// Returns whether a request for the given path from the given client is held back while
+    /// maintenance mode is on
+    pub fn applies_to(&self, path: &str, client: Client) -> bool {
+        let path_matches = self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| path_has_prefix(path, prefix));
+        path_matches && !self.allow.iter().any(|source| source.matches(client))
+    }
+
+    /// The maintenance page template, if one is configured
+    pub fn template(&self) -> Option<&str> {
+        self.template.as_deref()
+    }
+}
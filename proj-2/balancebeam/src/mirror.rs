@@ -0,0 +1,108 @@
+use crate::limits::MessageLimits;
+use crate::stream::Stream;
+use crate::{request, request_id, response};
+use rand::Rng;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration};
+
+/// How many mirrored requests may be outstanding at once. Beyond this, requests simply aren't
+/// mirrored, so a slow shadow pool can't make us pile up tasks and connections.
+const MAX_IN_FLIGHT: usize = 128;
+
+/// How long we give a shadow upstream to answer a mirrored request
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Copies a share of the requests we proxy to a shadow pool of upstreams (e.g. a new version of a
+/// service being tested against production traffic). Mirrored requests are fire-and-forget: they
+/// are sent from a separate task, and the shadow upstreams' responses are read and thrown away.
+pub struct Mirror {
+    upstreams: Vec<String>,
+    /// Percentage of requests to mirror, from 0 to 100
+    percent: f64,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirror {
+    pub fn new(upstreams: Vec<String>, percent: f64) -> Mirror {
+        Mirror {
+            upstreams,
+            percent,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Decides whether to mirror the request and if so, sends a copy of it to a random shadow
+    /// upstream in the background. Returns immediately either way.
+    pub fn maybe_mirror(&self, request: &http::Request<Vec<u8>>, response_limits: MessageLimits) {
+        let mut rng = rand::thread_rng();
+        if self.upstreams.is_empty() || rng.gen_range(0.0..100.0) >= self.percent {
+            return;
+        }
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::warn!(
+                    "Too many mirrored requests in flight; not mirroring request {}",
+                    request_id::of(request.headers())
+                );
+                return;
+            }
+        };
+        let upstream = self.upstreams[rng.gen_range(0..self.upstreams.len())].clone();
+        let request = copy_request(request);
+        tokio::spawn(async move {
+            match timeout(
+                MIRROR_TIMEOUT,
+                send_mirrored_request(&upstream, &request, &response_limits),
+            )
+            .await
+            {
+                Ok(Ok(response)) => log::debug!(
+                    "Mirror {} answered {} (request {})",
+                    upstream,
+                    response::format_response_line(&response),
+                    request_id::of(request.headers())
+                ),
+                Ok(Err(error)) => log::debug!(
+                    "Mirroring to {} failed: {} (request {})",
+                    upstream,
+                    error,
+                    request_id::of(request.headers())
+                ),
+                Err(_) => log::debug!(
+                    "Mirror {} timed out (request {})",
+                    upstream,
+                    request_id::of(request.headers())
+                ),
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn send_mirrored_request(
+    upstream: &str,
+    request: &http::Request<Vec<u8>>,
+    response_limits: &MessageLimits,
+) -> Result<http::Response<Vec<u8>>, String> {
+    let mut stream = Stream::connect(upstream)
+        .await
+        .map_err(|error| format!("could not connect: {}", error))?;
+    request::write_to_stream(request, &mut stream)
+        .await
+        .map_err(|error| format!("could not send request: {}", error))?;
+    response::read_from_stream(&mut stream, request.method(), response_limits)
+        .await
+        .map_err(|error| format!("could not read response: {:?}", error))
+}
+
+/// http::Request isn't Clone (its extensions might not be), but everything we put in one is
+fn copy_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
@@ -0,0 +1,402 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How much weight the latest request's latency gets in an upstream's average latency
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// How many requests an upstream must have answered before we judge its latency
+const MIN_LATENCY_SAMPLES: u32 = 5;
+
+/// The share of its weight an upstream gets at the very start of slow start
+const SLOW_START_MIN_FACTOR: f64 = 0.1;
+
+/// Settings for passively spotting upstreams that misbehave compared to the rest of the pool
+/// (outlier detection), and for easing upstreams back into the pool (slow start).
+///
+/// An ejected upstream gets no new connections until its ejection runs out. Every ejection lasts
+/// twice as long as the previous one (up to max_ejection_ms), unless the upstream has stayed out of
+/// trouble for max_ejection_ms since it was last ejected.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierDetection {
+    /// Eject an upstream after this many failed requests in a row (5xx responses count as
+    /// failures). 0 disables this check.
+    pub consecutive_errors: u32,
+    /// Eject an upstream whose average latency is this many times the median of the other
+    /// upstreams' averages. 0 disables this check.
+    pub latency_factor: f64,
+    /// Never eject an upstream for latency unless its average latency is at least this high
+    pub min_latency_ms: u64,
+    /// How long the first ejection lasts
+    pub base_ejection_ms: u64,
+    /// The longest an ejection may last
+    pub max_ejection_ms: u64,
+    /// Never eject more than this percentage of the pool at once
+    pub max_ejection_percent: u32,
+    /// When an upstream comes back (from an ejection, or after failing health checks), ramp its
+    /// share of traffic up over this long, rather than handing it a full share at once
+    pub slow_start_ms: u64,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> OutlierDetection {
+        OutlierDetection {
+            consecutive_errors: 5,
+            latency_factor: 0.0,
+            min_latency_ms: 100,
+            base_ejection_ms: 30000,
+            max_ejection_ms: 300000,
+            max_ejection_percent: 50,
+            slow_start_ms: 0,
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// Returns whether an upstream with the given health stands out from the rest of the pool,
+    /// whose (non-ejected) average latencies are given in other_latencies_ms
+    pub fn is_outlier(&self, health: &Health, other_latencies_ms: &[f64]) -> bool {
+        if self.consecutive_errors > 0 && health.consecutive_errors() >= self.consecutive_errors {
+            return true;
+        }
+        if self.latency_factor <= 0.0 || health.latency_samples() < MIN_LATENCY_SAMPLES {
+            return false;
+        }
+        let (Some(latency_ms), Some(median)) = (health.latency_ms(), median(other_latencies_ms))
+        else {
+            return false;
+        };
+        latency_ms >= self.min_latency_ms as f64 && latency_ms > median * self.latency_factor
+    }
+
+    /// Returns whether ejecting one more upstream would keep us within max_ejection_percent
+    pub fn may_eject(&self, already_ejected: usize, pool_size: usize) -> bool {
+        (already_ejected + 1) * 100 <= pool_size * self.max_ejection_percent as usize
+    }
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    Some(sorted[sorted.len() / 2])
+}
+
+/// What we have seen of an upstream's recent requests. Every connection to the upstream reads this
+/// and every response updates it, so it is kept in atomics rather than behind a lock. Points in
+/// time are stored as nanoseconds since EPOCH, with 0 for "never".
+#[derive(Debug, Default)]
+pub struct Health {
+    consecutive_errors: AtomicU32,
+    /// Moving average of the time the upstream took to respond (the bits of an f64)
+    latency_ms: AtomicU64,
+    latency_samples: AtomicU32,
+    /// Number of ejections in the current run of ejections, which sets the length of the next one
+    times_ejected: AtomicU32,
+    ejected_until: AtomicU64,
+    /// When the upstream last came back into the pool
+    returned_at: AtomicU64,
+}
+
+/// The point in time Health's timestamps count from
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+fn to_timestamp(instant: Instant) -> u64 {
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    (instant.saturating_duration_since(epoch).as_nanos() as u64).max(1)
+}
+
+fn from_timestamp(timestamp: u64) -> Option<Instant> {
+    (timestamp != 0).then(|| *EPOCH.get_or_init(Instant::now) + Duration::from_nanos(timestamp))
+}
+
+impl Health {
+    pub fn record_success(&self, latency: Duration) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+        // The average and the sample count may be updated by several responses at once; an average
+        // that misses one of them now and then does no harm
+        let samples = self.latency_samples.load(Ordering::Relaxed);
+        let _ = self
+            .latency_ms
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                let average = f64::from_bits(average);
+                let updated = if samples == 0 {
+                    latency_ms
+                } else {
+                    LATENCY_SMOOTHING * latency_ms + (1.0 - LATENCY_SMOOTHING) * average
+                };
+                Some(updated.to_bits())
+            });
+        let _ =
+            self.latency_samples
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |samples| {
+                    Some(samples.saturating_add(1))
+                });
+    }
+
+    pub fn record_error(&self) {
+        let _ =
+            self.consecutive_errors
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |errors| {
+                    Some(errors.saturating_add(1))
+                });
+    }
+
+    fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    fn latency_samples(&self) -> u32 {
+        self.latency_samples.load(Ordering::Relaxed)
+    }
+
+    /// The upstream's average latency, if it has answered any requests since it was last ejected
+    pub fn latency_ms(&self) -> Option<f64> {
+        (self.latency_samples() > 0)
+            .then(|| f64::from_bits(self.latency_ms.load(Ordering::Relaxed)))
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.is_ejected_at(Instant::now())
+    }
+
+    fn is_ejected_at(&self, now: Instant) -> bool {
+        from_timestamp(self.ejected_until.load(Ordering::Acquire))
+            .is_some_and(|ejected_until| now < ejected_until)
+    }
+
+    /// Ejects the upstream and returns how long for, or None if another response ejected it
+    /// first. Its stats start over once it comes back.
+    pub fn eject(&self, settings: &OutlierDetection) -> Option<Duration> {
+        self.eject_at(settings, Instant::now())
+    }
+
+    fn eject_at(&self, settings: &OutlierDetection, now: Instant) -> Option<Duration> {
+        let previous = self.ejected_until.load(Ordering::Acquire);
+        let previous_until = from_timestamp(previous);
+        if previous_until.is_some_and(|ejected_until| now < ejected_until) {
+            return None;
+        }
+        let max_ejection = Duration::from_millis(settings.max_ejection_ms);
+        let mut times_ejected = self.times_ejected.load(Ordering::Relaxed);
+        if previous_until
+            .is_some_and(|ejected_until| now.duration_since(ejected_until) > max_ejection)
+        {
+            times_ejected = 0;
+        }
+        let duration = Duration::from_millis(settings.base_ejection_ms)
+            .saturating_mul(2_u32.saturating_pow(times_ejected))
+            .min(max_ejection);
+        let until = to_timestamp(now + duration);
+        // Whoever swaps in the new ejection gets to make it
+        if self
+            .ejected_until
+            .compare_exchange(previous, until, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return None;
+        }
+        self.times_ejected
+            .store(times_ejected.saturating_add(1), Ordering::Relaxed);
+        self.returned_at.store(until, Ordering::Relaxed);
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+        self.latency_samples.store(0, Ordering::Relaxed);
+        Some(duration)
+    }
+
+    /// Notes that the upstream just came back into the pool (after failing health checks), so
+    /// that it goes through slow start
+    pub fn readmit(&self) {
+        self.returned_at
+            .store(to_timestamp(Instant::now()), Ordering::Relaxed);
+    }
+
+    /// Returns the fraction of its configured weight the upstream should get right now: none while
+    /// it is ejected, and a growing share while it is in slow start
+    pub fn weight_factor(&self, settings: Option<&OutlierDetection>) -> f64 {
+        self.weight_factor_at(settings, Instant::now())
+    }
+
+    fn weight_factor_at(&self, settings: Option<&OutlierDetection>, now: Instant) -> f64 {
+        if self.is_ejected_at(now) {
+            return 0.0;
+        }
+        let returned_at = from_timestamp(self.returned_at.load(Ordering::Relaxed));
+        let (Some(settings), Some(returned_at)) = (settings, returned_at) else {
+            return 1.0;
+        };
+        if settings.slow_start_ms == 0 {
+            return 1.0;
+        }
+        let progress = now.saturating_duration_since(returned_at).as_secs_f64()
+            / Duration::from_millis(settings.slow_start_ms).as_secs_f64();
+        progress.clamp(SLOW_START_MIN_FACTOR, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(
+        base_ejection_ms: u64,
+        max_ejection_ms: u64,
+        slow_start_ms: u64,
+    ) -> OutlierDetection {
+        OutlierDetection {
+            base_ejection_ms,
+            max_ejection_ms,
+            slow_start_ms,
+            ..OutlierDetection::default()
+        }
+    }
+
+    /// A point in time that Health's timestamps can represent (they can't go back past EPOCH)
+    fn start() -> Instant {
+        EPOCH.get_or_init(Instant::now);
+        Instant::now()
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Every ejection in a run lasts twice as long as the one before, up to max_ejection_ms
+    #[test]
+    fn ejections_double_up_to_the_max() {
+        let settings = settings(1000, 5000, 0);
+        let health = Health::default();
+        let mut now = start();
+        for expected in [1000, 2000, 4000, 5000, 5000] {
+            let duration = health.eject_at(&settings, now).unwrap();
+            assert_eq!(duration, ms(expected));
+            assert!(health.is_ejected_at(now + duration - ms(1)));
+            assert!(!health.is_ejected_at(now + duration));
+            now += duration;
+        }
+    }
+
+    /// An upstream that stays out of trouble for max_ejection_ms after an ejection starts over
+    /// with the base ejection
+    #[test]
+    fn ejection_run_resets() {
+        let settings = settings(1000, 5000, 0);
+        let health = Health::default();
+        let now = start();
+        assert_eq!(health.eject_at(&settings, now), Some(ms(1000)));
+        assert_eq!(health.eject_at(&settings, now + ms(6000)), Some(ms(2000)));
+        assert_eq!(health.eject_at(&settings, now + ms(13001)), Some(ms(1000)));
+    }
+
+    /// An ejected upstream can't be ejected again (e.g. by a response that was already on its way)
+    #[test]
+    fn no_ejection_while_ejected() {
+        let settings = settings(1000, 5000, 0);
+        let health = Health::default();
+        let now = start();
+        assert_eq!(health.eject_at(&settings, now), Some(ms(1000)));
+        assert_eq!(health.eject_at(&settings, now + ms(999)), None);
+        assert_eq!(health.eject_at(&settings, now + ms(1000)), Some(ms(2000)));
+    }
+
+    /// An ejection wipes the upstream's stats, so it isn't judged on them again when it comes back
+    #[test]
+    fn ejection_resets_stats() {
+        let settings = settings(1000, 5000, 0);
+        let health = Health::default();
+        health.record_success(ms(50));
+        health.record_error();
+        health.eject_at(&settings, start()).unwrap();
+        assert_eq!(health.consecutive_errors(), 0);
+        assert_eq!(health.latency_ms(), None);
+    }
+
+    /// An upstream gets no weight while ejected, then ramps up from SLOW_START_MIN_FACTOR to its
+    /// full weight over slow_start_ms
+    #[test]
+    fn slow_start_curve() {
+        let settings = settings(1000, 5000, 10000);
+        let health = Health::default();
+        let now = start();
+        let back = now + health.eject_at(&settings, now).unwrap();
+        let factor = |at| health.weight_factor_at(Some(&settings), at);
+        assert_eq!(factor(now), 0.0);
+        assert_eq!(factor(back - ms(1)), 0.0);
+        assert_eq!(factor(back), SLOW_START_MIN_FACTOR);
+        assert_eq!(factor(back + ms(500)), SLOW_START_MIN_FACTOR);
+        assert!((factor(back + ms(2500)) - 0.25).abs() < 1e-9);
+        assert!((factor(back + ms(5000)) - 0.5).abs() < 1e-9);
+        assert_eq!(factor(back + ms(10000)), 1.0);
+        assert_eq!(factor(back + ms(60000)), 1.0);
+    }
+
+    /// Without slow start, an upstream gets its full weight as soon as it comes back
+    #[test]
+    fn no_slow_start() {
+        let settings = settings(1000, 5000, 0);
+        let health = Health::default();
+        let now = start();
+        assert_eq!(health.weight_factor_at(Some(&settings), now), 1.0);
+        let back = now + health.eject_at(&settings, now).unwrap();
+        assert_eq!(health.weight_factor_at(Some(&settings), back - ms(1)), 0.0);
+        assert_eq!(health.weight_factor_at(Some(&settings), back), 1.0);
+    }
+
+    #[test]
+    fn consecutive_errors() {
+        let settings = OutlierDetection {
+            consecutive_errors: 3,
+            ..OutlierDetection::default()
+        };
+        let health = Health::default();
+        health.record_error();
+        health.record_error();
+        assert!(!settings.is_outlier(&health, &[]));
+        health.record_error();
+        assert!(settings.is_outlier(&health, &[]));
+        health.record_success(ms(10));
+        assert!(!settings.is_outlier(&health, &[]));
+    }
+
+    /// An upstream is a latency outlier once it has enough samples, is slower than min_latency_ms
+    /// and is more than latency_factor times slower than the median of the others
+    #[test]
+    fn latency_outliers() {
+        let settings = OutlierDetection {
+            latency_factor: 3.0,
+            min_latency_ms: 100,
+            ..OutlierDetection::default()
+        };
+        let health = Health::default();
+        for _ in 0..MIN_LATENCY_SAMPLES - 1 {
+            health.record_success(ms(400));
+        }
+        assert!(!settings.is_outlier(&health, &[100.0]));
+        health.record_success(ms(400));
+        assert!(settings.is_outlier(&health, &[100.0, 10.0, 120.0]));
+        assert!(!settings.is_outlier(&health, &[150.0, 10.0, 200.0]));
+        // Too quick to bother with, however much slower than the others it is
+        assert!(!OutlierDetection {
+            min_latency_ms: 500,
+            ..settings.clone()
+        }
+        .is_outlier(&health, &[1.0]));
+        // Nothing to compare with
+        assert!(!settings.is_outlier(&health, &[]));
+    }
+
+    #[test]
+    fn max_ejection_percent() {
+        let settings = OutlierDetection::default();
+        assert!(settings.may_eject(0, 2));
+        assert!(!settings.may_eject(1, 2));
+        assert!(!settings.may_eject(0, 1));
+        assert!(settings.may_eject(4, 10));
+        assert!(!settings.may_eject(5, 10));
+    }
+}
@@ -0,0 +1,245 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The 12 bytes every version 2 header starts with
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+/// A version 1 header (including the trailing \r\n) may be at most this long
+const V1_MAX_LENGTH: usize = 107;
+/// How long a proxy gets to send its header. Proxies send it as soon as they connect, so only a
+/// stuck proxy (or someone holding connections open) takes anywhere near this long.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which version of the PROXY protocol to speak when sending headers to upstreams
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// The connection did not start with a PROXY protocol signature
+    MissingHeader,
+    /// The connection started with a PROXY protocol signature, but the rest of the header is
+    /// invalid
+    MalformedHeader(#[allow(dead_code)] &'static str),
+    /// Encountered an I/O error when reading from the connection
+    ConnectionError(#[allow(dead_code)] std::io::Error),
+    /// The header didn't arrive within HEADER_TIMEOUT
+    Timeout,
+}
+
+/// The addresses of the original connection, as reported by whoever sent us a PROXY header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Addresses {
+    /// The client that opened the original connection
+    pub source: SocketAddr,
+    /// The address the client originally connected to
+    pub destination: SocketAddr,
+}
+
+/// Reads a PROXY protocol header (version 1 or 2) from the start of a connection. Returns
+/// Ok(Some(Addresses)) if the header describes a proxied TCP connection, or Ok(None) if the sender
+/// didn't pass on any addresses (e.g. v1 "UNKNOWN" or v2 "LOCAL" headers, which are used for the
+/// sender's own health checks). In that case the connection's peer address should be used.
+///
+/// Exactly the bytes of the header are consumed, so the stream can be handed off to the HTTP parser
+/// (or spliced to an upstream) afterwards. Gives up after HEADER_TIMEOUT.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    tokio::time::timeout(HEADER_TIMEOUT, read_any_header(stream))
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+/// Reads a header of either version (see read_header)
+async fn read_any_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    // Every v2 header is at least 16 bytes long and the shortest v1 header ("PROXY UNKNOWN\r\n")
+    // is 15 bytes, so it's safe to read 12 bytes before deciding which version we are looking at
+    let mut buffer = [0_u8; 12];
+    stream
+        .read_exact(&mut buffer)
+        .await
+        .map_err(Error::ConnectionError)?;
+    if buffer == V2_SIGNATURE {
+        read_v2_header(stream).await
+    } else if buffer.starts_with(b"PROXY ") {
+        read_v1_header(stream, &buffer).await
+    } else {
+        Err(Error::MissingHeader)
+    }
+}
+
+/// Reads the rest of a v1 header, given the first bytes of it. The header is terminated by \r\n,
+/// and we must not read past that, so the remainder is read one byte at a time. This only happens
+/// once per connection, and the header is short.
+async fn read_v1_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> Result<Option<Addresses>, Error> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(Error::MalformedHeader("v1 header is too long"));
+        }
+        let byte = stream.read_u8().await.map_err(Error::ConnectionError)?;
+        line.push(byte);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| Error::MalformedHeader("v1 header is not valid text"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {}
+        _ => return Err(Error::MalformedHeader("unsupported v1 address family")),
+    }
+    let source_ip: IpAddr = fields[2]
+        .parse()
+        .map_err(|_| Error::MalformedHeader("invalid v1 source address"))?;
+    let destination_ip: IpAddr = fields[3]
+        .parse()
+        .map_err(|_| Error::MalformedHeader("invalid v1 destination address"))?;
+    let source_port: u16 = fields[4]
+        .parse()
+        .map_err(|_| Error::MalformedHeader("invalid v1 source port"))?;
+    let destination_port: u16 = fields[5]
+        .parse()
+        .map_err(|_| Error::MalformedHeader("invalid v1 destination port"))?;
+    if (fields[1] == "TCP4") != source_ip.is_ipv4()
+        || source_ip.is_ipv4() != destination_ip.is_ipv4()
+    {
+        return Err(Error::MalformedHeader(
+            "v1 addresses don't match address family",
+        ));
+    }
+    Ok(Some(Addresses {
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+    }))
+}
+
+/// Reads the rest of a v2 header, after the signature
+async fn read_v2_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    let mut fixed = [0_u8; 4];
+    stream
+        .read_exact(&mut fixed)
+        .await
+        .map_err(Error::ConnectionError)?;
+    let (version_command, family) = (fixed[0], fixed[1]);
+    let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+    let mut payload = vec![0_u8; length];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .map_err(Error::ConnectionError)?;
+
+    if version_command >> 4 != 2 {
+        return Err(Error::MalformedHeader("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the sender opened this connection itself, so there's no client to report
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(Error::MalformedHeader("unsupported v2 command")),
+    }
+    // The high nibble is the address family, the low nibble the transport protocol. Anything we
+    // don't understand (AF_UNSPEC, AF_UNIX, UDP) is treated like LOCAL, as the spec recommends.
+    let (source_ip, destination_ip, ports) = match family {
+        0x11 if length >= 12 => {
+            let source: [u8; 4] = payload[0..4].try_into().unwrap();
+            let destination: [u8; 4] = payload[4..8].try_into().unwrap();
+            (
+                IpAddr::from(source),
+                IpAddr::from(destination),
+                &payload[8..12],
+            )
+        }
+        0x21 if length >= 36 => {
+            let source: [u8; 16] = payload[0..16].try_into().unwrap();
+            let destination: [u8; 16] = payload[16..32].try_into().unwrap();
+            (
+                IpAddr::from(source),
+                IpAddr::from(destination),
+                &payload[32..36],
+            )
+        }
+        0x11 | 0x21 => return Err(Error::MalformedHeader("v2 address block is too short")),
+        _ => return Ok(None),
+    };
+    Ok(Some(Addresses {
+        source: SocketAddr::new(source_ip, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination_ip, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+/// Serializes a PROXY protocol header describing a TCP connection from source to destination. If
+/// one address is IPv4 and the other IPv6, the IPv4 address is sent as an IPv4-mapped IPv6 address,
+/// since the protocol requires both addresses to be of the same family.
+///
+/// Without addresses (for clients on a Unix domain socket), the header says the source is unknown
+/// ("UNKNOWN" in v1, AF_UNSPEC in v2), and the receiver falls back on the connection's own.
+pub fn encode_header(version: Version, addresses: Option<&Addresses>) -> Vec<u8> {
+    let Some(addresses) = addresses else {
+        return match version {
+            Version::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            Version::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                // Version 2, PROXY command, AF_UNSPEC, no addresses
+                header.extend_from_slice(&[0x21, 0x00, 0x00, 0x00]);
+                header
+            }
+        };
+    };
+    let (source_ip, destination_ip) = match (addresses.source.ip(), addresses.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V6(destination)) => {
+            (IpAddr::V6(source.to_ipv6_mapped()), IpAddr::V6(destination))
+        }
+        (IpAddr::V6(source), IpAddr::V4(destination)) => {
+            (IpAddr::V6(source), IpAddr::V6(destination.to_ipv6_mapped()))
+        }
+        (source, destination) => (source, destination),
+    };
+    let (source_port, destination_port) = (addresses.source.port(), addresses.destination.port());
+
+    match version {
+        Version::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+            source_ip,
+            destination_ip,
+            source_port,
+            destination_port
+        )
+        .into_bytes(),
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+            match (source_ip, destination_ip) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    header.push(0x11);
+                    header.extend_from_slice(&12_u16.to_be_bytes());
+                    header.extend_from_slice(&source.octets());
+                    header.extend_from_slice(&destination.octets());
+                }
+                (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                    header.push(0x21);
+                    header.extend_from_slice(&36_u16.to_be_bytes());
+                    header.extend_from_slice(&source.octets());
+                    header.extend_from_slice(&destination.octets());
+                }
+                _ => unreachable!("addresses were converted to the same family above"),
+            }
+            header.extend_from_slice(&source_port.to_be_bytes());
+            header.extend_from_slice(&destination_port.to_be_bytes());
+            header
+        }
+    }
+}
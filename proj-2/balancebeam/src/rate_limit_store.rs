@@ -0,0 +1,271 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration, Instant};
+
+/// How long we wait on the store before giving up on it and limiting locally
+const STORE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// After the store fails, how long we limit locally before trying it again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The rate limiting window, in seconds. Matches SlideWindow's.
+const WINDOW_SECS: u64 = 60;
+
+/// Prefix of the keys we keep counts under
+const KEY_PREFIX: &str = "balancebeam:ratelimit";
+
+/// Most connections we open to the store at once. Each carries one command at a time, so this is
+/// how many requests can be checked against the store at the same time.
+const MAX_CONNECTIONS: usize = 8;
+
+/// Longest reply line (status, error, integer or bulk length) we read from the store
+const MAX_LINE_LENGTH: u64 = 1024;
+
+/// Longest bulk string we accept from the store. The only ones we ask for are counts.
+const MAX_BULK_LENGTH: i64 = 64;
+
+/// Counts a request and decides whether to limit it, in one step on the store so that requests
+/// reaching other instances at the same time can't slip in between. KEYS are the current and
+/// previous windows' counts; ARGV are the expiry for the current count (in seconds), how far into
+/// the current window we are (0 to 1), and the capacity. The estimate is SlideWindow's: a share of
+/// the previous window's count, plus the current one's (not counting this request). Returns 1 if
+/// the request should be turned away, in which case it isn't counted.
+const RATE_LIMIT_SCRIPT: &str = "\
+local current = redis.call('INCR', KEYS[1])
+redis.call('EXPIRE', KEYS[1], ARGV[1])
+local previous = tonumber(redis.call('GET', KEYS[2]) or '0')
+if previous * (1 - tonumber(ARGV[2])) + current - 1 < tonumber(ARGV[3]) then
+    return 0
+end
+redis.call('DECR', KEYS[1])
+return 1
+";
+
+#[derive(Debug)]
+pub enum Error {
+    /// We couldn't talk to the store
+    Unreachable(#[allow(dead_code)] std::io::Error),
+    /// The store took longer than STORE_TIMEOUT to answer
+    TimedOut,
+    /// The store answered with an error, or something we didn't expect
+    BadReply(#[allow(dead_code)] String),
+    /// The store failed recently, and it isn't time to try it again yet
+    Unavailable,
+}
+
+/// A Redis-protocol (RESP) store that several balancebeam instances share request counts through,
+/// so that a client's requests count against one limit no matter which instance they reach.
+///
+/// Counts are kept per client and per minute of wall-clock time (so that instances agree on where
+/// windows start), and requests are limited with the same sliding window estimate as SlideWindow.
+pub struct RateLimitStore {
+    address: String,
+    /// Open connections to the store that aren't in use
+    idle: parking_lot::Mutex<Vec<BufReader<TcpStream>>>,
+    /// Limits how many connections to the store we open at once
+    slots: Semaphore,
+    /// When the store last failed us
+    failed_at: parking_lot::Mutex<Option<Instant>>,
+}
+
+/// A reply from the store
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(#[allow(dead_code)] String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl RateLimitStore {
+    pub fn new(address: String) -> RateLimitStore {
+        RateLimitStore {
+            address,
+            idle: parking_lot::Mutex::new(Vec::new()),
+            slots: Semaphore::new(MAX_CONNECTIONS),
+            failed_at: parking_lot::Mutex::new(None),
+        }
+    }
+
+    /// Counts a request from the client, and returns whether it should be turned away because the
+    /// client has gone over `capacity` requests per minute (across all instances). Requests that
+    /// are turned away don't count.
+    pub async fn should_rate_limit(&self, client_ip: &str, capacity: usize) -> Result<bool, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let window = now.as_secs() / WINDOW_SECS;
+        let current_key = format!("{}:{}:{}", KEY_PREFIX, client_ip, window);
+        let previous_key = format!("{}:{}:{}", KEY_PREFIX, client_ip, window - 1);
+        let expiry = (2 * WINDOW_SECS).to_string();
+        let elapsed = ((now.as_secs_f64() % WINDOW_SECS as f64) / WINDOW_SECS as f64).to_string();
+        let capacity = capacity.to_string();
+
+        let reply = self
+            .run(&[
+                "EVAL",
+                RATE_LIMIT_SCRIPT,
+                "2",
+                &current_key,
+                &previous_key,
+                &expiry,
+                &elapsed,
+                &capacity,
+            ])
+            .await?;
+        match reply {
+            Reply::Integer(limited) => Ok(limited != 0),
+            reply => Err(Error::BadReply(format!("EVAL returned {:?}", reply))),
+        }
+    }
+
+    /// Sends a command to the store over one of our connections and returns its reply. If anything
+    /// goes wrong, the store's connections are dropped, and it isn't tried again for a while.
+    async fn run(&self, command: &[&str]) -> Result<Reply, Error> {
+        if self
+            .failed_at
+            .lock()
+            .is_some_and(|failed_at| failed_at.elapsed() < RETRY_INTERVAL)
+        {
+            return Err(Error::Unavailable);
+        }
+        let result = match timeout(STORE_TIMEOUT, self.try_run(command)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::TimedOut),
+        };
+        match &result {
+            Ok(_) => {
+                if self.failed_at.lock().take().is_some() {
+                    log::info!("Rate limit store {} is back", self.address);
+                }
+            }
+            Err(error) => {
+                log::warn!(
+                    "Rate limit store {} failed ({:?}); limiting locally for now",
+                    self.address,
+                    error
+                );
+                // Whatever broke this connection has likely broken the others too
+                self.idle.lock().clear();
+                *self.failed_at.lock() = Some(Instant::now());
+            }
+        }
+        result
+    }
+
+    async fn try_run(&self, command: &[&str]) -> Result<Reply, Error> {
+        // The semaphore is never closed
+        let _slot = self.slots.acquire().await.unwrap();
+        let idle = self.idle.lock().pop();
+        let mut stream = match idle {
+            Some(stream) => stream,
+            None => BufReader::new(
+                TcpStream::connect(&self.address)
+                    .await
+                    .map_err(Error::Unreachable)?,
+            ),
+        };
+
+        let mut request = format!("*{}\r\n", command.len()).into_bytes();
+        for arg in command {
+            request.extend(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        stream
+            .get_mut()
+            .write_all(&request)
+            .await
+            .map_err(Error::Unreachable)?;
+        let reply = read_reply(&mut stream).await?;
+
+        // Only a connection that has answered everything we sent it can be used again
+        self.idle.lock().push(stream);
+        Ok(reply)
+    }
+}
+
+/// Reads one reply, refusing to read any further than our replies could possibly go
+async fn read_reply<S: AsyncBufRead + Unpin>(stream: &mut S) -> Result<Reply, Error> {
+    let mut line = String::new();
+    let n = (&mut *stream)
+        .take(MAX_LINE_LENGTH)
+        .read_line(&mut line)
+        .await
+        .map_err(Error::Unreachable)?;
+    if n == 0 {
+        return Err(Error::Unreachable(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    if !line.ends_with("\r\n") {
+        return Err(Error::BadReply(
+            "reply line is cut short or too long".to_string(),
+        ));
+    }
+    let line = line.trim_end_matches("\r\n");
+    let (kind, rest) = line.split_at(line.len().min(1));
+    let parse_int = |text: &str| {
+        text.parse::<i64>()
+            .map_err(|_| Error::BadReply(line.to_string()))
+    };
+    match kind {
+        "+" => Ok(Reply::Status(rest.to_string())),
+        "-" => Err(Error::BadReply(rest.to_string())),
+        ":" => Ok(Reply::Integer(parse_int(rest)?)),
+        "$" => {
+            let len = parse_int(rest)?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            if len > MAX_BULK_LENGTH {
+                return Err(Error::BadReply(format!("{}-byte bulk reply", len)));
+            }
+            // The value, followed by \r\n
+            let mut value = vec![0; len as usize + 2];
+            stream
+                .read_exact(&mut value)
+                .await
+                .map_err(Error::Unreachable)?;
+            value.truncate(len as usize);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        _ => Err(Error::BadReply(line.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(mut bytes: &[u8]) -> Result<Reply, Error> {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(read_reply(&mut bytes))
+    }
+
+    #[test]
+    fn reads_replies() {
+        assert_eq!(read(b":1\r\n").unwrap(), Reply::Integer(1));
+        assert_eq!(read(b"+OK\r\n").unwrap(), Reply::Status("OK".to_string()));
+        assert_eq!(read(b"$-1\r\n").unwrap(), Reply::Bulk(None));
+        assert_eq!(
+            read(b"$2\r\n42\r\n").unwrap(),
+            Reply::Bulk(Some(b"42".to_vec()))
+        );
+        assert!(matches!(read(b"-ERR no\r\n"), Err(Error::BadReply(_))));
+    }
+
+    /// A broken (or hostile) store mustn't be able to make us allocate whatever it likes
+    #[test]
+    fn refuses_oversized_replies() {
+        assert!(matches!(
+            read(b"$9223372036854775807\r\n"),
+            Err(Error::BadReply(_))
+        ));
+        assert!(matches!(read(b"$65\r\n"), Err(Error::BadReply(_))));
+        let long_line = format!(":{}\r\n", "1".repeat(MAX_LINE_LENGTH as usize));
+        assert!(matches!(
+            read(long_line.as_bytes()),
+            Err(Error::BadReply(_))
+        ));
+    }
+}
@@ -1,10 +1,6 @@
+use crate::limits::MessageLimits;
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 
 #[derive(Debug)]
 pub enum Error {
@@ -12,26 +8,59 @@ pub enum Error {
     /// bytes that were successfully read before the client hung up
     IncompleteRequest(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedRequest(httparse::Error),
+    MalformedRequest(#[allow(dead_code)] httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
+    /// The request has both Content-Length and Transfer-Encoding headers, or several Content-Length
+    /// headers, so there's more than one way to tell where its body ends
+    ConflictingFraming,
+    /// The Transfer-Encoding header is malformed, or chunked isn't the last coding in it
+    InvalidTransferEncoding,
+    /// The Transfer-Encoding header lists a coding other than chunked, which we don't decode
+    UnsupportedTransferCoding,
+    /// The chunked request body is malformed, or the client hung up partway through it
+    MalformedChunk,
+    /// The request has more than one Host header
+    DuplicateHost,
+    /// The request body is bigger than the max_body_size limit
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request line and headers are bigger than the max_headers_size limit
+    HeadersTooLarge,
+    /// The request has more headers than the max_num_headers limit
+    TooManyHeaders,
+    /// The Expect header asks for something other than 100-continue
+    UnsupportedExpectation,
+    /// Encountered an I/O error when reading/writing the stream
     ConnectionError(std::io::Error),
 }
 
+/// Longest chunk-size line we accept in a chunked request body
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+
+/// How to tell where a request's body ends (RFC 9112 section 6.3)
+enum Framing {
+    /// The request has no body
+    None,
+    /// The body is as long as the Content-Length header says
+    Length(usize),
+    /// The body is sent in chunks (Transfer-Encoding: chunked), ending with an empty one
+    Chunked,
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
+/// Err(Error) if Content-Length is present but invalid. Only plain digits are valid: Rust would
+/// happily parse "+5" as 5, but a server further along might not.
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
     // Look for content-length header
     if let Some(header_value) = request.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
+        let digits = header_value.as_bytes();
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return Err(Error::InvalidContentLength);
+        }
         Ok(Some(
             header_value
                 .to_str()
@@ -45,6 +74,47 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     }
 }
 
+/// Works out how the request's body is framed. Requests that could be read more than one way are
+/// rejected outright: if we read them one way and the upstream another, whatever is left over
+/// would be taken for a request of its own (request smuggling).
+fn get_framing(request: &http::Request<Vec<u8>>) -> Result<Framing, Error> {
+    let headers = request.headers();
+    let has_transfer_encoding = headers.contains_key(http::header::TRANSFER_ENCODING);
+    if headers.get_all(http::header::CONTENT_LENGTH).iter().count() > 1
+        || (has_transfer_encoding && headers.contains_key(http::header::CONTENT_LENGTH))
+    {
+        return Err(Error::ConflictingFraming);
+    }
+    if !has_transfer_encoding {
+        return Ok(get_content_length(request)?.map_or(Framing::None, Framing::Length));
+    }
+
+    // Transfer codings are listed in the order they were applied, possibly over several headers
+    let mut codings = Vec::new();
+    for value in headers.get_all(http::header::TRANSFER_ENCODING) {
+        let value = value.to_str().or(Err(Error::InvalidTransferEncoding))?;
+        codings.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .map(str::to_ascii_lowercase),
+        );
+    }
+    match codings.split_last() {
+        Some((last, others)) if last == "chunked" => {
+            if others.iter().any(|coding| coding == "chunked") {
+                Err(Error::InvalidTransferEncoding)
+            } else if !others.is_empty() {
+                Err(Error::UnsupportedTransferCoding)
+            } else {
+                Ok(Framing::Chunked)
+            }
+        }
+        _ => Err(Error::InvalidTransferEncoding),
+    }
+}
+
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
 /// or to add a new X-Forwarded-For header if one is not already present.
@@ -74,12 +144,16 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+fn parse_request(
+    buffer: &[u8],
+    max_num_headers: usize,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_num_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::TooManyHeaders,
+        err => Error::MalformedRequest(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -98,65 +172,58 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// be called in order to read the request body (for a POST request). Only the bytes up to the end
+/// of the headers are consumed, so whatever follows them (the body, or the next request if the
+/// client pipelines its requests) is left in the stream.
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    limits: &MessageLimits,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = Vec::new();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
+        let available = stream.fill_buf().await.map_err(Error::ConnectionError)?;
+        if available.is_empty() {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Error::IncompleteRequest(request_buffer.len()));
         }
-        bytes_read += new_bytes;
+        let already_buffered = request_buffer.len();
+        let new_bytes = min(available.len(), limits.max_headers_size - already_buffered);
+        request_buffer.extend_from_slice(&available[..new_bytes]);
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+        if let Some((request, headers_len)) =
+            parse_request(&request_buffer, limits.max_num_headers)?
+        {
+            stream.consume(headers_len - already_buffered);
             return Ok(request);
         }
+        stream.consume(new_bytes);
+
+        // If the buffer is full and we still don't have a complete set of headers, give up
+        if request_buffer.len() == limits.max_headers_size {
+            return Err(Error::HeadersTooLarge);
+        }
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+/// This function reads the body for a request from the stream, until the body is content_length
+/// bytes long. It returns Ok(()) if successful, or Err(Error) if the client hung up first.
+async fn read_body<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let available = stream.fill_buf().await.map_err(Error::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
+        if available.is_empty() {
             log::debug!(
                 "Client hung up after sending a body of length {}, even though it said the content \
                 length is {}",
@@ -166,61 +233,333 @@ async fn read_body(
             return Err(Error::ContentLengthMismatch);
         }
 
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+        // Store the received bytes in the request body, leaving anything past its end (i.e. the
+        // next request) in the stream
+        let wanted = min(available.len(), content_length - request.body().len());
+        request.body_mut().extend_from_slice(&available[..wanted]);
+        stream.consume(wanted);
+    }
+    Ok(())
+}
+
+/// Reads a line of a chunked body (a chunk-size line, the end of a chunk or a trailer field) of at
+/// most max_size bytes, and returns it without its CRLF. Lines must end with CRLF, and may not
+/// contain a CR or LF otherwise: servers disagree on what a bare LF means, which is exactly what
+/// smuggling attacks exploit.
+async fn read_chunk_line<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    max_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    loop {
+        let available = stream.fill_buf().await.map_err(Error::ConnectionError)?;
+        if available.is_empty() {
+            return Err(Error::MalformedChunk);
+        }
+        let (taken, found_end) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(pos) => (pos + 1, true),
+            None => (available.len(), false),
+        };
+        line.extend_from_slice(&available[..taken]);
+        stream.consume(taken);
+        if line.len() > max_size + 2 {
+            return Err(Error::MalformedChunk);
         }
+        if found_end {
+            line.truncate(line.len() - 1);
+            if line.pop() != Some(b'\r') || line.contains(&b'\r') {
+                return Err(Error::MalformedChunk);
+            }
+            return Ok(line);
+        }
+    }
+}
 
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+/// Parses a chunk-size line: the size in hex, optionally followed by extensions (which we drop)
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = match line.iter().position(|&byte| byte == b';') {
+        // Whitespace is allowed before the extensions, but not before the size
+        Some(pos) => line[..pos].trim_ascii_end(),
+        None => line,
+    };
+    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(Error::MalformedChunk);
     }
-    Ok(())
+    usize::from_str_radix(std::str::from_utf8(size).unwrap(), 16).or(Err(Error::MalformedChunk))
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// Reads a chunked body from the stream, decoding it into the request body. Trailer fields are
+/// read past and dropped, since the body is forwarded with a Content-Length instead.
+async fn read_chunked_body<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    request: &mut http::Request<Vec<u8>>,
+    limits: &MessageLimits,
+) -> Result<(), Error> {
+    loop {
+        let size = parse_chunk_size(&read_chunk_line(stream, MAX_CHUNK_LINE_SIZE).await?)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_size - request.body().len() {
+            return Err(Error::RequestBodyTooLarge);
+        }
+        let body_length = request.body().len() + size;
+        read_body(stream, request, body_length)
+            .await
+            .map_err(|error| match error {
+                Error::ContentLengthMismatch => Error::MalformedChunk,
+                error => error,
+            })?;
+        if !read_chunk_line(stream, 0).await?.is_empty() {
+            return Err(Error::MalformedChunk);
+        }
+    }
+
+    // The trailer section ends with an empty line, like the headers
+    let mut trailers_size = 0;
+    loop {
+        let line = read_chunk_line(stream, limits.max_headers_size).await?;
+        if line.is_empty() {
+            return Ok(());
+        }
+        trailers_size += line.len() + 2;
+        if trailers_size > limits.max_headers_size {
+            return Err(Error::HeadersTooLarge);
+        }
+    }
+}
+
+/// Returns the number of bytes the request line and headers of a request take up on the wire
+fn headers_size(request: &http::Request<Vec<u8>>) -> usize {
+    let request_line_size = format_request_line(request).len() + 2;
+    let header_lines_size: usize = request
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + 2 + value.len() + 2)
+        .sum();
+    request_line_size + header_lines_size + 2
+}
+
+/// Reads a request's line and headers from a stream, returning an Error if the client closes the
+/// connection prematurely or sends invalid headers. The body is left in the stream for
+/// read_body_from_stream to read.
 ///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
+/// The headers are read under header_limits. Once they have been read, limits_for is called with
+/// the request to find out which limits actually apply to it (e.g. depending on its path), and
+/// the headers and announced body size are held to those.
+pub async fn read_headers_from_stream<S, F>(
+    stream: &mut S,
+    header_limits: &MessageLimits,
+    limits_for: F,
+) -> Result<http::Request<Vec<u8>>, Error>
+where
+    S: AsyncBufRead + Unpin,
+    F: FnOnce(&http::Request<Vec<u8>>) -> MessageLimits,
+{
+    let mut request = read_headers(stream, header_limits).await?;
+    normalize_uri(&mut request);
+    let limits = limits_for(&request);
+    if request.headers().len() > limits.max_num_headers {
+        return Err(Error::TooManyHeaders);
+    }
+    if headers_size(&request) > limits.max_headers_size {
+        return Err(Error::HeadersTooLarge);
+    }
+    if request.headers().get_all(http::header::HOST).iter().count() > 1 {
+        return Err(Error::DuplicateHost);
+    }
+    if let Some(expectation) = request.headers().get(http::header::EXPECT) {
+        if !expectation.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Err(Error::UnsupportedExpectation);
+        }
+    }
+    if let Framing::Length(length) = get_framing(&request)? {
+        if length > limits.max_body_size {
             return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
         }
     }
     Ok(request)
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// Rewrites the request's path into normal form (see normalize_path), so that everything that
+/// looks at the path, from route matching to the upstream, sees the same one
+fn normalize_uri(request: &mut http::Request<Vec<u8>>) {
+    let normalized = normalize_path(request.uri().path());
+    if normalized == request.uri().path() {
+        return;
+    }
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    let mut parts = request.uri().clone().into_parts();
+    // The normalized path only holds characters the original path did, plus unreserved ones
+    parts.path_and_query = Some(path_and_query.parse().unwrap());
+    *request.uri_mut() = http::Uri::from_parts(parts).unwrap();
+}
+
+/// Returns a request path in normal form: percent-encoded unreserved characters are decoded, runs
+/// of slashes are collapsed into one, and "." and ".." segments are resolved (RFC 3986 sections
+/// 6.2.2 and 5.2.4). Routes match paths by prefix, so without this "//admin", "/./admin",
+/// "/x/../admin" and "/%61dmin" would all reach /admin without going through its route. Other
+/// percent-encoded bytes, such as "%2F", are left encoded, since decoding them would change what
+/// the path means, and a stray "%" is encoded as "%25". Targets that aren't paths (such as "*") are
+/// returned as they are.
+pub fn normalize_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return path.to_string();
+    }
+    let decoded = decode_unreserved(path);
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/').skip(1) {
+        // A path ending in "/", "/." or "/.." names a directory, so keeps its trailing slash
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Decodes percent-encoded unreserved characters (letters, digits, "-", ".", "_" and "~") in a
+/// path, and upper-cases the hex digits of the escapes left in it
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i..i + 3) {
+            Some([b'%', high, low]) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                Some(hex_value(*high) << 4 | hex_value(*low))
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            Some(_) => {
+                decoded.extend(bytes[i..i + 3].iter().map(u8::to_ascii_uppercase));
+                i += 3;
+            }
+            // A "%" that doesn't start an escape is taken literally. Encoding it keeps it from
+            // starting one with the characters we decode after it.
+            None if bytes[i] == b'%' => {
+                decoded.extend_from_slice(b"%25");
+                i += 1;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    // Only ASCII escapes were replaced, and only with ASCII characters
+    String::from_utf8(decoded).unwrap()
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => digit.to_ascii_lowercase() - b'a' + 10,
+    }
+}
+
+/// Reads a request's body from the stream its headers were read from, held to the given limits.
+/// However the client framed the body, it is forwarded with a plain Content-Length, so the upstream
+/// has no room to read it differently than we did.
+pub async fn read_body_from_stream<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    request: &mut http::Request<Vec<u8>>,
+    limits: &MessageLimits,
+) -> Result<(), Error> {
+    match get_framing(request)? {
+        Framing::None => return Ok(()),
+        Framing::Length(content_length) => read_body(stream, request, content_length).await?,
+        Framing::Chunked => {
+            read_chunked_body(stream, request, limits).await?;
+            request
+                .headers_mut()
+                .remove(http::header::TRANSFER_ENCODING);
+        }
+    }
+    let content_length = request.body().len().to_string();
+    request.headers_mut().insert(
+        http::header::CONTENT_LENGTH,
+        http::HeaderValue::from_str(&content_length).unwrap(),
+    );
+    Ok(())
+}
+
+/// Returns whether the client sent "Expect: 100-continue" for a request with a body, which it may
+/// hold back until we tell it to go ahead with a 100 Continue response
+pub fn expects_continue(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get(http::header::EXPECT)
+        .is_some_and(|expectation| expectation.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+        && matches!(
+            get_framing(request),
+            Ok(Framing::Length(1..) | Framing::Chunked)
+        )
+}
+
+/// This function reads and returns an HTTP request from a stream, returning an Error if the client
+/// closes the connection prematurely or sends an invalid request. The limits are applied as in
+/// read_headers_from_stream.
+pub async fn read_from_stream<S, F>(
+    stream: &mut S,
+    header_limits: &MessageLimits,
+    limits_for: F,
+) -> Result<http::Request<Vec<u8>>, Error>
+where
+    S: AsyncBufRead + Unpin,
+    F: FnOnce(&http::Request<Vec<u8>>) -> MessageLimits,
+{
+    let mut limits = None;
+    let mut request = read_headers_from_stream(stream, header_limits, |request| {
+        *limits.insert(limits_for(request))
+    })
+    .await?;
+    read_body_from_stream(stream, &mut request, &limits.unwrap()).await?;
+    Ok(request)
+}
+
+/// This function serializes a request to bytes and writes those bytes to the provided stream. The
+/// request line and headers are gathered up in a buffer first, so that they go out in as few writes
+/// as possible.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
+    let mut stream = BufWriter::new(stream);
     stream
-        .write(&format_request_line(request).into_bytes())
+        .write_all(format_request_line(request).as_bytes())
         .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(b"\r\n").await?;
     for (header_name, header_value) in request.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+        stream.write_all(header_name.as_str().as_bytes()).await?;
+        stream.write_all(b": ").await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
     }
-    Ok(())
+    stream.write_all(b"\r\n").await?;
+    stream.write_all(request.body()).await?;
+    stream.flush().await
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -231,3 +570,199 @@ pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
         request.version()
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use tokio::io::{duplex, BufReader};
+
+    const LIMITS: MessageLimits = MessageLimits {
+        max_headers_size: 64 * 1024,
+        max_num_headers: 64,
+        max_body_size: 1024 * 1024,
+    };
+
+    /// Runs a future to completion (proptest's test bodies can't be async)
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn arb_request() -> impl Strategy<Value = http::Request<Vec<u8>>> {
+        (
+            prop::sample::select(vec!["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH"]),
+            "/[a-zA-Z0-9._~/-]{0,40}(\\?[a-z0-9=&]{0,20})?",
+            prop::collection::btree_map("x-[a-z0-9-]{1,20}", "[!-~]([ -~]{0,38}[!-~])?", 0..10),
+            prop::collection::vec(any::<u8>(), 0..2000),
+        )
+            .prop_map(|(method, uri, headers, body)| {
+                let mut request = http::Request::builder().method(method).uri(uri);
+                for (name, value) in headers {
+                    request = request.header(name, value);
+                }
+                if !body.is_empty() {
+                    request = request.header("content-length", body.len().to_string());
+                }
+                request.body(body).unwrap()
+            })
+    }
+
+    proptest! {
+        /// Whatever write_to_stream writes, read_from_stream should read back the same (but for
+        /// normalizing the path), however the bytes are split up on the way (the duplex's small
+        /// buffer makes for short writes and partial reads)
+        #[test]
+        fn round_trip(request in arb_request(), buffer_size in 1..64_usize) {
+            let (mut writer, reader) = duplex(buffer_size);
+            let mut reader = BufReader::new(reader);
+            let (written, read) = block_on(async {
+                tokio::join!(
+                    write_to_stream(&request, &mut writer),
+                    read_from_stream(&mut reader, &LIMITS, |_| LIMITS),
+                )
+            });
+            written.unwrap();
+            let read = read.unwrap();
+            prop_assert_eq!(read.method(), request.method());
+            prop_assert_eq!(read.uri().path(), normalize_path(request.uri().path()));
+            prop_assert_eq!(read.uri().query(), request.uri().query());
+            prop_assert_eq!(read.headers(), request.headers());
+            prop_assert_eq!(read.body(), request.body());
+        }
+
+        /// Garbage (on its own, or after a plausible request line) should be turned away with an
+        /// error, never a panic
+        #[test]
+        fn arbitrary_bytes(
+            prefix in prop::sample::select(vec![
+                "",
+                "GET /",
+                "GET / HTTP/1.1\r\n",
+                "POST /a HTTP/1.1\r\nContent-Length: ",
+                "POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            ]),
+            bytes in prop::collection::vec(any::<u8>(), 0..1000),
+        ) {
+            let (mut writer, reader) = duplex(64 * 1024);
+            let mut reader = BufReader::new(reader);
+            block_on(async {
+                writer.write_all(prefix.as_bytes()).await.unwrap();
+                writer.write_all(&bytes).await.unwrap();
+                drop(writer);
+                let _ = read_from_stream(&mut reader, &LIMITS, |_| LIMITS).await;
+            });
+        }
+
+        /// A chunked body, however it is cut into chunks (and whatever extensions they carry),
+        /// should be read as the body it encodes and come out with a Content-Length instead.
+        /// Whatever follows it in the stream is the next request's.
+        #[test]
+        fn chunked_body(
+            body in prop::collection::vec(any::<u8>(), 0..2000),
+            chunk_sizes in prop::collection::vec(1..300_usize, 1..10),
+            extension in prop::sample::select(vec!["", ";name", " ;name=value", ";a=\"b c\""]),
+            buffer_size in 1..64_usize,
+        ) {
+            let mut raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            let mut rest = &body[..];
+            for size in chunk_sizes.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, remaining) = rest.split_at((*size).min(rest.len()));
+                raw.extend_from_slice(format!("{:x}{}\r\n", chunk.len(), extension).as_bytes());
+                raw.extend_from_slice(chunk);
+                raw.extend_from_slice(b"\r\n");
+                rest = remaining;
+            }
+            raw.extend_from_slice(b"0\r\nX-Trailer: dropped\r\n\r\nGET /next HTTP/1.1\r\n\r\n");
+
+            let (mut writer, reader) = duplex(buffer_size);
+            let mut reader = BufReader::new(reader);
+            let (first, second) = block_on(async {
+                let write = async {
+                    writer.write_all(&raw).await.unwrap();
+                    drop(writer);
+                };
+                // The reader is dropped once done, so the writer can't be left waiting on it
+                let read = async move {
+                    let first = read_from_stream(&mut reader, &LIMITS, |_| LIMITS).await;
+                    let second = read_from_stream(&mut reader, &LIMITS, |_| LIMITS).await;
+                    (first, second)
+                };
+                tokio::join!(write, read).1
+            });
+            let first = first.unwrap();
+            prop_assert_eq!(first.body(), &body);
+            prop_assert!(!first.headers().contains_key("transfer-encoding"));
+            let content_length = body.len().to_string();
+            prop_assert_eq!(
+                first.headers().get("content-length").unwrap().to_str().unwrap(),
+                content_length.as_str()
+            );
+            let second = second.unwrap();
+            prop_assert_eq!(second.uri().path(), "/next");
+        }
+
+        /// A normalized path is already as normal as it gets
+        #[test]
+        fn normalize_path_is_idempotent(path in "/[a-c./%2eEF61]{0,30}") {
+            let normalized = normalize_path(&path);
+            prop_assert_eq!(normalize_path(&normalized), normalized.clone());
+        }
+    }
+
+    /// Every one of these is another way of writing /admin
+    #[test]
+    fn normalize_path_resolves_aliases() {
+        for path in [
+            "/admin",
+            "//admin",
+            "///admin",
+            "/./admin",
+            "/x/../admin",
+            "/x/y/../../admin",
+            "/../admin",
+            "/%61dmin",
+            "/%61%64%6D%69%6e",
+            "/%2e/admin",
+            "/x/%2E%2e/admin",
+        ] {
+            assert_eq!(normalize_path(path), "/admin", "{}", path);
+        }
+    }
+
+    #[test]
+    fn normalize_path_keeps_directories() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "");
+        assert_eq!(normalize_path("*"), "*");
+        assert_eq!(normalize_path("/.."), "/");
+        assert_eq!(normalize_path("/admin/"), "/admin/");
+        assert_eq!(normalize_path("/admin//"), "/admin/");
+        assert_eq!(normalize_path("/admin/x/.."), "/admin/");
+        assert_eq!(normalize_path("/admin/."), "/admin/");
+    }
+
+    /// Escapes of reserved characters mean something other than the characters themselves
+    #[test]
+    fn normalize_path_keeps_reserved_escapes() {
+        assert_eq!(normalize_path("/a%2fb"), "/a%2Fb");
+        assert_eq!(normalize_path("/a%2F..%2Fb"), "/a%2F..%2Fb");
+        assert_eq!(normalize_path("/50%"), "/50%25");
+        assert_eq!(normalize_path("/%zz"), "/%25zz");
+        assert_eq!(normalize_path("/%%66e"), "/%25fe");
+        assert_eq!(normalize_path("/caf%C3%A9"), "/caf%C3%A9");
+    }
+
+    /// The normalized path should be what gets forwarded, with the query left alone
+    #[test]
+    fn read_normalizes_path() {
+        let mut stream: &[u8] = b"GET //x/../admin/%7euser?next=/./x HTTP/1.1\r\n\r\n";
+        let request = block_on(read_from_stream(&mut stream, &LIMITS, |_| LIMITS)).unwrap();
+        assert_eq!(request.uri(), "/admin/~user?next=/./x");
+    }
+}
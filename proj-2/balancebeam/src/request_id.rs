@@ -0,0 +1,64 @@
+use crate::acl::{Client, Source};
+use serde::Deserialize;
+
+/// The header carrying a request's ID, both to the upstream and back to the client
+pub const HEADER: &str = "x-request-id";
+
+/// The longest request ID we take from a client
+const MAX_LENGTH: usize = 128;
+
+/// Where requests' IDs come from. Every request gets an ID, which is logged with everything
+/// balancebeam does for the request, forwarded to the upstream in X-Request-Id (so that its logs
+/// can be matched up with ours), and sent back to the client in X-Request-Id and on error pages.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIds {
+    /// Clients whose own X-Request-Id is kept, e.g. another proxy in front of balancebeam that has
+    /// already given the request an ID. Anyone else's is replaced with a new one.
+    pub trust: Vec<Source>,
+}
+
+impl RequestIds {
+    /// Settles on the request's ID: the one it came with, if the client is trusted to choose and
+    /// the ID is usable, or else `new_id`. Sets the request's X-Request-Id to it, and returns it.
+    pub fn assign(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+        client: Client,
+        new_id: String,
+    ) -> String {
+        let incoming = request
+            .headers()
+            .get(HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| is_usable(id));
+        let id = match incoming {
+            Some(id) if self.trust.iter().any(|source| source.matches(client)) => id.to_string(),
+            _ => new_id,
+        };
+        request
+            .headers_mut()
+            .insert(HEADER, http::HeaderValue::from_str(&id).unwrap());
+        id
+    }
+}
+
+/// Returns a new ID for a request
+pub fn generate() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Returns the ID of the request with the given headers (see RequestIds::assign), for code that
+/// logs about a request it was handed
+pub fn of(headers: &http::HeaderMap) -> &str {
+    headers
+        .get(HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or("-")
+}
+
+/// Returns whether an ID from a client is fit to use. IDs end up in our log lines, so they may only
+/// contain visible ASCII characters (no spaces or control characters).
+fn is_usable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
@@ -0,0 +1,97 @@
+use crate::stream;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The upstream isn't of the form host:port
+    InvalidAddress,
+    /// The --hosts-file couldn't be read
+    HostsFileUnreadable(#[allow(dead_code)] std::io::Error),
+    /// The system resolver failed to look up the host
+    LookupFailed(#[allow(dead_code)] std::io::Error),
+    /// The lookup succeeded, but the host has no addresses
+    NoAddresses,
+}
+
+/// Turns upstreams given as host:port into the socket addresses behind them.
+///
+/// Names are looked up in the hosts file (if one was given) first, which lets tests (and
+/// deployments without usable DNS) control what a name resolves to. Anything not in there goes to
+/// the system resolver.
+pub struct Resolver {
+    hosts_file: Option<String>,
+}
+
+impl Resolver {
+    pub fn new(hosts_file: Option<String>) -> Resolver {
+        Resolver { hosts_file }
+    }
+
+    /// Returns all addresses the given host:port upstream currently resolves to, formatted as
+    /// ip:port strings. Upstreams that are already IP addresses (or Unix domain socket paths) are
+    /// returned unchanged.
+    pub async fn resolve(&self, upstream: &str) -> Result<Vec<String>, Error> {
+        if let Ok(addr) = upstream.parse::<SocketAddr>() {
+            return Ok(vec![addr.to_string()]);
+        }
+        if stream::unix_path(upstream).is_some() {
+            return Ok(vec![upstream.to_string()]);
+        }
+        let (host, port) = upstream.rsplit_once(':').ok_or(Error::InvalidAddress)?;
+        let port: u16 = port.parse().map_err(|_| Error::InvalidAddress)?;
+
+        let mut addrs: Vec<SocketAddr> = match self.lookup_hosts_file(host).await? {
+            Some(ips) => ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect(),
+            None => tokio::net::lookup_host(upstream)
+                .await
+                .map_err(Error::LookupFailed)?
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(Error::NoAddresses);
+        }
+        // The system resolver may hand out the same records in a different order each time, and
+        // callers compare the results between lookups
+        addrs.sort();
+        addrs.dedup();
+        Ok(addrs.iter().map(|addr| addr.to_string()).collect())
+    }
+
+    /// Looks the host up in the hosts file. The file is re-read on every lookup so that changes to
+    /// it are picked up by the next refresh. Returns Ok(None) if the host isn't listed.
+    async fn lookup_hosts_file(&self, host: &str) -> Result<Option<Vec<IpAddr>>, Error> {
+        let path = match &self.hosts_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(Error::HostsFileUnreadable)?;
+        Ok(parse_hosts_file(&contents, host))
+    }
+}
+
+/// Finds all addresses listed for host in a file in /etc/hosts format: one address per line,
+/// followed by the names it belongs to, with # starting a comment.
+fn parse_hosts_file(contents: &str, host: &str) -> Option<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        if fields.any(|name| name.eq_ignore_ascii_case(host)) {
+            ips.push(ip);
+        }
+    }
+    if ips.is_empty() {
+        None
+    } else {
+        Some(ips)
+    }
+}
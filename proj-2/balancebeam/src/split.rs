@@ -0,0 +1,171 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// How many positions a sticky key is hashed into. Finer than any split anyone will configure.
+const STICKY_BUCKETS: u64 = 10000;
+
+/// 64-bit FNV-1a, which sticky keys are hashed with. Unlike the standard library's hasher, its
+/// output is fixed, so a key lands in the same group across restarts, Rust versions and every
+/// balancebeam instance behind the same load balancer.
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Divides requests between named upstream groups (e.g. sending 5% of traffic to a "canary" group
+/// and the rest to "stable").
+///
+/// Requests carrying the sticky header or cookie are always sent to the same group for as long as
+/// the split stays the same. Each group owns a contiguous slice of the range, in order of group
+/// name, so when the canary's share grows, the clients already on it stay on it. Other requests go
+/// wherever their connection was randomly assigned, so a client's requests on one keep-alive
+/// connection all see the same version of the application.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Split {
+    /// Relative share of requests each group gets
+    pub groups: BTreeMap<String, u32>,
+    /// Header whose value pins a client to a group (e.g. a user ID)
+    pub sticky_header: Option<String>,
+    /// Cookie whose value pins a client to a group, if the sticky header isn't there
+    pub sticky_cookie: Option<String>,
+}
+
+impl Split {
+    /// Picks the group the request should go to. `connection_key` is a random number drawn once
+    /// for the request's connection, which places requests without a sticky key. Returns None if
+    /// no group has a positive share.
+    pub fn choose_group(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        connection_key: u64,
+    ) -> Option<&str> {
+        let total_weight: u64 = self.groups.values().map(|weight| *weight as u64).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let key = self.sticky_key(request).map_or(connection_key, fnv1a);
+        let mut point = (key % STICKY_BUCKETS) * total_weight / STICKY_BUCKETS;
+        for (group, weight) in &self.groups {
+            if point < *weight as u64 {
+                return Some(group);
+            }
+            point -= *weight as u64;
+        }
+        unreachable!("point is less than the total weight")
+    }
+
+    fn sticky_key<'a>(&self, request: &'a http::Request<Vec<u8>>) -> Option<&'a [u8]> {
+        if let Some(value) = self
+            .sticky_header
+            .as_ref()
+            .and_then(|name| request.headers().get(name))
+        {
+            return Some(value.as_bytes());
+        }
+        let name = self.sticky_cookie.as_ref()?;
+        request
+            .headers()
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie_name, _)| cookie_name == name)
+            .map(|(_, value)| value.as_bytes())
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(groups: &[(&str, u32)]) -> Split {
+        Split {
+            groups: groups
+                .iter()
+                .map(|(name, weight)| (name.to_string(), *weight))
+                .collect(),
+            sticky_header: Some("x-user-id".to_string()),
+            sticky_cookie: Some("session".to_string()),
+        }
+    }
+
+    fn request(headers: &[(&str, &str)]) -> http::Request<Vec<u8>> {
+        let mut request = http::Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Vec::new()).unwrap()
+    }
+
+    /// Each group gets a contiguous slice of the buckets, in order of group name
+    #[test]
+    fn bucket_boundaries() {
+        let split = split(&[("b", 3), ("a", 1)]);
+        let plain = request(&[]);
+        let group = |key| split.choose_group(&plain, key).unwrap();
+        assert_eq!(group(0), "a");
+        assert_eq!(group(2499), "a");
+        assert_eq!(group(2500), "b");
+        assert_eq!(group(9999), "b");
+        // Only the bucket counts
+        assert_eq!(group(STICKY_BUCKETS), "a");
+        assert_eq!(group(STICKY_BUCKETS + 2500), "b");
+        assert_eq!(group(u64::MAX - u64::MAX % STICKY_BUCKETS), "a");
+    }
+
+    #[test]
+    fn empty_groups() {
+        let plain = request(&[]);
+        let one_group = split(&[("a", 0), ("b", 1), ("c", 0)]);
+        for key in [0, 1, 5000, 9999] {
+            assert_eq!(one_group.choose_group(&plain, key), Some("b"));
+        }
+        assert_eq!(split(&[("a", 0)]).choose_group(&plain, 0), None);
+        assert_eq!(Split::default().choose_group(&plain, 0), None);
+    }
+
+    /// Growing a group's share only moves keys into it, never out of it
+    #[test]
+    fn growing_share() {
+        let plain = request(&[]);
+        let small = split(&[("canary", 5), ("stable", 95)]);
+        let large = split(&[("canary", 10), ("stable", 90)]);
+        for key in 0..STICKY_BUCKETS {
+            if small.choose_group(&plain, key) == Some("canary") {
+                assert_eq!(large.choose_group(&plain, key), Some("canary"));
+            }
+        }
+    }
+
+    /// Sticky keys decide the group, whatever the connection, with the header taking precedence
+    /// over the cookie
+    #[test]
+    fn sticky_keys() {
+        let split = split(&[("a", 1), ("b", 1)]);
+        let by_header = request(&[("x-user-id", "42"), ("cookie", "session=x")]);
+        let by_cookie = request(&[("cookie", "theme=dark; session=42")]);
+        let expected = split.choose_group(&by_header, 0);
+        for key in [0, 1, 2500, 5000, 7500] {
+            assert_eq!(split.choose_group(&by_header, key), expected);
+            assert_eq!(split.choose_group(&by_cookie, key), expected);
+        }
+        assert_eq!(
+            split.sticky_key(&request(&[("cookie", "sessionid=1")])),
+            None
+        );
+    }
+
+    /// Known FNV-1a values, so a change of hash (which would move every sticky client) shows up
+    #[test]
+    fn fnv1a_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
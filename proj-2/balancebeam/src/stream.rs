@@ -0,0 +1,138 @@
+use crate::acl::Client;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::server::TlsStream;
+
+/// Addresses starting with this are paths of Unix domain sockets (as in unix:/run/app.sock) rather
+/// than host:port pairs
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Returns the socket path of an address written as unix:/path, or None for host:port addresses
+pub fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_PREFIX)
+}
+
+/// A connection to a client or an upstream, over TCP or a Unix domain socket. Client connections
+/// to listeners with TLS settings are decrypted on top of either.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream<Stream>>),
+}
+
+impl Stream {
+    /// Connects to the given address: host:port for TCP, or unix:/path for a Unix domain socket
+    pub async fn connect(address: &str) -> io::Result<Stream> {
+        match unix_path(address) {
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            None => Ok(Stream::Tcp(TcpStream::connect(address).await?)),
+        }
+    }
+
+    /// The address of the other end of the connection. Unix domain socket peers have no IP
+    /// address, so this is an error for them.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr(),
+            Stream::Unix(_) => Err(no_ip_address()),
+            Stream::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+
+    /// Our address on the connection. This is an error for Unix domain sockets, like peer_addr.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr(),
+            Stream::Unix(_) => Err(no_ip_address()),
+            Stream::Tls(stream) => stream.get_ref().0.local_addr(),
+        }
+    }
+}
+
+fn no_ip_address() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets don't have IP addresses",
+    )
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A socket we accept connections on, over TCP or a Unix domain socket
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accepts a new connection, returning it along with who it comes from
+    pub async fn accept(&self) -> io::Result<(Stream, Client)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Client::Ip(addr.ip())))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), Client::Unix))
+            }
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
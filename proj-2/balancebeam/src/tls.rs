@@ -0,0 +1,89 @@
+use crate::stream::Stream;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client gets to finish the TLS handshake. Like the PROXY protocol header's timeout,
+/// this keeps clients that connect and say nothing from holding connections open.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The certificate or key file couldn't be read
+    UnreadableFile(
+        #[allow(dead_code)] String,
+        #[allow(dead_code)] std::io::Error,
+    ),
+    /// The certificate file doesn't contain any PEM-encoded certificates
+    NoCertificates(#[allow(dead_code)] String),
+    /// The key file doesn't contain a PEM-encoded private key
+    NoKey(#[allow(dead_code)] String),
+    /// rustls won't use the certificate and key (e.g. because they don't belong together)
+    Rejected(#[allow(dead_code)] rustls::Error),
+}
+
+/// TLS settings for a listener. Its connections are decrypted here, and the requests on them
+/// proxied to the upstreams as plain HTTP.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// PEM file with the certificate chain to present, starting with the listener's own
+    /// certificate
+    pub cert: String,
+    /// PEM file with the certificate's private key
+    pub key: String,
+    /// The certificate and key, read when the configuration is loaded
+    #[serde(skip)]
+    server_config: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Tls {
+    /// Reads the certificate and key. Since this happens whenever the configuration is loaded,
+    /// renewed certificates can be picked up by reloading the configuration.
+    pub fn load(&mut self) -> Result<(), Error> {
+        let certs = rustls_pemfile::certs(&mut read_file(&self.cert)?.as_slice())
+            .collect::<Result<Vec<CertificateDer>, _>>()
+            .map_err(|error| Error::UnreadableFile(self.cert.clone(), error))?;
+        if certs.is_empty() {
+            return Err(Error::NoCertificates(self.cert.clone()));
+        }
+        let key: PrivateKeyDer = rustls_pemfile::private_key(&mut read_file(&self.key)?.as_slice())
+            .map_err(|error| Error::UnreadableFile(self.key.clone(), error))?
+            .ok_or_else(|| Error::NoKey(self.key.clone()))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(Error::Rejected)?;
+        // We only speak HTTP/1.1, so clients mustn't pick HTTP/2
+        server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        self.server_config = Some(Arc::new(server_config));
+        Ok(())
+    }
+
+    /// Returns what performs handshakes for the listener's connections
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.server_config
+                .clone()
+                .expect("TLS settings used before being loaded"),
+        )
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|error| Error::UnreadableFile(path.to_string(), error))
+}
+
+/// Performs the TLS handshake with a client, returning the decrypted connection. Gives up after
+/// HANDSHAKE_TIMEOUT.
+pub async fn accept(acceptor: TlsAcceptor, stream: Stream) -> io::Result<Stream> {
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    Ok(Stream::Tls(Box::new(stream)))
+}
@@ -0,0 +1,504 @@
+use crate::limits::MessageLimits;
+use crate::stream::Stream;
+use crate::{request, response};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+/// How many finished spans may wait to be exported. Beyond this, spans are dropped, so a slow
+/// collector can't make us use up memory.
+const MAX_QUEUED_SPANS: usize = 4096;
+
+/// Most spans sent in a single export
+const MAX_BATCH_SIZE: usize = 512;
+
+/// How long we give the collector to accept a batch of spans
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The traceparent flag saying that the trace is being recorded
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// Where finished spans are sent
+#[derive(Debug, Clone)]
+pub enum Destination {
+    /// POSTed to an OTLP/HTTP collector endpoint, e.g. http://127.0.0.1:4318/v1/traces
+    Collector(http::Uri),
+    /// Appended to a file, one JSON export request per line (like the collector's file exporter)
+    File(String),
+}
+
+/// Why a span export destination couldn't be used
+#[derive(Debug)]
+pub enum Error {
+    /// The collector endpoint isn't a valid http:// URL
+    InvalidEndpoint(#[allow(dead_code)] String),
+}
+
+impl Destination {
+    pub fn collector(endpoint: &str) -> Result<Destination, Error> {
+        let uri: http::Uri = endpoint
+            .parse()
+            .map_err(|_| Error::InvalidEndpoint(endpoint.to_string()))?;
+        if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+            return Err(Error::InvalidEndpoint(endpoint.to_string()));
+        }
+        Ok(Destination::Collector(uri))
+    }
+}
+
+/// The W3C trace context (https://www.w3.org/TR/trace-context/) a span belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The span this context was handed to us by (or, going out, the span we hand it on from)
+    pub span_id: [u8; 8],
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Parses a traceparent header value. Returns None if it isn't valid, in which case the
+    /// header should be ignored and a new trace started.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parse_hex::<1>(parts.next()?)?;
+        let trace_id = parse_hex::<16>(parts.next()?)?;
+        let span_id = parse_hex::<8>(parts.next()?)?;
+        let flags = parse_hex::<1>(parts.next()?)?;
+        // Later versions may add fields, but version 00 has exactly these four, and ff is invalid
+        if version[0] == 0xff
+            || (version[0] == 0 && parts.next().is_some())
+            || trace_id == [0; 16]
+            || span_id == [0; 8]
+        {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            flags: flags[0],
+        })
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+
+    pub fn to_traceparent(self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            self.flags
+        )
+    }
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    // Uppercase hex isn't allowed in traceparent
+    if text.len() != 2 * N || !text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// Returns a random ID that isn't all zeroes (which traceparent reserves for "no ID")
+fn new_id<const N: usize>() -> [u8; N] {
+    loop {
+        let mut id = [0; N];
+        rand::thread_rng().fill(&mut id[..]);
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+/// What a span stands for, as far as OTLP is concerned
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// A finished span, waiting to be exported
+#[derive(Debug)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl SpanData {
+    fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": to_hex(&self.trace_id),
+            "spanId": to_hex(&self.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({"key": key, "value": value}))
+                .collect::<Vec<_>>(),
+            // 1 = OK, 2 = ERROR
+            "status": {"code": if self.error { 2 } else { 1 }},
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = json!(to_hex(parent_span_id));
+        }
+        span
+    }
+}
+
+/// OTLP/JSON encodes 64-bit integers as strings
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Hands finished spans to a background task that exports them. Cloning a Tracer is cheap; every
+/// clone feeds the same task.
+#[derive(Clone)]
+pub struct Tracer {
+    spans: mpsc::Sender<SpanData>,
+}
+
+impl Tracer {
+    /// Starts the export task. Must be called from within the tokio runtime.
+    pub fn new(destination: Destination) -> Tracer {
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_SPANS);
+        tokio::spawn(export_spans(destination, receiver));
+        Tracer { spans: sender }
+    }
+
+    /// Starts the span for a request we received, continuing the trace in its traceparent header
+    /// if it has a valid one, or starting a new (sampled) trace otherwise
+    pub fn start_request_span(&self, request: &http::Request<Vec<u8>>, client_ip: &str) -> Span {
+        let incoming = request
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(TraceContext::parse);
+        let context = match incoming {
+            Some(incoming) => TraceContext {
+                span_id: new_id(),
+                ..incoming
+            },
+            None => TraceContext {
+                trace_id: new_id(),
+                span_id: new_id(),
+                flags: FLAG_SAMPLED,
+            },
+        };
+        let mut span = Span::new(
+            self.clone(),
+            context,
+            incoming.map(|incoming| incoming.span_id),
+            "accept",
+            SpanKind::Server,
+        );
+        span.set_attribute("http.request.method", request.method().as_str());
+        span.set_attribute("url.path", request.uri().path());
+        span.set_attribute("client.address", client_ip);
+        span
+    }
+}
+
+/// A span that is under way. It ends, and is handed off to be exported, when it is dropped.
+pub struct Span {
+    tracer: Tracer,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+impl Span {
+    fn new(
+        tracer: Tracer,
+        context: TraceContext,
+        parent_span_id: Option<[u8; 8]>,
+        name: &'static str,
+        kind: SpanKind,
+    ) -> Span {
+        Span {
+            tracer,
+            context,
+            parent_span_id,
+            name,
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    /// Starts a span for a step of the work this span stands for
+    pub fn child(&self, name: &'static str, kind: SpanKind) -> Span {
+        Span::new(
+            self.tracer.clone(),
+            TraceContext {
+                span_id: new_id(),
+                ..self.context
+            },
+            Some(self.context.span_id),
+            name,
+            kind,
+        )
+    }
+
+    /// The traceparent header value to pass on to whatever this span calls
+    pub fn traceparent(&self) -> String {
+        self.context.to_traceparent()
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        self.attributes.push((key, value.into().0));
+    }
+
+    /// Records the status code of the response to this span's request. 5xx responses mark the
+    /// span as failed.
+    pub fn set_status(&mut self, status: http::StatusCode) {
+        self.set_attribute("http.response.status_code", status.as_u16() as i64);
+        if status.is_server_error() {
+            self.set_error();
+        }
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.context.is_sampled() {
+            return;
+        }
+        let data = SpanData {
+            trace_id: self.context.trace_id,
+            span_id: self.context.span_id,
+            parent_span_id: self.parent_span_id,
+            name: self.name,
+            kind: self.kind,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: std::mem::take(&mut self.attributes),
+            error: self.error,
+        };
+        if self.tracer.spans.try_send(data).is_err() {
+            log::debug!("Too many spans waiting to be exported; dropping one");
+        }
+    }
+}
+
+/// A span attribute value, in its OTLP/JSON form
+pub struct AttributeValue(Value);
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> AttributeValue {
+        AttributeValue(json!({ "stringValue": value }))
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> AttributeValue {
+        AttributeValue(json!({ "intValue": value.to_string() }))
+    }
+}
+
+/// Exports spans as they come in, batching up whatever has piled up since the last export
+async fn export_spans(destination: Destination, mut spans: mpsc::Receiver<SpanData>) {
+    while let Some(span) = spans.recv().await {
+        let mut batch = vec![span];
+        while batch.len() < MAX_BATCH_SIZE {
+            match spans.try_recv() {
+                Ok(span) => batch.push(span),
+                Err(_) => break,
+            }
+        }
+        let body = export_request(&batch).to_string();
+        let result = match &destination {
+            Destination::Collector(uri) => send_to_collector(uri, body).await,
+            Destination::File(path) => append_to_file(path, body).await,
+        };
+        if let Err(error) = result {
+            log::warn!("Failed to export {} spans: {}", batch.len(), error);
+        }
+    }
+}
+
+/// Builds an OTLP ExportTraceServiceRequest holding the given spans
+fn export_request(spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    {"key": "service.name", "value": {"stringValue": "balancebeam"}},
+                ],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "balancebeam", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans.iter().map(SpanData::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+async fn send_to_collector(uri: &http::Uri, body: String) -> Result<(), String> {
+    let authority = uri.authority().unwrap().as_str();
+    let host_and_port = match uri.port() {
+        Some(_) => authority.to_string(),
+        None => format!("{}:80", authority),
+    };
+    let request = http::Request::builder()
+        .method(http::Method::POST)
+        .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header("Host", authority)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .body(body.into_bytes())
+        .unwrap();
+    let response = timeout(EXPORT_TIMEOUT, async {
+        let mut stream = TcpStream::connect(&host_and_port)
+            .await
+            .map(Stream::Tcp)
+            .map_err(|error| format!("could not connect to {}: {}", host_and_port, error))?;
+        request::write_to_stream(&request, &mut stream)
+            .await
+            .map_err(|error| format!("could not send spans: {}", error))?;
+        response::read_from_stream(&mut stream, request.method(), &MessageLimits::default())
+            .await
+            .map_err(|error| format!("could not read the collector's response: {:?}", error))
+    })
+    .await
+    .map_err(|_| "the collector timed out".to_string())??;
+    if !response.status().is_success() {
+        return Err(format!(
+            "the collector answered {}",
+            response::format_response_line(&response)
+        ));
+    }
+    Ok(())
+}
+
+async fn append_to_file(path: &str, mut body: String) -> Result<(), String> {
+    body.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|error| format!("could not open {}: {}", path, error))?;
+    file.write_all(body.as_bytes())
+        .await
+        .map_err(|error| format!("could not write to {}: {}", path, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn round_trip() {
+        let traceparent = format!("00-{}-{}-01", TRACE_ID, SPAN_ID);
+        let context = TraceContext::parse(&traceparent).unwrap();
+        assert_eq!(context.trace_id[..2], [0x4b, 0xf9]);
+        assert_eq!(context.span_id[..2], [0x00, 0xf0]);
+        assert!(context.is_sampled());
+        assert_eq!(context.to_traceparent(), traceparent);
+
+        let unsampled = TraceContext::parse(&format!("00-{}-{}-00", TRACE_ID, SPAN_ID)).unwrap();
+        assert!(!unsampled.is_sampled());
+    }
+
+    /// Later versions are parsed as far as version 00 goes, even if they add fields. We only ever
+    /// send version 00.
+    #[test]
+    fn versions() {
+        for traceparent in [
+            format!("01-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("cc-{}-{}-01-whatever-comes-next", TRACE_ID, SPAN_ID),
+        ] {
+            let context = TraceContext::parse(&traceparent).unwrap();
+            assert_eq!(
+                context.to_traceparent(),
+                format!("00-{}-{}-01", TRACE_ID, SPAN_ID)
+            );
+        }
+        // Version ff is forbidden, and version 00 has exactly four fields
+        assert_eq!(
+            TraceContext::parse(&format!("ff-{}-{}-01", TRACE_ID, SPAN_ID)),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse(&format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID)),
+            None
+        );
+    }
+
+    /// All-zero IDs mean "no ID"
+    #[test]
+    fn zero_ids() {
+        let zero_trace = "0".repeat(32);
+        let zero_span = "0".repeat(16);
+        assert_eq!(
+            TraceContext::parse(&format!("00-{}-{}-01", zero_trace, SPAN_ID)),
+            None
+        );
+        assert_eq!(
+            TraceContext::parse(&format!("00-{}-{}-01", TRACE_ID, zero_span)),
+            None
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        for traceparent in [
+            String::new(),
+            format!("00-{}-{}", TRACE_ID, SPAN_ID),
+            format!("0-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID),
+            format!("00-{}-{}-1", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID),
+            format!("00-{}-{}-0g", TRACE_ID, SPAN_ID),
+            format!("00_{}_{}_01", TRACE_ID, SPAN_ID),
+        ] {
+            assert_eq!(TraceContext::parse(&traceparent), None, "{}", traceparent);
+        }
+    }
+}
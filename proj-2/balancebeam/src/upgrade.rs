@@ -0,0 +1,256 @@
+use crate::stream::{self, Listener};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpSocket, UnixListener, UnixStream};
+use tokio::time::{timeout, Duration};
+
+/// Tells a new process which of its file descriptors are listening sockets handed down to it, as
+/// comma-separated address=fd pairs (the address being the one given on the command line)
+const LISTENER_FDS_VAR: &str = "BALANCEBEAM_LISTENER_FDS";
+
+/// Tells a new process which file descriptor to report that it's ready on
+const READY_FD_VAR: &str = "BALANCEBEAM_READY_FD";
+
+/// How long the new process has to start up before we give up on it and keep serving ourselves
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Backlog for listening sockets we set up ourselves (with --reuse-port)
+const LISTEN_BACKLOG: u32 = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The new process couldn't be started
+    SpawnFailed(#[allow(dead_code)] std::io::Error),
+    /// The new process exited (or closed its end of the readiness socket) before it was ready
+    ExitedEarly,
+    /// The new process didn't get ready within READY_TIMEOUT
+    TimedOut,
+}
+
+/// What the process we are replacing handed down to us, if we were started by an upgrade
+pub struct Inherited {
+    listeners: HashMap<String, OwnedFd>,
+    ready: Option<StdUnixStream>,
+}
+
+impl Inherited {
+    /// Picks up whatever the previous process handed down. Must be called once, at startup, before
+    /// any files are opened or any threads started (since it clears the environment variables).
+    pub fn from_env() -> Inherited {
+        let mut inherited = Inherited {
+            listeners: HashMap::new(),
+            ready: None,
+        };
+        if let Ok(listeners) = std::env::var(LISTENER_FDS_VAR) {
+            for pair in listeners.split(',').filter(|pair| !pair.is_empty()) {
+                let Some((address, fd)) = pair.rsplit_once('=') else {
+                    continue;
+                };
+                let Some(fd) = parse_socket_fd(fd) else {
+                    log::warn!(
+                        "Ignoring inherited listener {}: {} isn't a socket",
+                        address,
+                        fd
+                    );
+                    continue;
+                };
+                if inherited
+                    .listeners
+                    .values()
+                    .any(|listener| listener.as_raw_fd() == fd)
+                {
+                    log::warn!("Ignoring inherited listener {}: {} is taken", address, fd);
+                    continue;
+                }
+                // Safety: the previous process set this variable for us, the descriptor is a
+                // socket, and no other OwnedFd owns it
+                let listener = unsafe { OwnedFd::from_raw_fd(fd) };
+                inherited.listeners.insert(address.to_string(), listener);
+            }
+        }
+        if let Some(fd) = std::env::var(READY_FD_VAR)
+            .ok()
+            .and_then(|fd| parse_socket_fd(&fd))
+            .filter(|fd| {
+                !inherited
+                    .listeners
+                    .values()
+                    .any(|listener| listener.as_raw_fd() == *fd)
+            })
+        {
+            // Safety: as above
+            inherited.ready = Some(unsafe { StdUnixStream::from_raw_fd(fd) });
+        }
+        // Processes we start ourselves get their own values
+        std::env::remove_var(LISTENER_FDS_VAR);
+        std::env::remove_var(READY_FD_VAR);
+        inherited
+    }
+
+    /// Tells the previous process that we are up and running, so that it can stop accepting
+    /// connections. Listeners it handed down that we didn't take are closed.
+    pub fn notify_ready(self) {
+        if let Some(mut ready) = self.ready {
+            if let Err(error) = ready.write_all(b"1") {
+                log::warn!(
+                    "Could not tell the previous process that we are ready: {}",
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Parses a file descriptor handed down in an environment variable, returning it only if it is an
+/// open socket. Anything else (a stale or forged variable) mustn't be taken over, since closing it
+/// later would close some other file of ours.
+fn parse_socket_fd(fd: &str) -> Option<RawFd> {
+    let fd = fd.parse::<RawFd>().ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // Safety: fstat only writes to the buffer it's given, and we only read it if fstat succeeded
+    let stat = unsafe {
+        if libc::fstat(fd, stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    (stat.st_mode & libc::S_IFMT == libc::S_IFSOCK).then_some(fd)
+}
+
+/// Returns a listener for the given address (host:port, or unix:/path for a Unix domain socket):
+/// the one the previous process handed down for it, if there is one, and a newly bound one
+/// otherwise. With reuse_port, a new TCP listener is bound with SO_REUSEPORT, so that other
+/// processes can listen on the same address at the same time.
+pub async fn bind(
+    address: &str,
+    reuse_port: bool,
+    inherited: &mut Inherited,
+) -> std::io::Result<Listener> {
+    let handed_down = inherited.listeners.remove(address);
+    if let Some(path) = stream::unix_path(address) {
+        let listener = match handed_down {
+            Some(fd) => {
+                log::info!("Took over the listening socket for {}", address);
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                UnixListener::from_std(listener)?
+            }
+            None => {
+                remove_stale_socket(path).await;
+                UnixListener::bind(path)?
+            }
+        };
+        return Ok(Listener::Unix(listener));
+    }
+    if let Some(fd) = handed_down {
+        log::info!("Took over the listening socket for {}", address);
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(listener)?));
+    }
+    if !reuse_port {
+        return Ok(Listener::Tcp(TcpListener::bind(address).await?));
+    }
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(address).await? {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        match socket.bind(addr) {
+            Ok(()) => return Ok(Listener::Tcp(socket.listen(LISTEN_BACKLOG)?)),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "address resolved to nothing",
+        )
+    }))
+}
+
+/// Removes the socket file at the given path if it was left behind by a process that is no longer
+/// listening on it (binding fails if the file exists). Sockets someone is listening on are left
+/// alone, so that binding fails as it would for a TCP address in use.
+async fn remove_stale_socket(path: &str) {
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket && UnixStream::connect(path).await.is_err() {
+        log::info!("Removing stale socket file {}", path);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Starts a new balancebeam process from the binary we were started from (which may have been
+/// replaced with a newer version since), with the same arguments, handing it our listening
+/// sockets (given as the address each was bound for, and its file descriptor, which must stay
+/// open until this returns). Returns its process ID once it is ready to take over.
+pub async fn spawn_replacement(listeners: &[(String, RawFd)]) -> Result<u32, Error> {
+    let (ours, theirs) = StdUnixStream::pair().map_err(Error::SpawnFailed)?;
+    let mut fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+    fds.push(theirs.as_raw_fd());
+
+    let mut command = tokio::process::Command::new(current_binary());
+    command
+        .args(std::env::args_os().skip(1))
+        .env(
+            LISTENER_FDS_VAR,
+            listeners
+                .iter()
+                .map(|(address, fd)| format!("{}={}", address, fd))
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+        .env(READY_FD_VAR, theirs.as_raw_fd().to_string());
+    // Safety: fcntl is async-signal-safe, and only touches descriptors we own
+    unsafe {
+        command.pre_exec(move || {
+            // Rust opens everything close-on-exec; these are the descriptors the new process needs
+            for fd in &fds {
+                if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().map_err(Error::SpawnFailed)?;
+    // Only the new process should hold its end now, so that we notice if it goes away
+    drop(theirs);
+
+    ours.set_nonblocking(true).map_err(Error::SpawnFailed)?;
+    let mut ours = UnixStream::from_std(ours).map_err(Error::SpawnFailed)?;
+    let mut byte = [0; 1];
+    match timeout(READY_TIMEOUT, ours.read(&mut byte)).await {
+        Ok(Ok(1)) => Ok(child.id().unwrap_or_default()),
+        Ok(_) => {
+            let _ = child.wait().await;
+            Err(Error::ExitedEarly)
+        }
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(Error::TimedOut)
+        }
+    }
+}
+
+/// The path of the binary we were started from. If it has been replaced since, Linux reports the
+/// old file's path with " (deleted)" on the end; the new file is at the path without it.
+pub fn current_binary() -> std::path::PathBuf {
+    let path =
+        std::env::current_exe().unwrap_or_else(|_| std::env::args_os().next().unwrap().into());
+    match path
+        .to_str()
+        .and_then(|path| path.strip_suffix(" (deleted)"))
+    {
+        Some(path) => path.into(),
+        None => path,
+    }
+}
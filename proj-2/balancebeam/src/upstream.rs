@@ -0,0 +1,356 @@
+use crate::outlier::{Health, OutlierDetection};
+use arc_swap::ArcSwap;
+use rand::Rng;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout, Duration};
+
+/// Where balancebeam learned about an upstream. Each source of upstreams only ever adds and removes
+/// its own upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// --upstream (after resolving hostnames)
+    CommandLine,
+    /// --upstream-file
+    File,
+}
+
+/// What a source of upstreams tells us about an upstream.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamSpec {
+    pub address: String,
+    /// Share of new connections this upstream gets, relative to the other upstreams
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Free-form labels, e.g. for the version of the service the upstream runs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The group the upstream belongs to, for splitting traffic between groups
+    #[serde(default = "default_group")]
+    pub group: String,
+    /// Most connections balancebeam may have open to the upstream at once, overriding
+    /// --upstream-max-connections (0 = unlimited)
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_group() -> String {
+    "default".to_string()
+}
+
+/// Splits an upstream written as group=address (e.g. "canary=10.0.0.1:80") into its group and
+/// address. Upstreams without a group are in the "default" group.
+pub fn parse_grouped_address(upstream: &str) -> (String, &str) {
+    match upstream.split_once('=') {
+        Some((group, address)) => (group.to_string(), address),
+        None => (default_group(), upstream),
+    }
+}
+
+impl UpstreamSpec {
+    /// An upstream in the given group, with the default weight and no tags
+    pub fn new(address: String, group: String) -> UpstreamSpec {
+        UpstreamSpec {
+            address,
+            weight: default_weight(),
+            tags: Vec::new(),
+            group,
+            max_connections: None,
+        }
+    }
+}
+
+/// How many connections we may open to each upstream, and what happens to connections that have to
+/// wait for one of those to close.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimits {
+    /// Applies to upstreams that don't set their own max_connections (0 = unlimited)
+    pub max_connections: usize,
+    /// How many connections may wait for a free slot on one upstream; beyond that they are turned
+    /// away at once
+    pub queue_size: usize,
+    /// How long a connection may wait for a free slot
+    pub queue_timeout: Duration,
+    /// How long a client may keep its upstream connection (and slot) while it sends no requests
+    pub idle_timeout: Duration,
+}
+
+/// Why we couldn't get a connection slot on an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionError {
+    /// Too many connections were already waiting
+    QueueFull,
+    /// No slot came free within the queue timeout
+    TimedOut,
+}
+
+/// An upstream server in the pool.
+#[derive(Debug)]
+pub struct Upstream {
+    pub spec: UpstreamSpec,
+    pub origin: Origin,
+    /// Number of client connections currently being proxied to this upstream
+    active_connections: AtomicUsize,
+    /// Connection slots, if the number of connections to the upstream is limited
+    slots: Option<Arc<Semaphore>>,
+    pub max_connections: usize,
+    /// Number of connections waiting for a slot
+    queued: AtomicUsize,
+    /// Tells idle connections holding a slot that somebody has started waiting for one
+    slot_wanted: Notify,
+    /// Number of connections turned away because the queue was full
+    queue_rejections: AtomicU64,
+    /// Number of connections that gave up waiting for a slot
+    queue_timeouts: AtomicU64,
+    /// Cleared when connecting to the upstream or health checking it fails, and set again once it
+    /// passes a health check. Only healthy upstreams get new connections.
+    healthy: AtomicBool,
+    /// Set once the upstream has been removed from the pool. Connections that are already using it
+    /// may finish what they are doing, but shouldn't send it anything new.
+    draining: AtomicBool,
+    /// How the upstream has been doing lately, for outlier detection
+    pub health: Health,
+}
+
+impl Upstream {
+    pub fn new(spec: UpstreamSpec, origin: Origin, limits: &ConnectionLimits) -> Arc<Upstream> {
+        let max_connections = spec.max_connections.unwrap_or(limits.max_connections);
+        Arc::new(Upstream {
+            spec,
+            origin,
+            active_connections: AtomicUsize::new(0),
+            slots: (max_connections > 0).then(|| Arc::new(Semaphore::new(max_connections))),
+            max_connections,
+            queued: AtomicUsize::new(0),
+            slot_wanted: Notify::new(),
+            queue_rejections: AtomicU64::new(0),
+            queue_timeouts: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            draining: AtomicBool::new(false),
+            health: Health::default(),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.spec.address
+    }
+
+    pub fn group(&self) -> &str {
+        &self.spec.group
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Takes the upstream out of rotation until it passes a health check. Returns whether it was
+    /// healthy until now.
+    pub fn mark_failed(&self) -> bool {
+        self.healthy.swap(false, Ordering::SeqCst)
+    }
+
+    /// Puts the upstream back into rotation. Returns whether it had failed until now.
+    pub fn mark_healthy(&self) -> bool {
+        !self.healthy.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        if self.active_connections.load(Ordering::SeqCst) > 0 {
+            log::info!(
+                "Draining upstream {} ({} connections left)",
+                self.address(),
+                self.active_connections.load(Ordering::SeqCst)
+            );
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn queue_rejections(&self) -> u64 {
+        self.queue_rejections.load(Ordering::SeqCst)
+    }
+
+    pub fn queue_timeouts(&self) -> u64 {
+        self.queue_timeouts.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether a new connection could be opened right now without waiting
+    pub fn has_free_slot(&self) -> bool {
+        self.slots
+            .as_ref()
+            .is_none_or(|slots| slots.available_permits() > 0)
+    }
+
+    /// Waits for a free connection slot on the upstream (if its connections are limited). The slot
+    /// is held until the returned permit is dropped.
+    pub async fn admit(
+        &self,
+        limits: &ConnectionLimits,
+    ) -> Result<Option<OwnedSemaphorePermit>, AdmissionError> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= limits.queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.queue_rejections.fetch_add(1, Ordering::SeqCst);
+            return Err(AdmissionError::QueueFull);
+        }
+        self.slot_wanted.notify_waiters();
+        let result = timeout(limits.queue_timeout, slots.clone().acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match result {
+            // The semaphore is never closed
+            Ok(permit) => Ok(Some(permit.unwrap())),
+            Err(_) => {
+                self.queue_timeouts.fetch_add(1, Ordering::SeqCst);
+                Err(AdmissionError::TimedOut)
+            }
+        }
+    }
+
+    /// Returns once a client connection that is between requests should let go of its connection
+    /// to this upstream, and of the slot that comes with it: as soon as another connection is
+    /// waiting for a slot, or once the client has been idle for the idle timeout
+    pub async fn wait_until_idle_connection_wanted(&self, limits: &ConnectionLimits) {
+        let wanted = self.slot_wanted.notified();
+        tokio::pin!(wanted);
+        // Start listening before looking at the queue, so that nobody joins it unnoticed in between
+        wanted.as_mut().enable();
+        if self.queued() > 0 {
+            return;
+        }
+        tokio::select! {
+            _ = wanted => {}
+            _ = sleep(limits.idle_timeout) => {}
+        }
+    }
+
+    /// Records that a client connection is now using this upstream (holding the given connection
+    /// slot, if any), until the returned guard is dropped
+    pub fn track_connection(
+        self: &Arc<Self>,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> ActiveConnection {
+        self.active_connections.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection {
+            upstream: self.clone(),
+            _slot: slot,
+        }
+    }
+}
+
+/// A client connection's claim on an upstream. See Upstream::track_connection.
+pub struct ActiveConnection {
+    upstream: Arc<Upstream>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveConnection {
+    pub fn upstream(&self) -> &Arc<Upstream> {
+        &self.upstream
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let remaining = self
+            .upstream
+            .active_connections
+            .fetch_sub(1, Ordering::SeqCst)
+            - 1;
+        if remaining == 0 && self.upstream.is_draining() {
+            log::info!("Upstream {} has finished draining", self.upstream.address());
+        }
+    }
+}
+
+/// The upstreams balancebeam knows about, healthy or not. Connections look at an immutable snapshot
+/// of the list, which they get without taking any lock; changes to the list are made to a copy that
+/// is then swapped in. Upstreams failing and recovering only flips their healthy flags, so it
+/// doesn't touch the list at all.
+pub struct Pool {
+    upstreams: ArcSwap<Vec<Arc<Upstream>>>,
+    /// Held while the list is being changed, so that changes made at the same time (by DNS
+    /// refreshes and upstream file changes) don't undo each other
+    changing: parking_lot::Mutex<()>,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool {
+            upstreams: ArcSwap::from_pointee(Vec::new()),
+            changing: parking_lot::Mutex::new(()),
+        }
+    }
+
+    /// Returns the current list of upstreams. This is cheap, but the guard shouldn't be held across
+    /// an await; use snapshot() for that.
+    pub fn load(&self) -> arc_swap::Guard<Arc<Vec<Arc<Upstream>>>> {
+        self.upstreams.load()
+    }
+
+    /// Returns the current list of upstreams, to hold on to for as long as needed
+    pub fn snapshot(&self) -> Arc<Vec<Arc<Upstream>>> {
+        self.upstreams.load_full()
+    }
+
+    /// Changes the list of upstreams. Connections that are picking an upstream meanwhile carry on
+    /// with the old list.
+    pub fn update(&self, change: impl FnOnce(&mut Vec<Arc<Upstream>>)) {
+        let _changing = self.changing.lock();
+        let mut upstreams = Vec::clone(&self.upstreams.load());
+        change(&mut upstreams);
+        self.upstreams.store(Arc::new(upstreams));
+    }
+}
+
+/// Picks one of the given upstreams at random, with each upstream's chance proportional to its
+/// weight (scaled down for upstreams that are ejected or in slow start). Returns None if there are
+/// no upstreams with a non-zero weight.
+pub fn choose_weighted(
+    upstreams: &[&Arc<Upstream>],
+    outlier_detection: Option<&OutlierDetection>,
+) -> Option<Arc<Upstream>> {
+    let weights: Vec<f64> = upstreams
+        .iter()
+        .map(|u| u.spec.weight as f64 * u.health.weight_factor(outlier_detection))
+        .collect();
+    let total_weight: f64 = weights.iter().sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+    let mut point = rand::thread_rng().gen_range(0.0..total_weight);
+    for (upstream, weight) in upstreams.iter().zip(weights) {
+        if point < weight {
+            return Some(Arc::clone(upstream));
+        }
+        point -= weight;
+    }
+    // Rounding may leave the point just past the last upstream with a non-zero weight
+    upstreams
+        .iter()
+        .rev()
+        .find(|u| u.spec.weight > 0 && !u.health.is_ejected())
+        .map(|upstream| Arc::clone(upstream))
+}
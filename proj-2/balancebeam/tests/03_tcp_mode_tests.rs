@@ -0,0 +1,96 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TcpEchoServer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn setup_tcp(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(TcpEchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address())
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, &["--mode", "tcp"]).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends a message over a fresh connection to balancebeam and returns whatever comes back
+async fn send_raw(balancebeam: &BalanceBeam, message: &[u8]) -> Vec<u8> {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(message)
+        .await
+        .expect("Could not write to balancebeam");
+    let mut reply = vec![0_u8; message.len()];
+    conn.read_exact(&mut reply)
+        .await
+        .expect("Could not read reply from balancebeam");
+    reply
+}
+
+/// Send bytes that are definitely not HTTP, and make sure they are passed through untouched
+#[tokio::test]
+async fn test_tcp_mode_splices_raw_bytes() {
+    let (balancebeam, mut upstreams) = setup_tcp(1).await;
+
+    for i in 0..3 {
+        let message = format!("\x00\x01 not http at all {}\r\n\r\n", i).into_bytes();
+        log::info!("Sending raw message #{}", i);
+        assert_eq!(send_raw(&balancebeam, &message).await, message);
+    }
+
+    log::info!("Checking that the upstream received 3 connections");
+    assert_eq!(upstreams.pop().unwrap().stop().await, 3);
+
+    log::info!("All done :)");
+}
+
+/// HTTP requests should still work in TCP mode, but balancebeam must not touch them (so no
+/// X-Forwarded-For header gets added)
+#[tokio::test]
+async fn test_tcp_mode_does_not_parse_http() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--mode", "tcp"]).await;
+
+    let response_text = balancebeam
+        .get("/tcp-mode")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /tcp-mode HTTP/1.1"));
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Kill one of the upstreams and make sure connections fail over to the other one
+#[tokio::test]
+async fn test_tcp_mode_failover() {
+    let (balancebeam, mut upstreams) = setup_tcp(2).await;
+
+    log::info!("Killing one of the upstream servers");
+    upstreams.pop().unwrap().stop().await;
+
+    for i in 0..6 {
+        let message = format!("failover-{}", i).into_bytes();
+        log::info!(
+            "Sending raw message #{} after killing an upstream server",
+            i
+        );
+        assert_eq!(send_raw(&balancebeam, &message).await, message);
+    }
+
+    assert_eq!(upstreams.pop().unwrap().stop().await, 6);
+    log::info!("All done :)");
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use common::{
+    init_logging, read_response, send_raw_request, BalanceBeam, EchoServer, Server, TcpEchoServer,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Starts a balancebeam accepting PROXY protocol headers from the given proxies
+async fn setup_accepting_from(trusted_proxy: &str) -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--accept-proxy-protocol", "--trusted-proxy", trusted_proxy],
+    )
+    .await;
+    (balancebeam, upstream)
+}
+
+async fn setup_accepting() -> (BalanceBeam, EchoServer) {
+    setup_accepting_from("127.0.0.1").await
+}
+
+/// A v1 header's source address should be used as the client address in X-Forwarded-For
+#[tokio::test]
+async fn test_accept_v1_header() {
+    let (balancebeam, upstream) = setup_accepting().await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"PROXY TCP4 203.0.113.7 127.0.0.1 51000 80\r\n\
+        GET /v1 HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("GET /v1 HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 203.0.113.7"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Same as above, but with a binary v2 header carrying an IPv6 address
+#[tokio::test]
+async fn test_accept_v2_header() {
+    let (balancebeam, upstream) = setup_accepting().await;
+
+    let mut request = V2_SIGNATURE.to_vec();
+    // Version 2 PROXY command, TCP over IPv6, 36 bytes of addresses
+    request.extend_from_slice(&[0x21, 0x21, 0x00, 36]);
+    request.extend_from_slice(
+        &"2001:db8::42"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    request.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+    request.extend_from_slice(&51000_u16.to_be_bytes());
+    request.extend_from_slice(&80_u16.to_be_bytes());
+    request.extend_from_slice(b"GET /v2 HTTP/1.1\r\nHost: example.com\r\n\r\n");
+
+    let response_text = send_raw_request(&balancebeam.address, &request).await;
+    assert!(response_text.contains("GET /v2 HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 2001:db8::42"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A v2 LOCAL header carries no addresses, so the peer address should be used. Multiple requests
+/// on the same connection should keep working after the header.
+#[tokio::test]
+async fn test_accept_v2_local_header() {
+    let (balancebeam, upstream) = setup_accepting().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    conn.write_all(&header).await.unwrap();
+    for i in 0..2 {
+        let request = format!("GET /local-{} HTTP/1.1\r\nHost: example.com\r\n\r\n", i);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let response_text = read_response(&mut conn).await;
+        assert!(response_text.contains(&format!("GET /local-{} HTTP/1.1", i)));
+        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Connections without a header must be dropped without reaching the upstream, since we can't
+/// know who the client is
+#[tokio::test]
+async fn test_missing_header_is_rejected() {
+    let (balancebeam, upstream) = setup_accepting().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /no-header HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = Vec::new();
+    let _ = conn.read_to_end(&mut buffer).await;
+    assert!(
+        buffer.is_empty(),
+        "balancebeam should hang up without replying"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Peers that aren't trusted proxies are clients themselves: their requests need no header, and
+/// a header they send anyway is no way to choose their address
+#[tokio::test]
+async fn test_untrusted_peer_is_the_client() {
+    let (balancebeam, upstream) = setup_accepting_from("192.0.2.0/24").await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"GET /direct HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+
+    log::info!("Sending a PROXY header from an untrusted peer");
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"PROXY TCP4 203.0.113.7 127.0.0.1 51000 80\r\n\
+        GET /spoofed HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response_text.starts_with("HTTP/1.1 400"),
+        "{}",
+        response_text
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A trusted proxy that never sends its header should be hung up on rather than holding the
+/// connection open forever
+#[tokio::test]
+async fn test_header_timeout() {
+    let (balancebeam, upstream) = setup_accepting().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"PROXY TCP4").await.unwrap();
+    let mut buffer = Vec::new();
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        conn.read_to_end(&mut buffer),
+    )
+    .await;
+    assert!(read.is_ok(), "balancebeam should have hung up by now");
+    assert!(buffer.is_empty());
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// In TCP mode, balancebeam should pass the client address on to the upstream. The upstream here
+/// echoes everything back, so we get to see the header balancebeam sent.
+#[tokio::test]
+async fn test_send_v1_header_in_tcp_mode() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--mode",
+            "tcp",
+            "--accept-proxy-protocol",
+            "--trusted-proxy",
+            "127.0.0.0/8",
+            "--send-proxy-protocol",
+            "v1",
+        ],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"PROXY TCP4 198.51.100.1 192.0.2.1 40000 5432\r\nping")
+        .await
+        .unwrap();
+    let expected = b"PROXY TCP4 198.51.100.1 192.0.2.1 40000 5432\r\nping";
+    let mut reply = vec![0_u8; expected.len()];
+    conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        String::from_utf8_lossy(expected)
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Same as above with a v2 header, where the client connects to balancebeam directly
+#[tokio::test]
+async fn test_send_v2_header_in_tcp_mode() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mode", "tcp", "--send-proxy-protocol", "v2"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"ping").await.unwrap();
+    let mut reply = vec![0_u8; 12 + 4 + 12 + 4];
+    conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply[..12], V2_SIGNATURE);
+    assert_eq!(&reply[12..16], &[0x21, 0x11, 0x00, 12]);
+    // Source is our end of the connection, destination is balancebeam's listening address
+    let local_port = conn.local_addr().unwrap().port();
+    let balancebeam_port: u16 = balancebeam
+        .address
+        .rsplit(':')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(&reply[16..20], &[127, 0, 0, 1]);
+    assert_eq!(&reply[20..24], &[127, 0, 0, 1]);
+    assert_eq!(&reply[24..26], &local_port.to_be_bytes());
+    assert_eq!(&reply[26..28], &balancebeam_port.to_be_bytes());
+    assert_eq!(&reply[28..], b"ping");
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server, TempFile};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn setup_with_config(
+    config: &str,
+    extra_args: &[&str],
+) -> (BalanceBeam, EchoServer, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(config);
+    let upstream = EchoServer::new().await;
+    let mut args = vec!["--config", config_file.path_str()];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+    (balancebeam, upstream, config_file)
+}
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Clients matching the global deny list should be refused before any request is read
+#[tokio::test]
+async fn test_global_deny_refuses_connections() {
+    let (balancebeam, upstream, _config) =
+        setup_with_config(r#"{"access": {"deny": ["127.0.0.0/8"]}}"#, &[]).await;
+
+    log::info!("Sending a request from a denied address");
+    assert!(
+        balancebeam.get("/denied").await.is_err(),
+        "balancebeam should have refused the connection"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Route access lists apply only to requests under the route's prefix, and denied requests get a
+/// 403
+#[tokio::test]
+async fn test_route_access_list() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{
+            "access": {"allow": ["127.0.0.1"]},
+            "routes": [{"prefix": "/internal", "access": {"allow": ["10.0.0.0/8"]}}]
+        }"#,
+        &[],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam, "/public").await, 200);
+    assert_eq!(get_status(&balancebeam, "/internalish").await, 200);
+    assert_eq!(get_status(&balancebeam, "/internal").await, 403);
+    assert_eq!(get_status(&balancebeam, "/internal/secrets").await, 403);
+
+    log::info!("Checking that only the permitted requests reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Other ways of writing a path under a route's prefix should still go through the route, and the
+/// upstream should be sent the path in its plain form
+#[tokio::test]
+async fn test_route_access_list_normalizes_paths() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{"routes": [{"prefix": "/admin", "access": {"deny": ["127.0.0.1"]}}]}"#,
+        &[],
+    )
+    .await;
+
+    for path in [
+        "//admin",
+        "/./admin",
+        "/x/../admin",
+        "/%61dmin",
+        "/%2e%2e/admin/users",
+    ] {
+        log::info!("Requesting {}", path);
+        let response_text = send_raw_request(
+            &balancebeam.address,
+            format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path).as_bytes(),
+        )
+        .await;
+        assert!(
+            response_text.starts_with("HTTP/1.1 403"),
+            "{} should have been denied:\n{}",
+            path,
+            response_text
+        );
+    }
+
+    log::info!("Checking the path the upstream gets");
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"GET //public/./x/../%7eme HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response_text.contains("GET /public/~me HTTP/1.1"),
+        "{}",
+        response_text
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Access lists should be re-read along with the rest of the configuration on SIGHUP
+#[tokio::test]
+async fn test_access_list_reload() {
+    let (balancebeam, upstream, config) = setup_with_config(
+        r#"{"routes": [{"prefix": "/admin", "access": {"deny": ["127.0.0.1"]}}]}"#,
+        &[],
+    )
+    .await;
+    assert_eq!(get_status(&balancebeam, "/admin").await, 403);
+
+    log::info!("Rewriting the configuration to allow localhost and reloading");
+    config.write(r#"{"routes": [{"prefix": "/admin", "access": {"allow": ["127.0.0.1"]}}]}"#);
+    balancebeam.send_signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/admin").await, 200);
+
+    log::info!("A broken configuration should be ignored, keeping the previous one");
+    config.write(r#"{"routes": [{"prefix": "/admin", "access": {"allow": ["not an ip"]}}]}"#);
+    balancebeam.send_signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/admin").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Behind another proxy, the address from the PROXY protocol header is the one that gets checked
+#[tokio::test]
+async fn test_access_list_uses_proxy_protocol_address() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{"access": {"deny": ["203.0.113.0/24"]}}"#,
+        &["--accept-proxy-protocol", "--trusted-proxy", "127.0.0.1"],
+    )
+    .await;
+
+    let response_text = send_raw_request(
+        &balancebeam.address,
+        b"PROXY TCP4 198.51.100.9 127.0.0.1 51000 80\r\n\
+        GET /allowed HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await;
+    assert!(response_text.contains("x-forwarded-for: 198.51.100.9"));
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        b"PROXY TCP4 203.0.113.9 127.0.0.1 51000 80\r\n\
+        GET /denied HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut buffer = Vec::new();
+    let _ = conn.read_to_end(&mut buffer).await;
+    assert!(
+        buffer.is_empty(),
+        "balancebeam should hang up on denied clients"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A proxy the access list denies shouldn't get in by vouching for a client that is permitted
+#[tokio::test]
+async fn test_access_list_checks_proxy_too() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{"access": {"deny": ["127.0.0.1"]}}"#,
+        &["--accept-proxy-protocol", "--trusted-proxy", "127.0.0.1"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let _ = conn
+        .write_all(
+            b"PROXY TCP4 198.51.100.9 127.0.0.1 51000 80\r\n\
+            GET /allowed HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        )
+        .await;
+    let mut buffer = Vec::new();
+    let _ = conn.read_to_end(&mut buffer).await;
+    assert!(
+        buffer.is_empty(),
+        "balancebeam should hang up on denied proxies"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
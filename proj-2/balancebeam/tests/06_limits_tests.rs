@@ -0,0 +1,111 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TempFile};
+
+async fn setup_with_config(config: &str) -> (BalanceBeam, EchoServer, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(config);
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", config_file.path_str()])
+            .await;
+    (balancebeam, upstream, config_file)
+}
+
+async fn post_status(balancebeam: &BalanceBeam, path: &str, body_size: usize) -> u16 {
+    reqwest::Client::new()
+        .post(format!("http://{}{}", balancebeam.address, path))
+        .body("x".repeat(body_size))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+async fn get_with_headers_status(balancebeam: &BalanceBeam, path: &str, n_headers: usize) -> u16 {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    for i in 0..n_headers {
+        request = request.header(format!("x-extra-{}", i), "some value");
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// A route can allow bigger request bodies than the global limit, and the global limit applies
+/// everywhere else
+#[tokio::test]
+async fn test_request_body_limits() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{
+            "limits": {"request": {"max_body_size": 100}},
+            "routes": [{"prefix": "/upload", "limits": {"request": {"max_body_size": 5000}}}]
+        }"#,
+    )
+    .await;
+
+    assert_eq!(post_status(&balancebeam, "/api", 100).await, 200);
+    assert_eq!(post_status(&balancebeam, "/api", 101).await, 413);
+    assert_eq!(post_status(&balancebeam, "/upload/file", 5000).await, 200);
+    assert_eq!(post_status(&balancebeam, "/upload/file", 5001).await, 413);
+
+    log::info!("Checking that oversized requests never reached the upstream");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Requests with too many headers, or headers that are too big, get a 431. A route may be stricter
+/// than the global limits.
+#[tokio::test]
+async fn test_request_header_limits() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{
+            "limits": {"request": {"max_num_headers": 10}},
+            "routes": [
+                {"prefix": "/public", "limits": {"request": {"max_headers_size": 200}}},
+                {"prefix": "/internal", "limits": {"request": {"max_num_headers": 40}}}
+            ]
+        }"#,
+    )
+    .await;
+
+    // reqwest adds a couple of headers of its own, so leave some room for those
+    assert_eq!(get_with_headers_status(&balancebeam, "/", 3).await, 200);
+    assert_eq!(get_with_headers_status(&balancebeam, "/", 20).await, 431);
+    assert_eq!(
+        get_with_headers_status(&balancebeam, "/internal", 20).await,
+        200
+    );
+    assert_eq!(
+        get_with_headers_status(&balancebeam, "/public", 0).await,
+        200
+    );
+    assert_eq!(
+        get_with_headers_status(&balancebeam, "/public", 8).await,
+        431
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Responses bigger than the response limits are turned into a 502, rather than being passed on
+#[tokio::test]
+async fn test_response_body_limits() {
+    let (balancebeam, upstream, _config) = setup_with_config(
+        r#"{"routes": [{"prefix": "/small", "limits": {"response": {"max_body_size": 1000}}}]}"#,
+    )
+    .await;
+
+    // The echo server sends back our request, including the body
+    assert_eq!(post_status(&balancebeam, "/small", 100).await, 200);
+    assert_eq!(post_status(&balancebeam, "/small", 2000).await, 502);
+    assert_eq!(post_status(&balancebeam, "/large", 2000).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, Server, TempFile};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts echo servers listening on the same port on 127.0.0.1 and 127.0.0.2, so that a hostname
+/// can be pointed at either one (or both) through the hosts file
+async fn start_upstreams() -> (u16, EchoServer, EchoServer) {
+    let port: u16 = free_local_address()
+        .rsplit(':')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let first = EchoServer::new_at_address(format!("127.0.0.1:{}", port)).await;
+    let second = EchoServer::new_at_address(format!("127.0.0.2:{}", port)).await;
+    (port, first, second)
+}
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// A hostname upstream should be expanded into every address it resolves to
+#[tokio::test]
+async fn test_hostname_expands_to_all_addresses() {
+    init_logging();
+    let (port, first, second) = start_upstreams().await;
+    let hosts = TempFile::new("127.0.0.1 backend.test\n127.0.0.2 backend.test # second record\n");
+    let upstream = format!("backend.test:{}", port);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--hosts-file", hosts.path_str()]).await;
+
+    send_requests(&balancebeam, 30).await;
+
+    let first_count = Box::new(first).stop().await;
+    let second_count = Box::new(second).stop().await;
+    log::info!("Requests received: {} and {}", first_count, second_count);
+    assert_eq!(first_count + second_count, 30);
+    assert!(
+        first_count > 0 && second_count > 0,
+        "Both addresses behind the hostname should have received requests"
+    );
+    log::info!("All done :)");
+}
+
+/// When the records behind a hostname change, balancebeam should pick up the new addresses and
+/// stop using the old ones
+#[tokio::test]
+async fn test_reresolution_updates_pool() {
+    init_logging();
+    let (port, first, second) = start_upstreams().await;
+    let hosts = TempFile::new("127.0.0.1 backend.test\n");
+    let upstream = format!("backend.test:{}", port);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--hosts-file",
+            hosts.path_str(),
+            "--dns-refresh-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 5).await;
+
+    log::info!("Pointing the hostname at the second upstream");
+    hosts.write("127.0.0.2 backend.test\n");
+    sleep(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, 5).await;
+
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// If a name stops resolving, the addresses it had should stay in the pool
+#[tokio::test]
+async fn test_failed_resolution_keeps_addresses() {
+    init_logging();
+    let (port, first, second) = start_upstreams().await;
+    let hosts = TempFile::new("127.0.0.1 backend.test\n");
+    let upstream = format!("backend.test:{}", port);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--hosts-file",
+            hosts.path_str(),
+            "--dns-refresh-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Making the hosts file unreadable");
+    std::fs::remove_file(&hosts.path).unwrap();
+    sleep(Duration::from_secs(2)).await;
+    send_requests(&balancebeam, 5).await;
+
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 0);
+    log::info!("All done :)");
+}
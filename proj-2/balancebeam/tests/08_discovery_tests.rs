@@ -0,0 +1,194 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TcpEchoServer, TempFile};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+async fn start_balancebeam(upstream_file: &TempFile, extra_args: &[&str]) -> BalanceBeam {
+    init_logging();
+    let mut args = vec![
+        "--upstream-file",
+        upstream_file.path_str(),
+        "--upstream-file-poll-interval",
+        "1",
+    ];
+    args.extend_from_slice(extra_args);
+    BalanceBeam::new_with_args(&[], &args).await
+}
+
+async fn send_requests(client: &reqwest::Client, balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Upstreams can be listed one per line, and balancebeam should follow along when the file is
+/// rewritten (here in the JSON format)
+#[tokio::test]
+async fn test_upstream_file_changes_are_picked_up() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let upstream_file = TempFile::new(&format!(
+        "# written by the orchestrator\n{}\n",
+        first.address
+    ));
+    let balancebeam = start_balancebeam(&upstream_file, &[]).await;
+
+    send_requests(&reqwest::Client::new(), &balancebeam, 5).await;
+
+    log::info!("Replacing the first upstream with the second");
+    upstream_file.write(&format!(r#"[{{"address": "{}"}}]"#, second.address));
+    sleep(Duration::from_secs(2)).await;
+    send_requests(&reqwest::Client::new(), &balancebeam, 5).await;
+
+    assert_eq!(Box::new(first).stop().await, 5);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// A plain-text file caught while it is being rewritten shouldn't take upstreams out of the pool.
+/// Only an explicitly empty (JSON) list empties it.
+#[tokio::test]
+async fn test_truncated_upstream_file_is_ignored() {
+    let upstream = EchoServer::new().await;
+    let upstream_file = TempFile::new(&format!("{}\n", upstream.address));
+    // Health checks would show up in the request count
+    let balancebeam =
+        start_balancebeam(&upstream_file, &["--active-health-check-interval", "60"]).await;
+    send_requests(&reqwest::Client::new(), &balancebeam, 1).await;
+
+    let cut_short = &upstream.address[..upstream.address.len() - 2];
+    for contents in [
+        String::new(),
+        "# upstreams\n".to_string(),
+        cut_short.to_string(),
+        format!(
+            "{}\n",
+            &upstream.address[..upstream.address.find(':').unwrap() - 1]
+        ),
+    ] {
+        log::info!("Writing a truncated upstream file: {:?}", contents);
+        upstream_file.write(&contents);
+        sleep(Duration::from_secs(2)).await;
+        send_requests(&reqwest::Client::new(), &balancebeam, 1).await;
+    }
+
+    log::info!("Emptying the upstream list on purpose");
+    upstream_file.write("[]");
+    sleep(Duration::from_secs(2)).await;
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Upstreams should get traffic in proportion to their weights, and never with a weight of 0
+#[tokio::test]
+async fn test_upstream_weights() {
+    let heavy = EchoServer::new().await;
+    let light = EchoServer::new().await;
+    let unused = EchoServer::new().await;
+    let upstream_file = TempFile::new(&format!(
+        r#"[
+            {{"address": "{}", "weight": 4, "tags": ["big"]}},
+            {{"address": "{}", "weight": 1}},
+            {{"address": "{}", "weight": 0}}
+        ]"#,
+        heavy.address, light.address, unused.address
+    ));
+    // Health checks would show up in the request counts
+    let balancebeam =
+        start_balancebeam(&upstream_file, &["--active-health-check-interval", "60"]).await;
+
+    // Upstreams are picked per connection, so every request needs a connection of its own
+    for _ in 0..100 {
+        send_requests(&reqwest::Client::new(), &balancebeam, 1).await;
+    }
+
+    let heavy_count = Box::new(heavy).stop().await;
+    let light_count = Box::new(light).stop().await;
+    log::info!("Requests received: {} and {}", heavy_count, light_count);
+    assert_eq!(heavy_count + light_count, 100);
+    assert!(
+        heavy_count > light_count && light_count > 0,
+        "Upstreams should be picked in proportion to their weights"
+    );
+    assert_eq!(Box::new(unused).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Once an upstream is removed, a keep-alive connection that was using it should carry on with a
+/// different upstream, rather than being closed or sticking with the removed one
+#[tokio::test]
+async fn test_removed_upstream_drains_http() {
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let upstream_file = TempFile::new(&format!("{}\n", first.address));
+    let balancebeam = start_balancebeam(&upstream_file, &[]).await;
+
+    // The client keeps its connection to balancebeam open between requests
+    let client = reqwest::Client::new();
+    send_requests(&client, &balancebeam, 2).await;
+
+    log::info!("Replacing the first upstream with the second");
+    upstream_file.write(&format!("{}\n", second.address));
+    sleep(Duration::from_secs(2)).await;
+    send_requests(&client, &balancebeam, 3).await;
+
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// In TCP mode, connections to a removed upstream should stay open until the client is done with
+/// them, while new connections go to the remaining upstreams
+#[tokio::test]
+async fn test_removed_upstream_drains_tcp() {
+    let first = TcpEchoServer::new().await;
+    let second = TcpEchoServer::new().await;
+    let upstream_file = TempFile::new(&format!("{}\n", first.address));
+    let balancebeam = start_balancebeam(&upstream_file, &["--mode", "tcp"]).await;
+
+    let mut old_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut reply = [0_u8; 5];
+    old_conn.write_all(b"hello").await.unwrap();
+    old_conn.read_exact(&mut reply).await.unwrap();
+
+    log::info!("Replacing the first upstream with the second");
+    upstream_file.write(&format!("{}\n", second.address));
+    sleep(Duration::from_secs(2)).await;
+
+    log::info!("Checking that the existing connection still works");
+    old_conn.write_all(b"still").await.unwrap();
+    old_conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"still");
+
+    let mut new_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    new_conn.write_all(b"fresh").await.unwrap();
+    new_conn.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"fresh");
+
+    drop(old_conn);
+    drop(new_conn);
+    assert_eq!(Box::new(first).stop().await, 1);
+    assert_eq!(Box::new(second).stop().await, 1);
+    log::info!("All done :)");
+}
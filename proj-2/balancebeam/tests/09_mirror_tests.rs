@@ -0,0 +1,84 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .post(&path, "some body")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("POST {} HTTP/1.1", path)));
+        assert!(response_text.contains("some body"));
+    }
+}
+
+/// With the default settings every request should reach both the primary and the shadow upstream
+#[tokio::test]
+async fn test_mirror_all_requests() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&primary.address], &["--mirror-upstream", &shadow.address])
+            .await;
+
+    send_requests(&balancebeam, 10).await;
+    // Mirrored requests are sent in the background, so give the last ones a moment to arrive
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(Box::new(primary).stop().await, 10);
+    assert_eq!(Box::new(shadow).stop().await, 10);
+    log::info!("All done :)");
+}
+
+/// Only about the configured share of requests should be mirrored
+#[tokio::test]
+async fn test_mirror_percent() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let shadow = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary.address],
+        &[
+            "--mirror-upstream",
+            &shadow.address,
+            "--mirror-percent",
+            "30",
+            // Health checks would show up in the primary's request count
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    send_requests(&balancebeam, 100).await;
+    sleep(Duration::from_millis(500)).await;
+
+    let shadow_count = Box::new(shadow).stop().await;
+    log::info!("Shadow upstream received {} requests", shadow_count);
+    assert!(
+        shadow_count > 5 && shadow_count < 60,
+        "Roughly 30% of requests should have been mirrored"
+    );
+    assert_eq!(Box::new(primary).stop().await, 100);
+    log::info!("All done :)");
+}
+
+/// A shadow upstream that is down must not affect the responses clients get
+#[tokio::test]
+async fn test_mirror_failures_are_ignored() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let dead_shadow = free_local_address();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&primary.address], &["--mirror-upstream", &dead_shadow]).await;
+
+    send_requests(&balancebeam, 10).await;
+
+    assert_eq!(Box::new(primary).stop().await, 10);
+    log::info!("All done :)");
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use common::{
+    free_local_address, init_logging, BalanceBeam, EchoServer, ErrorServer, Server, TempFile,
+};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts balancebeam with a "stable" group that answers 200 and a "canary" group that answers 500,
+/// so the status of a response tells which group served it
+async fn setup_with_split(split: &str) -> (BalanceBeam, EchoServer, ErrorServer, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(&format!(r#"{{"split": {}}}"#, split));
+    let stable = EchoServer::new().await;
+    let canary = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[
+            &format!("stable={}", stable.address),
+            &format!("canary={}", canary.address),
+        ],
+        &[
+            "--config",
+            config_file.path_str(),
+            // The canary always fails its health checks
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    (balancebeam, stable, canary, config_file)
+}
+
+async fn get_status(balancebeam: &BalanceBeam, headers: &[(&str, &str)]) -> u16 {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Traffic should follow the split's weights, and a new split should take effect on SIGHUP
+#[tokio::test]
+async fn test_split_weights_and_reload() {
+    let (balancebeam, stable, canary, config_file) =
+        setup_with_split(r#"{"groups": {"stable": 3, "canary": 1}}"#).await;
+
+    for _ in 0..40 {
+        get_status(&balancebeam, &[]).await;
+    }
+
+    log::info!("Shifting all traffic to the stable group");
+    config_file.write(r#"{"split": {"groups": {"stable": 1, "canary": 0}}}"#);
+    balancebeam.send_signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    for _ in 0..20 {
+        assert_eq!(get_status(&balancebeam, &[]).await, 200);
+    }
+
+    let stable_count = Box::new(stable).stop().await;
+    let canary_count = Box::new(canary).stop().await;
+    log::info!("Requests received: {} and {}", stable_count, canary_count);
+    assert_eq!(stable_count + canary_count, 60);
+    assert!(
+        canary_count > 0 && canary_count < 20,
+        "The canary should have gotten roughly a quarter of the first 40 requests"
+    );
+    log::info!("All done :)");
+}
+
+/// Requests with the same sticky header or cookie should always go to the same group, even over
+/// separate connections
+#[tokio::test]
+async fn test_sticky_split() {
+    let (balancebeam, stable, canary, _config_file) = setup_with_split(
+        r#"{"groups": {"stable": 1, "canary": 1}, "sticky_header": "x-user-id", "sticky_cookie": "session"}"#,
+    )
+    .await;
+
+    let mut statuses = Vec::new();
+    for user in 0..20 {
+        let user = format!("user-{}", user);
+        let cookie = format!("theme=dark; session={}", user);
+        let by_header = get_status(&balancebeam, &[("x-user-id", &user)]).await;
+        for _ in 0..3 {
+            assert_eq!(
+                get_status(&balancebeam, &[("x-user-id", &user)]).await,
+                by_header
+            );
+        }
+        let by_cookie = get_status(&balancebeam, &[("cookie", &cookie)]).await;
+        for _ in 0..3 {
+            assert_eq!(
+                get_status(&balancebeam, &[("cookie", &cookie)]).await,
+                by_cookie
+            );
+        }
+        statuses.push(by_header);
+    }
+    assert!(
+        statuses.contains(&200) && statuses.contains(&500),
+        "Different users should be spread over both groups"
+    );
+
+    Box::new(stable).stop().await;
+    Box::new(canary).stop().await;
+    log::info!("All done :)");
+}
+
+/// Requests without a sticky key should stay in one group for the whole of their connection
+#[tokio::test]
+async fn test_split_per_connection() {
+    let (balancebeam, stable, canary, _config_file) =
+        setup_with_split(r#"{"groups": {"stable": 1, "canary": 1}}"#).await;
+
+    let mut first_statuses = Vec::new();
+    for _ in 0..10 {
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/", balancebeam.address);
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            let response = client.get(&url).send().await.unwrap();
+            statuses.push(response.status().as_u16());
+            response.bytes().await.unwrap();
+        }
+        assert!(
+            statuses.iter().all(|status| *status == statuses[0]),
+            "Requests on one connection went to different groups: {:?}",
+            statuses
+        );
+        first_statuses.push(statuses[0]);
+    }
+    log::info!("Groups of each connection: {:?}", first_statuses);
+
+    Box::new(stable).stop().await;
+    Box::new(canary).stop().await;
+    log::info!("All done :)");
+}
+
+/// If every upstream in the chosen group is down, requests should be served by another group
+#[tokio::test]
+async fn test_split_falls_back_to_other_groups() {
+    init_logging();
+    let config_file = TempFile::new(r#"{"split": {"groups": {"stable": 0, "canary": 1}}}"#);
+    let stable = EchoServer::new().await;
+    let dead_canary = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[
+            &format!("stable={}", stable.address),
+            &format!("canary={}", dead_canary),
+        ],
+        &["--config", config_file.path_str()],
+    )
+    .await;
+
+    for _ in 0..5 {
+        assert_eq!(get_status(&balancebeam, &[]).await, 200);
+    }
+
+    assert_eq!(Box::new(stable).stop().await, 5);
+    log::info!("All done :)");
+}
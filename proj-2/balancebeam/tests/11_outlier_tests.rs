@@ -0,0 +1,202 @@
+mod common;
+
+use common::{
+    free_local_address, init_logging, BalanceBeam, ErrorServer, NamedServer, Server, TempFile,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn start_balancebeam(
+    upstreams: &[&str],
+    outlier_detection: &str,
+    health_check_interval: &str,
+) -> (BalanceBeam, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(&format!(
+        r#"{{"outlier_detection": {}}}"#,
+        outlier_detection
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        upstreams,
+        &[
+            "--config",
+            config_file.path_str(),
+            "--active-health-check-interval",
+            health_check_interval,
+        ],
+    )
+    .await;
+    (balancebeam, config_file)
+}
+
+/// Sends requests over separate connections, and counts the responses by body (for NamedServers)
+/// or by status (for anything else)
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for _ in 0..n_requests {
+        let response = reqwest::get(format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        let key = if response.status().is_success() {
+            response.text().await.unwrap()
+        } else {
+            response.status().as_u16().to_string()
+        };
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Sends requests until three of them have failed, which is what it takes to get the failing
+/// upstream ejected in test_ejection_backs_off
+async fn send_until_ejected(balancebeam: &BalanceBeam) {
+    let mut errors = 0;
+    for _ in 0..100 {
+        errors += send_requests(balancebeam, 1).await.get("500").unwrap_or(&0);
+        if errors == 3 {
+            return;
+        }
+    }
+    panic!("The failing upstream was only hit {} times", errors);
+}
+
+/// An upstream that keeps failing should be ejected after the configured number of errors
+#[tokio::test]
+async fn test_consecutive_errors_eject() {
+    let good = NamedServer::new("good").await;
+    let bad = ErrorServer::new().await;
+    let (balancebeam, _config) = start_balancebeam(
+        &[&good.address, &bad.address],
+        r#"{"consecutive_errors": 3, "base_ejection_ms": 60000}"#,
+        "60",
+    )
+    .await;
+
+    let counts = send_requests(&balancebeam, 40).await;
+    log::info!("Responses: {:?}", counts);
+    assert_eq!(counts.get("500"), Some(&3));
+    assert_eq!(counts.get("good"), Some(&37));
+
+    Box::new(good).stop().await;
+    Box::new(bad).stop().await;
+    log::info!("All done :)");
+}
+
+/// Ejected upstreams come back once their ejection is over, and every further ejection lasts
+/// twice as long as the one before
+#[tokio::test]
+async fn test_ejection_backs_off() {
+    let good = NamedServer::new("good").await;
+    let bad = ErrorServer::new().await;
+    let (balancebeam, _config) = start_balancebeam(
+        &[&good.address, &bad.address],
+        r#"{"consecutive_errors": 3, "base_ejection_ms": 3000}"#,
+        "60",
+    )
+    .await;
+
+    // Each phase stops at the error that gets the upstream ejected, so the ejection starts right
+    // when the phase ends
+    send_until_ejected(&balancebeam).await;
+
+    log::info!("Waiting out the first ejection (3s)");
+    sleep(Duration::from_millis(3500)).await;
+    send_until_ejected(&balancebeam).await;
+
+    log::info!("The second ejection (6s) should still be going after 3s");
+    sleep(Duration::from_secs(3)).await;
+    let counts = send_requests(&balancebeam, 10).await;
+    assert_eq!(counts.get("500"), None);
+
+    log::info!("Waiting out the second ejection");
+    sleep(Duration::from_secs(4)).await;
+    send_until_ejected(&balancebeam).await;
+
+    Box::new(good).stop().await;
+    Box::new(bad).stop().await;
+    log::info!("All done :)");
+}
+
+/// An upstream that is much slower than the rest of the pool should be ejected
+#[tokio::test]
+async fn test_latency_outlier_ejected() {
+    let first = NamedServer::new("first").await;
+    let second = NamedServer::new("second").await;
+    let slow = NamedServer::new_slow("slow", Duration::from_millis(300)).await;
+    let (balancebeam, _config) = start_balancebeam(
+        &[&first.address, &second.address, &slow.address],
+        r#"{"consecutive_errors": 0, "latency_factor": 3, "min_latency_ms": 100, "base_ejection_ms": 60000}"#,
+        "60",
+    )
+    .await;
+
+    let counts = send_requests(&balancebeam, 60).await;
+    log::info!("Responses: {:?}", counts);
+    assert!(
+        counts.get("slow").copied().unwrap_or(0) <= 6,
+        "The slow upstream should have been ejected once it had answered a handful of requests"
+    );
+
+    Box::new(first).stop().await;
+    Box::new(second).stop().await;
+    Box::new(slow).stop().await;
+    log::info!("All done :)");
+}
+
+/// Outlier detection must never eject more than max_ejection_percent of the pool
+#[tokio::test]
+async fn test_max_ejection_percent() {
+    let first = ErrorServer::new().await;
+    let second = ErrorServer::new().await;
+    let (balancebeam, _config) = start_balancebeam(
+        &[&first.address, &second.address],
+        r#"{"consecutive_errors": 1, "base_ejection_ms": 60000, "max_ejection_percent": 50}"#,
+        "60",
+    )
+    .await;
+
+    // One of the two upstreams gets ejected, but the other one has to stay
+    let counts = send_requests(&balancebeam, 20).await;
+    assert_eq!(counts.get("500"), Some(&20));
+    let first_count = Box::new(first).stop().await;
+    let second_count = Box::new(second).stop().await;
+    log::info!("Requests received: {} and {}", first_count, second_count);
+    assert!(first_count == 1 || second_count == 1);
+    log::info!("All done :)");
+}
+
+/// An upstream that comes back after failing health checks should get a small share of traffic at
+/// first, and a full share once slow start is over
+#[tokio::test]
+async fn test_slow_start_after_recovery() {
+    let steady = NamedServer::new("steady").await;
+    let returning_address = free_local_address();
+    let (balancebeam, _config) = start_balancebeam(
+        &[&steady.address, &returning_address],
+        r#"{"slow_start_ms": 15000}"#,
+        "1",
+    )
+    .await;
+
+    log::info!("Waiting for the health check to notice the missing upstream");
+    sleep(Duration::from_millis(1500)).await;
+    let returning =
+        NamedServer::new_at_address("returning", Duration::ZERO, returning_address.clone()).await;
+    sleep(Duration::from_millis(1200)).await;
+
+    // Even if these requests are slow to get through, they should all be in the first third of
+    // slow start, where the returning upstream gets well under half of the traffic
+    let early = send_requests(&balancebeam, 30).await;
+    log::info!("Responses early in slow start: {:?}", early);
+    assert!(early.get("returning").copied().unwrap_or(0) < 11);
+
+    sleep(Duration::from_secs(15)).await;
+    let late = send_requests(&balancebeam, 40).await;
+    log::info!("Responses after slow start: {:?}", late);
+    assert!(late.get("returning").copied().unwrap_or(0) > 10);
+
+    Box::new(steady).stop().await;
+    Box::new(returning).stop().await;
+    log::info!("All done :)");
+}
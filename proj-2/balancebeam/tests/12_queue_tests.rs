@@ -0,0 +1,139 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, NamedServer, Server};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Starts balancebeam in front of an upstream that takes a second to answer each request, allowing
+/// only one connection to it at a time
+async fn setup(queue_size: &str, queue_timeout_ms: &str) -> (BalanceBeam, NamedServer, String) {
+    init_logging();
+    let upstream = NamedServer::new_slow("slow", Duration::from_secs(1)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-max-connections",
+            "1",
+            "--upstream-queue-size",
+            queue_size,
+            "--upstream-queue-timeout-ms",
+            queue_timeout_ms,
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    (balancebeam, upstream, admin_address)
+}
+
+/// Sends a request over a connection of its own (which is closed afterwards, freeing up the
+/// upstream connection slot)
+async fn get_status(address: String) -> u16 {
+    reqwest::get(format!("http://{}/", address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// A connection that can't get a slot within the queue timeout gets a 503
+#[tokio::test]
+async fn test_queue_timeout() {
+    let (balancebeam, upstream, _admin) = setup("10", "300").await;
+
+    let first = tokio::spawn(get_status(balancebeam.address.clone()));
+    sleep(Duration::from_millis(200)).await;
+    let started = Instant::now();
+    assert_eq!(get_status(balancebeam.address.clone()).await, 503);
+    assert!(started.elapsed() < Duration::from_millis(900));
+    assert_eq!(first.await.unwrap(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A connection that gets a slot before the queue timeout runs out is served normally
+#[tokio::test]
+async fn test_queued_request_is_served() {
+    let (balancebeam, upstream, _admin) = setup("10", "5000").await;
+
+    let first = tokio::spawn(get_status(balancebeam.address.clone()));
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(get_status(balancebeam.address.clone()).await, 200);
+    assert_eq!(first.await.unwrap(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A keep-alive client that has gone quiet shouldn't hold on to the only upstream connection slot
+/// while other clients wait for it, and should still be served when it speaks up again
+#[tokio::test]
+async fn test_idle_client_gives_up_slot() {
+    let (balancebeam, upstream, _admin) = setup("10", "300").await;
+
+    let keep_alive = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+    let response = keep_alive.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    response.text().await.unwrap();
+
+    log::info!("Sending a request from another client while the first one is idle");
+    assert_eq!(get_status(balancebeam.address.clone()).await, 200);
+
+    log::info!("The idle client should get a new upstream connection");
+    let response = keep_alive.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Once the queue is full, further connections are turned away at once. The admin interface
+/// should show the queue.
+#[tokio::test]
+async fn test_queue_full_and_metrics() {
+    let (balancebeam, upstream, admin_address) = setup("1", "5000").await;
+
+    let first = tokio::spawn(get_status(balancebeam.address.clone()));
+    sleep(Duration::from_millis(200)).await;
+    let second = tokio::spawn(get_status(balancebeam.address.clone()));
+    sleep(Duration::from_millis(200)).await;
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    let labels = format!("{{upstream=\"{}\",group=\"default\"}}", upstream.address);
+    assert!(metrics.contains(&format!("balancebeam_upstream_queue_depth{} 1", labels)));
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_active_connections{} 1",
+        labels
+    )));
+
+    let started = Instant::now();
+    assert_eq!(get_status(balancebeam.address.clone()).await, 503);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert_eq!(first.await.unwrap(), 200);
+    assert_eq!(second.await.unwrap(), 200);
+
+    let metrics = reqwest::get(format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_queue_rejections_total{} 1",
+        labels
+    )));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use common::{
+    free_local_address, init_logging, spans_in_export, BalanceBeam, Collector, EchoServer, Server,
+    TempFile,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const CLIENT_SPAN_ID: &str = "00f067aa0ba902b7";
+
+/// Returns the traceparent header the upstream received, as echoed back by an EchoServer
+fn forwarded_traceparent(echoed_request: &str) -> String {
+    echoed_request
+        .lines()
+        .find_map(|line| line.strip_prefix("traceparent: "))
+        .expect("The upstream didn't receive a traceparent header")
+        .to_string()
+}
+
+fn span_named<'a>(spans: &'a [serde_json::Value], name: &str) -> &'a serde_json::Value {
+    spans
+        .iter()
+        .find(|span| span["name"] == name)
+        .unwrap_or_else(|| panic!("No {} span in {:?}", name, spans))
+}
+
+fn attribute<'a>(span: &'a serde_json::Value, key: &str) -> &'a serde_json::Value {
+    &span["attributes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|attribute| attribute["key"] == key)
+        .unwrap_or_else(|| panic!("Span {:?} has no {} attribute", span, key))["value"]
+}
+
+async fn get_with_traceparent(balancebeam: &BalanceBeam, traceparent: &str) -> String {
+    reqwest::Client::new()
+        .get(format!("http://{}/hello", balancebeam.address))
+        .header("traceparent", traceparent)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// An incoming trace should be continued: the upstream sees the same trace, with balancebeam's
+/// span as the parent, and the collector gets balancebeam's spans
+#[tokio::test]
+async fn test_propagates_and_exports_to_collector() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let collector = Collector::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trace-endpoint",
+            &collector.endpoint(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let echoed = get_with_traceparent(
+        &balancebeam,
+        &format!("00-{}-{}-01", TRACE_ID, CLIENT_SPAN_ID),
+    )
+    .await;
+    let traceparent = forwarded_traceparent(&echoed);
+    log::info!("Upstream received traceparent {}", traceparent);
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts.len(), 4);
+    assert_eq!(parts[0], "00");
+    assert_eq!(parts[1], TRACE_ID);
+    assert_ne!(parts[2], CLIENT_SPAN_ID);
+    assert_eq!(parts[3], "01");
+
+    let spans = collector.wait_for_spans(3).await;
+    log::info!("Exported spans: {:?}", spans);
+    let accept = span_named(&spans, "accept");
+    let connect = span_named(&spans, "upstream connect");
+    let response = span_named(&spans, "upstream response");
+    for span in [accept, connect, response] {
+        assert_eq!(span["traceId"], TRACE_ID);
+    }
+    assert_eq!(accept["parentSpanId"], CLIENT_SPAN_ID);
+    assert_eq!(accept["kind"], 2);
+    assert_eq!(connect["parentSpanId"], accept["spanId"]);
+    assert_eq!(response["parentSpanId"], accept["spanId"]);
+    assert_eq!(response["spanId"], parts[2]);
+    assert_eq!(attribute(accept, "url.path")["stringValue"], "/hello");
+    assert_eq!(
+        attribute(accept, "http.response.status_code")["intValue"],
+        "200"
+    );
+    assert_eq!(
+        attribute(response, "server.address")["stringValue"],
+        upstream.address.as_str()
+    );
+    let start: u128 = accept["startTimeUnixNano"]
+        .as_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let end: u128 = accept["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+    assert!(start <= end);
+
+    collector.stop().await;
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Requests without a (valid) traceparent header start a new trace. Spans can be written to a
+/// file instead of a collector.
+#[tokio::test]
+async fn test_starts_trace_and_exports_to_file() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let trace_file = TempFile::new("");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trace-file",
+            trace_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let echoed = get_with_traceparent(&balancebeam, "not a traceparent").await;
+    let traceparent = forwarded_traceparent(&echoed);
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts.len(), 4);
+    assert_eq!(parts[1].len(), 32);
+    assert_ne!(parts[1], "0".repeat(32));
+    assert_eq!(parts[3], "01");
+
+    let mut spans = Vec::new();
+    for _ in 0..50 {
+        let contents = std::fs::read_to_string(&trace_file.path).unwrap();
+        spans = contents
+            .lines()
+            .flat_map(|line| spans_in_export(&serde_json::from_str(line).unwrap()))
+            .collect();
+        if spans.len() >= 3 {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(spans.len(), 3, "Unexpected spans in file: {:?}", spans);
+    let accept = span_named(&spans, "accept");
+    assert_eq!(accept["traceId"], parts[1]);
+    assert!(accept.get("parentSpanId").is_none());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Traces the client doesn't sample are passed on, but not exported
+#[tokio::test]
+async fn test_unsampled_trace_not_exported() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let trace_file = TempFile::new("");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trace-file",
+            trace_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let echoed = get_with_traceparent(
+        &balancebeam,
+        &format!("00-{}-{}-00", TRACE_ID, CLIENT_SPAN_ID),
+    )
+    .await;
+    let traceparent = forwarded_traceparent(&echoed);
+    assert!(traceparent.starts_with(&format!("00-{}-", TRACE_ID)));
+    assert!(traceparent.ends_with("-00"));
+
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(std::fs::read_to_string(&trace_file.path).unwrap(), "");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// When the upstream can't be reached, the spans should say so
+#[tokio::test]
+async fn test_failed_request_span() {
+    init_logging();
+    let collector = Collector::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&free_local_address()],
+        &[
+            "--trace-endpoint",
+            &collector.endpoint(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    let spans = collector.wait_for_spans(2).await;
+    let accept = span_named(&spans, "accept");
+    let connect = span_named(&spans, "upstream connect");
+    assert_eq!(accept["status"]["code"], 2);
+    assert_eq!(
+        attribute(accept, "http.response.status_code")["intValue"],
+        "502"
+    );
+    assert_eq!(connect["status"]["code"], 2);
+
+    collector.stop().await;
+    log::info!("All done :)");
+}
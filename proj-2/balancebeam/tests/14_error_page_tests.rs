@@ -0,0 +1,135 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, Server, TempFile};
+
+/// Requests to /denied and /api/denied are refused (403), which gives us an error page to look at
+async fn setup(error_pages: &str) -> (BalanceBeam, EchoServer, TempFile) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_file = TempFile::new(&format!(
+        r#"{{
+            "error_pages": {},
+            "routes": [
+                {{"prefix": "/denied", "access": {{"deny": ["127.0.0.1"]}}}},
+                {{"prefix": "/api", "error_format": "json"}},
+                {{"prefix": "/api/denied", "error_format": "json", "access": {{"deny": ["127.0.0.1"]}}}}
+            ]
+        }}"#,
+        error_pages
+    ));
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--config", config_file.path_str()])
+            .await;
+    (balancebeam, upstream, config_file)
+}
+
+/// Returns the response's status, Content-Type and body
+async fn get(balancebeam: &BalanceBeam, path: &str, accept: Option<&str>) -> (u16, String, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(accept) = accept {
+        request = request.header("Accept", accept);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    let content_type = response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    (status, content_type, response.text().await.unwrap())
+}
+
+fn is_request_id(id: &str) -> bool {
+    id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Without any error page settings, errors are plain text, with a request ID to find them by in
+/// the logs
+#[tokio::test]
+async fn test_plain_text_errors() {
+    let (balancebeam, upstream, _config) = setup("{}").await;
+
+    let (status, content_type, body) = get(&balancebeam, "/denied", None).await;
+    assert_eq!(status, 403);
+    assert_eq!(content_type, "text/plain");
+    let mut lines = body.lines();
+    assert_eq!(lines.next(), Some("HTTP 403 Forbidden"));
+    let request_id = lines.next().unwrap().strip_prefix("Request ID: ").unwrap();
+    assert!(is_request_id(request_id), "Bad request ID {}", request_id);
+
+    // Every request gets its own ID
+    let (_, _, other_body) = get(&balancebeam, "/denied", None).await;
+    assert_ne!(body, other_body);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// API routes answer with JSON, unless the client asks for something else
+#[tokio::test]
+async fn test_json_errors_for_api_routes() {
+    let (balancebeam, upstream, _config) = setup("{}").await;
+
+    let (status, content_type, body) = get(&balancebeam, "/api/denied", None).await;
+    assert_eq!(status, 403);
+    assert_eq!(content_type, "application/json");
+    let error: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["error"]["status"], 403);
+    assert_eq!(error["error"]["message"], "Forbidden");
+    assert!(is_request_id(
+        error["error"]["request_id"].as_str().unwrap()
+    ));
+
+    let (_, content_type, _) = get(&balancebeam, "/api/denied", Some("text/html")).await;
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    let (_, content_type, _) = get(&balancebeam, "/api/denied", Some("*/*")).await;
+    assert_eq!(content_type, "application/json");
+
+    log::info!("Clients can ask for JSON anywhere");
+    let (_, content_type, _) = get(
+        &balancebeam,
+        "/denied",
+        Some("text/html;q=0.5, application/json"),
+    )
+    .await;
+    assert_eq!(content_type, "application/json");
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// HTML error pages come from the templates for their status, or their class of status
+#[tokio::test]
+async fn test_html_templates() {
+    init_logging();
+    let forbidden = TempFile::new("<p>Nope ({{status}} {{reason}}), request {{request_id}}</p>");
+    let server_error = TempFile::new("<p>Our fault: {{status}}</p>");
+    let (balancebeam, upstream, config) = setup(&format!(
+        r#"{{"format": "html", "html": {{"403": "{}", "5xx": "{}"}}}}"#,
+        forbidden.path_str(),
+        server_error.path_str()
+    ))
+    .await;
+
+    let (status, content_type, body) = get(&balancebeam, "/denied", None).await;
+    assert_eq!(status, 403);
+    assert_eq!(content_type, "text/html; charset=utf-8");
+    let request_id = body
+        .strip_prefix("<p>Nope (403 Forbidden), request ")
+        .and_then(|rest| rest.strip_suffix("</p>"))
+        .unwrap_or_else(|| panic!("Unexpected page {}", body));
+    assert!(is_request_id(request_id));
+
+    log::info!("Checking that other errors use their class's template");
+    let unreachable =
+        BalanceBeam::new_with_args(&[&free_local_address()], &["--config", config.path_str()])
+            .await;
+    let (status, _, body) = get(&unreachable, "/", None).await;
+    assert_eq!(status, 502);
+    assert_eq!(body, "<p>Our fault: 502</p>");
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, RespServer, Server};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+async fn start_balancebeam(upstream: &EchoServer, limit: &str, store: &str) -> BalanceBeam {
+    BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            limit,
+            "--rate-limit-store",
+            store,
+        ],
+    )
+    .await
+}
+
+async fn get_status(balancebeam: &BalanceBeam) -> u16 {
+    reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+}
+
+/// Instances sharing a store should enforce one limit between them
+#[tokio::test]
+async fn test_limit_shared_between_instances() {
+    init_logging();
+    let store = RespServer::new().await;
+    let upstream = EchoServer::new().await;
+    let first = start_balancebeam(&upstream, "4", &store.address).await;
+    let second = start_balancebeam(&upstream, "4", &format!("redis://{}", store.address)).await;
+
+    let started_in = current_minute();
+    let mut allowed = 0;
+    for _ in 0..3 {
+        for balancebeam in [&first, &second] {
+            if get_status(balancebeam).await == 200 {
+                allowed += 1;
+            }
+        }
+    }
+    log::info!("{} of 6 requests were let through", allowed);
+    if current_minute() == started_in {
+        assert_eq!(allowed, 4);
+    } else {
+        // A little of the previous window's count is forgotten as the new window starts
+        assert!((4..=5).contains(&allowed));
+    }
+    assert_eq!(store.total("127.0.0.1"), allowed);
+
+    assert_eq!(Box::new(upstream).stop().await, allowed as usize);
+    log::info!("All done :)");
+}
+
+/// Without a store to share counts through, each instance limits clients on its own
+#[tokio::test]
+async fn test_falls_back_to_local_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let first = start_balancebeam(&upstream, "3", &free_local_address()).await;
+    let second = start_balancebeam(&upstream, "3", &free_local_address()).await;
+
+    for balancebeam in [&first, &second] {
+        for _ in 0..3 {
+            assert_eq!(get_status(balancebeam).await, 200);
+        }
+        assert_eq!(get_status(balancebeam).await, 429);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// If the store goes away, limiting carries on locally
+#[tokio::test]
+async fn test_store_goes_away() {
+    init_logging();
+    let store = RespServer::new().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&upstream, "2", &store.address).await;
+
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(store.total("127.0.0.1"), 1);
+
+    log::info!("Stopping the store");
+    store.stop();
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(get_status(&balancebeam).await, 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,230 @@
+mod common;
+
+use common::{
+    free_local_address, init_logging, BalanceBeam, EchoServer, NamedServer, Server, TempFile,
+};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends requests (each over a connection of its own) until stopped, counting how many succeed
+/// and how many fail
+struct Load {
+    task: tokio::task::JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+    succeeded: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl Load {
+    fn start(address: &str) -> Load {
+        let url = format!("http://{}/", address);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let succeeded = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let (task_stopped, task_succeeded, task_failed) =
+            (stopped.clone(), succeeded.clone(), failed.clone());
+        let task = tokio::spawn(async move {
+            while !task_stopped.load(Ordering::SeqCst) {
+                match reqwest::get(&url).await {
+                    Ok(response) if response.status().is_success() => {
+                        task_succeeded.fetch_add(1, Ordering::SeqCst)
+                    }
+                    Ok(response) => {
+                        log::warn!("Request failed: {}", response.status());
+                        task_failed.fetch_add(1, Ordering::SeqCst)
+                    }
+                    Err(err) => {
+                        log::warn!("Request failed: {}", err);
+                        task_failed.fetch_add(1, Ordering::SeqCst)
+                    }
+                };
+            }
+        });
+        Load {
+            task,
+            stopped,
+            succeeded,
+            failed,
+        }
+    }
+
+    /// Stops sending requests, and returns how many succeeded and how many failed
+    async fn stop(self) -> (usize, usize) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.task.await.unwrap();
+        (
+            self.succeeded.load(Ordering::SeqCst),
+            self.failed.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// A balancebeam process we didn't start ourselves (because it was started by an upgrade), which
+/// is killed when dropped
+struct Replacement(i32);
+
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        let _ = kill(Pid::from_raw(self.0), Signal::SIGKILL);
+    }
+}
+
+fn read_pid(pid_file: &TempFile) -> i32 {
+    std::fs::read_to_string(&pid_file.path)
+        .expect("Could not read pid file")
+        .trim()
+        .parse()
+        .expect("pid file doesn't hold a process ID")
+}
+
+/// On SIGUSR2, a new process should take over the listening socket and the old one should exit,
+/// without a single request being refused along the way
+#[tokio::test]
+async fn test_upgrade_without_dropping_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let pid_file = TempFile::new("");
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--pid-file",
+            pid_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let old_pid = balancebeam.pid().unwrap() as i32;
+    assert_eq!(read_pid(&pid_file), old_pid);
+
+    let load = Load::start(&balancebeam.address);
+    sleep(Duration::from_millis(500)).await;
+    log::info!("Sending SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(10))
+        .await
+        .expect("The old process didn't exit");
+    assert!(status.success());
+    let new_pid = read_pid(&pid_file);
+    assert_ne!(new_pid, old_pid);
+    let _replacement = Replacement(new_pid);
+
+    // The new process should carry on serving
+    sleep(Duration::from_millis(500)).await;
+    let (succeeded, failed) = load.stop().await;
+    log::info!("{} requests succeeded, {} failed", succeeded, failed);
+    assert_eq!(failed, 0);
+    assert!(succeeded > 0);
+    let response_text = balancebeam
+        .get("/after-upgrade")
+        .await
+        .expect("Error sending request to the new process");
+    assert!(response_text.contains("GET /after-upgrade HTTP/1.1"));
+
+    log::info!("All done :)");
+}
+
+/// Requests that are in progress when the old process stops accepting connections should still be
+/// answered, and the old process should wait for them before exiting
+#[tokio::test]
+async fn test_upgrade_finishes_requests_in_progress() {
+    init_logging();
+    let upstream = NamedServer::new_slow("slow", Duration::from_secs(2)).await;
+    let pid_file = TempFile::new("");
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--pid-file",
+            pid_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let old_pid = balancebeam.pid().unwrap() as i32;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let in_progress = tokio::spawn(async move { reqwest::get(url).await?.text().await });
+    sleep(Duration::from_millis(500)).await;
+    balancebeam.send_signal(Signal::SIGUSR2);
+
+    // The request should be answered, and the old process exit once it is
+    assert_eq!(in_progress.await.unwrap().unwrap(), "slow");
+    balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("The old process didn't exit");
+    let new_pid = read_pid(&pid_file);
+    assert_ne!(new_pid, old_pid);
+    let _replacement = Replacement(new_pid);
+    assert_eq!(balancebeam.get("/").await.unwrap(), "slow");
+
+    log::info!("All done :)");
+}
+
+/// With --reuse-port, a second process can listen alongside the first, which then exits on SIGQUIT
+/// without any requests being refused
+#[tokio::test]
+async fn test_reuse_port_and_quit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = free_local_address();
+    let args = ["--reuse-port", "--active-health-check-interval", "60"];
+    let mut first = BalanceBeam::new_at_address(address.clone(), &[&upstream.address], &args).await;
+    let second = BalanceBeam::new_at_address(address.clone(), &[&upstream.address], &args).await;
+
+    let load = Load::start(&address);
+    sleep(Duration::from_millis(500)).await;
+    log::info!("Sending SIGQUIT to the first process");
+    first.send_signal(Signal::SIGQUIT);
+    let status = first
+        .wait_for_exit(Duration::from_secs(10))
+        .await
+        .expect("The first process didn't exit");
+    assert!(status.success());
+
+    sleep(Duration::from_millis(500)).await;
+    let (succeeded, failed) = load.stop().await;
+    log::info!("{} requests succeeded, {} failed", succeeded, failed);
+    assert_eq!(failed, 0);
+    assert!(second.get("/").await.is_ok());
+
+    assert_eq!(Box::new(upstream).stop().await, succeeded + 1);
+    log::info!("All done :)");
+}
+
+/// Only sockets are taken over from the environment, so that a stale variable doesn't make
+/// balancebeam mistake some other file it was started with for a listener
+#[tokio::test]
+async fn test_inherited_listener_must_be_socket() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let address = free_local_address();
+    let not_a_socket = TempFile::new("not a socket");
+    let mut child = tokio::process::Command::new(BalanceBeam::target_bin_path())
+        .args(["--bind", &address, "--upstream", &upstream.address])
+        .args(["--active-health-check-interval", "60"])
+        // Claim that stdin, a regular file, is the listening socket for the address
+        .env("BALANCEBEAM_LISTENER_FDS", format!("{}=0", address))
+        .stdin(std::fs::File::open(&not_a_socket.path).unwrap())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Could not execute balancebeam binary");
+    sleep(Duration::from_secs(1)).await;
+
+    let response = reqwest::get(format!("http://{}/", address))
+        .await
+        .expect("balancebeam should have bound the address itself");
+    assert_eq!(response.status().as_u16(), 200);
+
+    child.kill().await.unwrap();
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
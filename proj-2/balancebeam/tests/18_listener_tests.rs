@@ -0,0 +1,124 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, Server, TempFile};
+
+/// Starts balancebeam listening on its usual address and on a second one named "internal".
+/// Returns the internal listener's address along with everything else.
+async fn setup_with_config(config: &str) -> (BalanceBeam, String, EchoServer, TempFile) {
+    init_logging();
+    let config_file = TempFile::new(config);
+    let upstream = EchoServer::new().await;
+    let internal_address = free_local_address();
+    let internal_bind = format!("internal={}", internal_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--config", config_file.path_str(), "--bind", &internal_bind],
+    )
+    .await;
+    (balancebeam, internal_address, upstream, config_file)
+}
+
+async fn post_status(address: &str, path: &str, body_size: usize) -> u16 {
+    reqwest::Client::new()
+        .post(format!("http://{}{}", address, path))
+        .body("x".repeat(body_size))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Each listener should use its own routes (or the global ones, if it has none) and its own limits
+#[tokio::test]
+async fn test_listeners_have_own_routes_and_limits() {
+    let (balancebeam, internal_address, upstream, _config) = setup_with_config(
+        r#"{
+            "routes": [{"prefix": "/admin", "access": {"deny": ["0.0.0.0/0"]}}],
+            "listeners": {
+                "internal": {
+                    "routes": [{"prefix": "/upload", "limits": {"request": {"max_body_size": 5000}}}],
+                    "limits": {"request": {"max_body_size": 100}}
+                }
+            }
+        }"#,
+    )
+    .await;
+    let public_address = balancebeam.address.clone();
+
+    log::info!("The global routes apply to the listener without settings of its own");
+    assert_eq!(post_status(&public_address, "/admin", 0).await, 403);
+    assert_eq!(post_status(&public_address, "/other", 1000).await, 200);
+
+    log::info!("The internal listener has its own routes and limits instead");
+    assert_eq!(post_status(&internal_address, "/admin", 0).await, 200);
+    assert_eq!(post_status(&internal_address, "/other", 1000).await, 413);
+    assert_eq!(post_status(&internal_address, "/upload", 1000).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A listener's access list should refuse connections to it without affecting the other listeners
+#[tokio::test]
+async fn test_listener_access_list() {
+    let (balancebeam, internal_address, upstream, _config) =
+        setup_with_config(r#"{"listeners": {"internal": {"access": {"allow": ["10.0.0.0/8"]}}}}"#)
+            .await;
+
+    assert!(balancebeam.get("/public").await.is_ok());
+    assert!(
+        reqwest::get(format!("http://{}/internal", internal_address))
+            .await
+            .is_err(),
+        "The internal listener should have refused the connection"
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A listener with TLS settings should serve HTTPS with its certificate, while the other listeners
+/// stay plain HTTP
+#[tokio::test]
+async fn test_listener_tls() {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = TempFile::new(&certified.cert.pem());
+    let key = TempFile::new(&certified.key_pair.serialize_pem());
+    let (balancebeam, internal_address, upstream, _config) = setup_with_config(&format!(
+        r#"{{"listeners": {{"internal": {{"tls": {{"cert": "{}", "key": "{}"}}}}}}}}"#,
+        cert.path_str(),
+        key.path_str()
+    ))
+    .await;
+    let client = reqwest::Client::builder()
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(certified.cert.pem().as_bytes()).unwrap(),
+        )
+        .build()
+        .unwrap();
+
+    log::info!("Requests over TLS, on one connection");
+    for path in ["/first", "/second"] {
+        let response = client
+            .get(format!("https://{}{}", internal_address, path))
+            .send()
+            .await
+            .expect("Error sending request over TLS");
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Plain HTTP isn't accepted on the TLS listener, and is still spoken on the other");
+    assert!(reqwest::get(format!("http://{}/plain", internal_address))
+        .await
+        .is_err());
+    assert!(balancebeam.get("/public").await.is_ok());
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use common::{init_logging, read_response, BalanceBeam, EchoServer, Server, TempFile};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::sleep;
+
+/// Returns a path in the temp directory to put a Unix domain socket at
+fn socket_path() -> String {
+    let name = format!(
+        "balancebeam-test-{}.sock",
+        rand::thread_rng().gen_range(0..u64::MAX)
+    );
+    std::env::temp_dir()
+        .join(name)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Sends the raw bytes over a Unix domain socket connection, and returns the response, or None if
+/// balancebeam turned the connection away (hanging up without a response)
+async fn send_unix(path: &str, raw: &[u8]) -> Option<String> {
+    let mut conn = UnixStream::connect(path)
+        .await
+        .expect("Could not connect to balancebeam's socket");
+    // If balancebeam has already hung up, this fails, and so does reading
+    let _ = conn.write_all(raw).await;
+    let mut conn = BufReader::new(conn);
+    if conn
+        .fill_buf()
+        .await
+        .map_or(true, |buffer| buffer.is_empty())
+    {
+        return None;
+    }
+    Some(read_response(&mut conn).await)
+}
+
+/// Requests should be proxied to (and health checks sent to) upstreams listening on Unix domain
+/// sockets
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    let path = socket_path();
+    let upstream = EchoServer::new_unix(&path).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/over-unix")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /over-unix HTTP/1.1"));
+
+    log::info!("Waiting for health checks");
+    sleep(Duration::from_secs(2)).await;
+    let response_text = balancebeam
+        .get("/after-health-checks")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after-health-checks HTTP/1.1"));
+
+    // Two proxied requests, and at least one health check
+    assert!(Box::new(upstream).stop().await >= 3);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// balancebeam should accept connections on a Unix domain socket, taking over a socket file left
+/// behind by a process that is no longer listening on it
+#[tokio::test]
+async fn test_unix_socket_listener() {
+    init_logging();
+    let path = socket_path();
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(std::path::Path::new(&path).exists());
+
+    let upstream = EchoServer::new().await;
+    let bind = format!("unix:{}", path);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--bind", &bind]).await;
+
+    let mut conn = UnixStream::connect(&path)
+        .await
+        .expect("Could not connect to balancebeam's socket");
+    conn.write_all(b"GET /from-unix HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_response(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("GET /from-unix HTTP/1.1"));
+    // The client has no IP address, and isn't passed off as 127.0.0.1
+    assert!(response.contains("x-forwarded-for: unix"));
+
+    // The TCP listener works as before
+    assert!(balancebeam.get("/from-tcp").await.is_ok());
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Clients on a Unix domain socket aren't in any network, so access lists have to name them
+/// ("unix") to let them in
+#[tokio::test]
+async fn test_unix_clients_need_naming() {
+    init_logging();
+    let (loopback_path, named_path) = (socket_path(), socket_path());
+    let config = TempFile::new(
+        r#"{"listeners": {
+            "loopback": {"access": {"allow": ["127.0.0.0/8", "::1"]}},
+            "named": {"access": {"allow": ["unix"]}}
+        }}"#,
+    );
+    let upstream = EchoServer::new().await;
+    let (loopback_bind, named_bind) = (
+        format!("loopback=unix:{}", loopback_path),
+        format!("named=unix:{}", named_path),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            config.path_str(),
+            "--bind",
+            &loopback_bind,
+            "--bind",
+            &named_bind,
+        ],
+    )
+    .await;
+    let request = b"GET /local HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    log::info!("Allowing loopback addresses doesn't let Unix domain socket clients in");
+    assert_eq!(send_unix(&loopback_path, request).await, None);
+
+    log::info!("Naming them does");
+    let response = send_unix(&named_path, request).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    log::info!("The TCP listener works as before");
+    assert!(balancebeam.get("/from-tcp").await.is_ok());
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    let _ = std::fs::remove_file(&loopback_path);
+    let _ = std::fs::remove_file(&named_path);
+    log::info!("All done :)");
+}
+
+/// A proxy connecting over a Unix domain socket is only trusted to send PROXY protocol headers if
+/// --trusted-proxy names "unix", not because it's on this machine
+#[tokio::test]
+async fn test_unix_trusted_proxy() {
+    init_logging();
+    let request = b"PROXY TCP4 203.0.113.7 127.0.0.1 51000 80\r\n\
+        GET /proxied HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let upstream = EchoServer::new().await;
+
+    for (trusted_proxy, trusted) in [("127.0.0.0/8", false), ("unix", true)] {
+        let path = socket_path();
+        let bind = format!("unix:{}", path);
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&upstream.address],
+            &[
+                "--bind",
+                &bind,
+                "--accept-proxy-protocol",
+                "--trusted-proxy",
+                trusted_proxy,
+            ],
+        )
+        .await;
+        let response = send_unix(&path, request).await.unwrap();
+        log::info!(
+            "Response with --trusted-proxy {}: {}",
+            trusted_proxy,
+            response
+        );
+        if trusted {
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.contains("x-forwarded-for: 203.0.113.7"));
+        } else {
+            // The PROXY line is taken for the start of a (malformed) request
+            assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        }
+        drop(balancebeam);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use common::{init_logging, read_response, BalanceBeam, EchoServer, Server, TempFile};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Sends the headers of a POST request with "Expect: <expectation>" (holding the body back) and
+/// returns the connection
+async fn send_expecting(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    expectation: &str,
+    body_size: usize,
+) -> TcpStream {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let headers = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: {}\r\n\r\n",
+        path, body_size, expectation
+    );
+    conn.write_all(headers.as_bytes()).await.unwrap();
+    conn
+}
+
+/// Reads a response from balancebeam without sending it anything else, failing if it takes too
+/// long (i.e. balancebeam is waiting for a body we haven't sent)
+async fn read_response_without_body(conn: &mut TcpStream) -> String {
+    timeout(Duration::from_secs(5), read_response(conn))
+        .await
+        .expect("balancebeam didn't answer before the body was sent")
+}
+
+/// A client that waits for 100 Continue should get it, and its request should reach the upstream
+/// (without the Expect header, since we already have the whole body)
+#[tokio::test]
+async fn test_continue_then_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = send_expecting(&balancebeam, "/upload", "100-continue", 11).await;
+    let mut interim = [0_u8; CONTINUE.len()];
+    timeout(Duration::from_secs(5), conn.read_exact(&mut interim))
+        .await
+        .expect("balancebeam didn't send 100 Continue")
+        .unwrap();
+    assert_eq!(&interim, CONTINUE);
+
+    conn.write_all(b"hello world").await.unwrap();
+    let response = read_response(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("POST /upload HTTP/1.1"));
+    assert!(response.ends_with("hello world"));
+    assert!(!response.to_lowercase().contains("expect:"));
+
+    // The connection carries on as usual
+    conn.write_all(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(read_response(&mut conn)
+        .await
+        .contains("GET /next HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Requests that will be turned down anyway should be, before the client sends the body. The
+/// client is told the connection is closing, since it might send the body after all.
+#[tokio::test]
+async fn test_early_rejection() {
+    init_logging();
+    let config_file = TempFile::new(r#"{"limits": {"request": {"max_body_size": 100}}}"#);
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            config_file.path_str(),
+            "--max-requests-per-minute",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Too big a body");
+    let mut conn = send_expecting(&balancebeam, "/upload", "100-continue", 1000).await;
+    let response = read_response_without_body(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 413"));
+
+    log::info!("Within the body limit, but over the rate limit");
+    assert!(balancebeam.get("/first").await.is_ok());
+    let mut conn = send_expecting(&balancebeam, "/upload", "100-continue", 10).await;
+    let response = read_response_without_body(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 429"));
+    assert!(response.to_lowercase().contains("connection: close"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Expectations other than 100-continue can't be met
+#[tokio::test]
+async fn test_unsupported_expectation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = send_expecting(&balancebeam, "/upload", "something-else", 10).await;
+    let response = read_response_without_body(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 417"));
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::{init_logging, read_response, send_raw_request, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Requests that could be framed more than one way, each followed by what would be a second
+/// request if the upstream read the first one differently than we did
+const AMBIGUOUS_REQUESTS: &[(&str, &str, u16)] = &[
+    (
+        "CL.TE",
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        400,
+    ),
+    (
+        "TE.CL",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n5c\r\nGET /smuggled HTTP/1.1\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "differing Content-Lengths",
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\nContent-Length: 27\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        400,
+    ),
+    (
+        "repeated Content-Length",
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\nContent-Length: 0\r\n\r\n",
+        400,
+    ),
+    (
+        "Content-Length list",
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5, 5\r\n\r\nhello",
+        400,
+    ),
+    (
+        "signed Content-Length",
+        "POST / HTTP/1.1\r\nHost: x\r\nContent-Length: +5\r\n\r\nhello",
+        400,
+    ),
+    (
+        "unknown transfer coding",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: xchunked\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "chunked not last",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, identity\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "chunked not last, over two headers",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "chunked twice",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked, chunked\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "coding we don't decode",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+        501,
+    ),
+    (
+        "space before colon",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding : chunked\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "obs-fold",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding:\r\n chunked\r\n\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "bare LF in chunk-size line",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5\nhello\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "LF in chunk extension",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n5;a\nb\r\nhello\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "chunk longer than its size",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhello\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "chunk size with prefix",
+        "POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0x5\r\nhello\r\n0\r\n\r\n",
+        400,
+    ),
+    (
+        "duplicate Host",
+        "GET / HTTP/1.1\r\nHost: x\r\nHost: y\r\n\r\n",
+        400,
+    ),
+];
+
+/// None of the known smuggling payloads should make it to the upstream, in any form
+#[tokio::test]
+async fn test_ambiguous_requests_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    for (name, raw_request, expected_status) in AMBIGUOUS_REQUESTS {
+        log::info!("Sending {}", name);
+        let response = send_raw_request(&balancebeam.address, raw_request.as_bytes()).await;
+        let expected = format!("HTTP/1.1 {} ", expected_status);
+        assert!(
+            response.starts_with(&expected),
+            "Expected {} for {}, got: {}",
+            expected_status,
+            name,
+            response
+        );
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// After a request we couldn't read, we can't tell where the next one starts, so the connection
+/// should be closed instead of reading on
+#[tokio::test]
+async fn test_connection_closed_after_bad_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1O\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let response = read_response(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 400"));
+    assert!(response.to_lowercase().contains("connection: close"));
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty(), "Got more than one response");
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// A chunked request should be forwarded with a Content-Length instead, and requests pipelined
+/// behind it (or behind one with a Content-Length) should each be forwarded as requests of their
+/// own, not as part of the one before
+#[tokio::test]
+async fn test_forwarded_framing_is_normalized() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        b"POST /chunked HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: t\r\n\r\n\
+        POST /length HTTP/1.1\r\nHost: x\r\nContent-Length: 000\r\n\r\n\
+        GET /pipelined HTTP/1.1\r\nHost: x\r\n\r\n",
+    )
+    .await
+    .unwrap();
+
+    let response = read_response(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("POST /chunked HTTP/1.1"));
+    assert!(response.contains("content-length: 11"));
+    assert!(!response.contains("transfer-encoding"));
+    assert!(!response.contains("x-trailer"));
+    assert!(response.ends_with("hello world"));
+
+    let response = read_response(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.contains("POST /length HTTP/1.1"));
+    assert!(response.contains("content-length: 0\n"));
+    assert!(!response.contains("GET /pipelined"));
+
+    let response = read_response(&mut conn).await;
+    log::info!("Response: {}", response);
+    assert!(response.contains("GET /pipelined HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
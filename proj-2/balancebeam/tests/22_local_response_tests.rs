@@ -0,0 +1,224 @@
+mod common;
+
+use common::{init_logging, send_raw_request, BalanceBeam, EchoServer, Server, TempDir, TempFile};
+use reqwest::header;
+
+const PAGE: &str = "<!DOCTYPE html><p>Hello from disk</p>";
+
+/// Starts balancebeam with a route serving files from a temp directory at /static, and an inline
+/// response at /healthz
+async fn setup() -> (BalanceBeam, EchoServer, TempDir, TempFile) {
+    init_logging();
+    let root = TempDir::new();
+    root.write("index.html", PAGE.as_bytes());
+    root.write("data/numbers.txt", b"0123456789");
+    root.write("app.js", b"console.log('hi');");
+    let config_file = TempFile::new(&format!(
+        r#"{{"routes": [
+            {{"prefix": "/static", "respond": {{"files": {{"root": "{}"}}}}}},
+            {{"prefix": "/healthz", "respond": {{"inline": {{
+                "body": "{{\"status\": \"ok\"}}",
+                "content_type": "application/json",
+                "headers": {{"Cache-Control": "no-store"}}
+            }}}}}}
+        ]}}"#,
+        root.path_str()
+    ));
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            config_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    (balancebeam, upstream, root, config_file)
+}
+
+/// Inline responses should be sent as configured, and files served with their content type and
+/// validators, without any request reaching the upstream
+#[tokio::test]
+async fn test_local_responses() {
+    let (balancebeam, upstream, _root, _config) = setup().await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    log::info!("Inline response");
+    let response = client.get(url("/healthz")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+    assert_eq!(response.text().await.unwrap(), r#"{"status": "ok"}"#);
+
+    log::info!("Files, and the index file for the directory");
+    let response = client.get(url("/static/app.js")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/javascript");
+    assert!(response.headers().contains_key(header::ETAG));
+    assert!(response.headers().contains_key(header::LAST_MODIFIED));
+    let response = client.get(url("/static/")).send().await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+    assert_eq!(response.text().await.unwrap(), PAGE);
+    let response = client
+        .get(url("/static/data/numbers.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "0123456789");
+
+    log::info!("HEAD gets the headers only");
+    let response = client.head(url("/static/app.js")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "18");
+
+    log::info!("Missing files, escaping the root and other methods");
+    let response = client.get(url("/static/missing.txt")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let response = send_raw_request(
+        &balancebeam.address,
+        b"GET /static/%2e%2e/%2e%2e/etc/passwd HTTP/1.1\r\nHost: x\r\n\r\n",
+    )
+    .await;
+    // The dot segments are resolved before routing, so this is a request for /etc/passwd, which
+    // isn't under the route at all
+    assert!(
+        response.starts_with("HTTP/1.1 200") && response.contains("GET /etc/passwd HTTP/1.1"),
+        "{}",
+        response
+    );
+    let response = client.post(url("/static/app.js")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 405);
+    assert_eq!(response.headers()[header::ALLOW], "GET, HEAD");
+
+    log::info!("Other paths still go to the upstream");
+    let response = client.get(url("/proxied")).send().await.unwrap();
+    assert!(response.text().await.unwrap().contains("GET /proxied"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Cached copies should be revalidated with ETag and Last-Modified
+#[tokio::test]
+async fn test_conditional_requests() {
+    let (balancebeam, upstream, _root, _config) = setup().await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/static/app.js", balancebeam.address);
+
+    let response = client.get(&url).send().await.unwrap();
+    let etag = response.headers()[header::ETAG].clone();
+    let last_modified = response.headers()[header::LAST_MODIFIED].clone();
+
+    let response = client
+        .get(&url)
+        .header(header::IF_NONE_MATCH, &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 304);
+    assert!(response.text().await.unwrap().is_empty());
+    let response = client
+        .get(&url)
+        .header(header::IF_NONE_MATCH, "\"something-else\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .get(&url)
+        .header(header::IF_MODIFIED_SINCE, &last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 304);
+    let response = client
+        .get(&url)
+        .header(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Range requests should get just the bytes they ask for
+#[tokio::test]
+async fn test_range_requests() {
+    let (balancebeam, upstream, _root, _config) = setup().await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/static/data/numbers.txt", balancebeam.address);
+    let get_range = |range: &'static str| client.get(&url).header(header::RANGE, range).send();
+
+    let response = get_range("bytes=2-5").await.unwrap();
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+    assert_eq!(response.text().await.unwrap(), "2345");
+
+    let response = get_range("bytes=7-").await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "789");
+    let response = get_range("bytes=-3").await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "789");
+    let response = get_range("bytes=8-100").await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 8-9/10");
+
+    log::info!("Unsatisfiable, and several ranges (which get the whole file)");
+    let response = get_range("bytes=10-").await.unwrap();
+    assert_eq!(response.status().as_u16(), 416);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    let response = get_range("bytes=0-1,4-5").await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap(), "0123456789");
+
+    log::info!("If-Range with a stale validator gets the whole file");
+    let response = client
+        .get(&url)
+        .header(header::RANGE, "bytes=2-5")
+        .header(header::IF_RANGE, "\"stale\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Files are streamed from disk, so they can be bigger than any message we would read into memory
+/// (the default max_body_size is 10MB)
+#[tokio::test]
+async fn test_large_file() {
+    let (balancebeam, upstream, root, _config) = setup().await;
+    let contents: Vec<u8> = (0..12_000_000_u32).map(|i| (i % 251) as u8).collect();
+    root.write("large.bin", &contents);
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/static/large.bin", balancebeam.address);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "12000000");
+    assert!(response.bytes().await.unwrap() == contents);
+
+    let response = client
+        .get(&url)
+        .header(header::RANGE, "bytes=11000000-11000009")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 206);
+    assert_eq!(
+        response.bytes().await.unwrap(),
+        &contents[11_000_000..11_000_010]
+    );
+
+    log::info!("The connection can be used again after a file");
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap().len(), contents.len());
+
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
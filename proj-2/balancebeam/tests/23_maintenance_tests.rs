@@ -0,0 +1,125 @@
+mod common;
+
+use common::{free_local_address, init_logging, BalanceBeam, EchoServer, Server, TempFile};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn setup_with_config(config: &str) -> (BalanceBeam, EchoServer, TempFile, String) {
+    init_logging();
+    let config_file = TempFile::new(config);
+    let upstream = EchoServer::new().await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            config_file.path_str(),
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    (balancebeam, upstream, config_file, admin_address)
+}
+
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::get(format!("http://{}{}", balancebeam.address, path))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Sends a request to the admin interface's /maintenance endpoint, returning the response body
+async fn admin_maintenance(admin_address: &str, method: reqwest::Method) -> String {
+    reqwest::Client::new()
+        .request(method, format!("http://{}/maintenance", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin interface")
+        .text()
+        .await
+        .unwrap()
+}
+
+/// Maintenance mode should be switched through the admin interface, and only hold back the
+/// configured routes, with the configured page
+#[tokio::test]
+async fn test_maintenance_through_admin_interface() {
+    let template = TempFile::new("<h1>Back soon</h1><p>{{status}} {{request_id}}</p>");
+    let (balancebeam, upstream, _config, admin_address) = setup_with_config(&format!(
+        r#"{{"maintenance": {{"prefixes": ["/app"], "retry_after": 120, "html": "{}"}}}}"#,
+        template.path_str()
+    ))
+    .await;
+
+    assert_eq!(
+        admin_maintenance(&admin_address, reqwest::Method::GET).await,
+        r#"{"enabled":false}"#
+    );
+    assert_eq!(get_status(&balancebeam, "/app/page").await, 200);
+
+    log::info!("Switching maintenance mode on");
+    assert_eq!(
+        admin_maintenance(&admin_address, reqwest::Method::PUT).await,
+        r#"{"enabled":true}"#
+    );
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/app/page", balancebeam.address))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "120");
+    let page = response.text().await.unwrap();
+    assert!(page.starts_with("<h1>Back soon</h1><p>503 "), "{}", page);
+    let response = reqwest::get(format!("http://{}/app", balancebeam.address))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "120");
+    assert!(response.text().await.unwrap().starts_with("HTTP 503"));
+    assert_eq!(get_status(&balancebeam, "/other").await, 200);
+
+    log::info!("Switching maintenance mode off");
+    assert_eq!(
+        admin_maintenance(&admin_address, reqwest::Method::DELETE).await,
+        r#"{"enabled":false}"#
+    );
+    assert_eq!(get_status(&balancebeam, "/app/page").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// SIGUSR1 should toggle maintenance mode, and allowlisted clients should get through to the
+/// upstreams while it is on
+#[tokio::test]
+async fn test_maintenance_signal_and_allowlist() {
+    let (balancebeam, upstream, config, _admin_address) =
+        setup_with_config(r#"{"maintenance": {"allow": ["10.0.0.0/8"]}}"#).await;
+
+    balancebeam.send_signal(Signal::SIGUSR1);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/anything").await, 503);
+
+    log::info!("Allowing localhost through");
+    config.write(r#"{"maintenance": {"allow": ["127.0.0.1"]}}"#);
+    balancebeam.send_signal(Signal::SIGHUP);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/anything").await, 200);
+
+    log::info!("Switching maintenance mode off again");
+    config.write("{}");
+    balancebeam.send_signal(Signal::SIGHUP);
+    balancebeam.send_signal(Signal::SIGUSR1);
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(get_status(&balancebeam, "/anything").await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use tokio::process::Command;
+
+/// Runs `balancebeam bench` with the given arguments, returning its exit status and output
+async fn run_bench(args: &[&str]) -> (bool, String) {
+    let output = Command::new(BalanceBeam::target_bin_path())
+        .arg("bench")
+        .args(args)
+        .output()
+        .await
+        .expect("Could not run balancebeam bench");
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+/// A short benchmark should get requests through without errors, and report the results as JSON
+#[tokio::test]
+async fn test_bench_reports_results() {
+    init_logging();
+    let (success, output) = run_bench(&[
+        "--upstreams",
+        "2",
+        "--connections",
+        "4",
+        "--duration",
+        "1",
+        "--warmup",
+        "0",
+        "--body-size",
+        "100",
+        "--json",
+    ])
+    .await;
+    assert!(success, "{}", output);
+
+    let results: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(results["upstreams"], 2);
+    assert_eq!(results["connections"], 4);
+    assert!(results["requests"].as_u64().unwrap() > 0, "{}", results);
+    assert_eq!(results["errors"], 0, "{}", results);
+    let latency = &results["latency_us"];
+    assert!(latency["p50"].as_u64().unwrap() <= latency["p99"].as_u64().unwrap());
+    assert!(latency["p99"].as_u64().unwrap() <= latency["max"].as_u64().unwrap());
+    log::info!("All done :)");
+}
+
+/// Arguments after -- should be passed on to the balancebeam under test, and the requests it
+/// turns away counted as errors
+#[tokio::test]
+async fn test_bench_passes_proxy_arguments() {
+    init_logging();
+    let (success, output) = run_bench(&[
+        "--duration",
+        "1",
+        "--warmup",
+        "0",
+        "--json",
+        "--",
+        "--max-requests-per-minute",
+        "1",
+    ])
+    .await;
+    assert!(success, "{}", output);
+    let results: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(results["requests"], 1, "{}", results);
+    assert!(results["errors"].as_u64().unwrap() > 0, "{}", results);
+    log::info!("All done :)");
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TempFile};
+
+/// Requests to /denied are refused (403), which gives us an error page to look at
+async fn setup(trust: &str) -> (BalanceBeam, EchoServer, TempFile) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_file = TempFile::new(&format!(
+        r#"{{
+            "request_ids": {{"trust": {}}},
+            "routes": [{{"prefix": "/denied", "access": {{"deny": ["127.0.0.1"]}}}}]
+        }}"#,
+        trust
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--config",
+            config_file.path_str(),
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    (balancebeam, upstream, config_file)
+}
+
+/// Sends a request with the given X-Request-Id (if any), returning the response's X-Request-Id and
+/// body
+async fn get(balancebeam: &BalanceBeam, path: &str, request_id: Option<&str>) -> (String, String) {
+    let mut request = reqwest::Client::new().get(format!("http://{}{}", balancebeam.address, path));
+    if let Some(request_id) = request_id {
+        request = request.header("X-Request-Id", request_id);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    (request_id, response.text().await.unwrap())
+}
+
+fn is_generated_id(id: &str) -> bool {
+    id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Requests should get a new ID, which the upstream and the client both see, and which error pages
+/// show. IDs from untrusted clients are replaced.
+#[tokio::test]
+async fn test_generated_request_ids() {
+    let (balancebeam, upstream, _config) = setup("[]").await;
+
+    let (request_id, body) = get(&balancebeam, "/page", None).await;
+    assert!(
+        is_generated_id(&request_id),
+        "Bad request ID {}",
+        request_id
+    );
+    assert!(
+        body.contains(&format!("x-request-id: {}\n", request_id)),
+        "The upstream didn't get the request ID:\n{}",
+        body
+    );
+    let (other_id, _) = get(&balancebeam, "/page", None).await;
+    assert_ne!(request_id, other_id);
+
+    log::info!("Sending our own request ID, which isn't trusted");
+    let (request_id, body) = get(&balancebeam, "/page", Some("client-chosen")).await;
+    assert!(
+        is_generated_id(&request_id),
+        "Bad request ID {}",
+        request_id
+    );
+    assert!(!body.contains("client-chosen"), "{}", body);
+
+    log::info!("Getting an error page");
+    let (request_id, body) = get(&balancebeam, "/denied", None).await;
+    assert!(
+        is_generated_id(&request_id),
+        "Bad request ID {}",
+        request_id
+    );
+    assert!(
+        body.contains(&format!("Request ID: {}", request_id)),
+        "{}",
+        body
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Trusted clients' own request IDs should be kept, as long as they are fit to log
+#[tokio::test]
+async fn test_trusted_request_ids() {
+    let (balancebeam, upstream, _config) = setup(r#"["127.0.0.1"]"#).await;
+
+    let (request_id, body) = get(&balancebeam, "/page", Some("edge-4f2a/7")).await;
+    assert_eq!(request_id, "edge-4f2a/7");
+    assert!(body.contains("x-request-id: edge-4f2a/7\n"), "{}", body);
+    let (request_id, body) = get(&balancebeam, "/denied", Some("edge-4f2a/8")).await;
+    assert_eq!(request_id, "edge-4f2a/8");
+    assert!(body.contains("Request ID: edge-4f2a/8"), "{}", body);
+
+    log::info!("Markup in an ID is escaped on HTML error pages");
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/denied", balancebeam.address))
+        .header("X-Request-Id", r#"<b>"edge"&'7'</b>"#)
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], r#"<b>"edge"&'7'</b>"#);
+    let body = response.text().await.unwrap();
+    assert!(
+        body.contains("Request ID: &lt;b&gt;&quot;edge&quot;&amp;&#39;7&#39;&lt;/b&gt;"),
+        "{}",
+        body
+    );
+
+    log::info!("Sending an ID with a space in it");
+    let (request_id, _) = get(&balancebeam, "/page", Some("two words")).await;
+    assert!(
+        is_generated_id(&request_id),
+        "Bad request ID {}",
+        request_id
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -1,4 +1,7 @@
-use rand::Rng;
+use crate::common::free_local_address;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::process::ExitStatus;
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
@@ -11,7 +14,7 @@ pub struct BalanceBeam {
 }
 
 impl BalanceBeam {
-    fn target_bin_path() -> std::path::PathBuf {
+    pub fn target_bin_path() -> std::path::PathBuf {
         let mut path = std::env::current_exe().expect("Could not get current test executable path");
         path.pop();
         path.pop();
@@ -19,26 +22,46 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let mut extra_args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            extra_args.push("--active-health-check-interval".to_string());
+            extra_args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            extra_args.push("--max-requests-per-minute".to_string());
+            extra_args.push(max_requests_per_minute.to_string());
+        }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any other command-line arguments
+    /// through unchanged.
+    #[allow(dead_code)]
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        BalanceBeam::new_at_address(free_local_address(), upstreams, extra_args).await
+    }
+
+    /// Like new_with_args, but listening on the given address (which another balancebeam may be
+    /// listening on too, with --reuse-port)
+    #[allow(dead_code)]
+    pub async fn new_at_address(
+        address: String,
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> BalanceBeam {
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
         cmd.arg("--bind").arg(&address);
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
@@ -84,6 +107,32 @@ impl BalanceBeam {
         BalanceBeam { child, address }
     }
 
+    /// Sends a signal (e.g. SIGHUP to reload the configuration) to the balancebeam process
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: Signal) {
+        let pid = self
+            .child
+            .id()
+            .expect("balancebeam process has already exited");
+        kill(Pid::from_raw(pid as i32), signal).expect("Could not signal balancebeam");
+    }
+
+    /// Returns the balancebeam process's ID (while it is still running)
+    #[allow(dead_code)]
+    pub fn pid(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Waits for the balancebeam process to exit, returning its exit status, or None if it was
+    /// still running after `timeout`
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        tokio::time::timeout(timeout, self.child.wait())
+            .await
+            .ok()
+            .map(|status| status.expect("Could not wait for balancebeam"))
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
@@ -0,0 +1,114 @@
+use crate::common::free_local_address;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// A stand-in for an OpenTelemetry collector, which keeps the spans exported to it over OTLP/HTTP
+/// (JSON)
+#[allow(dead_code)]
+pub struct Collector {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    spans: Arc<Mutex<Vec<serde_json::Value>>>,
+}
+
+#[allow(dead_code)]
+async fn collect(
+    spans: Arc<Mutex<Vec<serde_json::Value>>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let is_export = req.method() == hyper::Method::POST
+        && req.uri().path() == "/v1/traces"
+        && req
+            .headers()
+            .get("content-type")
+            .is_some_and(|value| value == "application/json");
+    if !is_export {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+        return Ok(response);
+    }
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let export: serde_json::Value =
+        serde_json::from_slice(&body).expect("Collector received invalid JSON");
+    spans.lock().unwrap().extend(spans_in_export(&export));
+    Ok(Response::new(Body::from("{}")))
+}
+
+/// Returns the spans in an OTLP ExportTraceServiceRequest
+#[allow(dead_code)]
+pub fn spans_in_export(export: &serde_json::Value) -> Vec<serde_json::Value> {
+    let mut spans = Vec::new();
+    for resource_spans in export["resourceSpans"].as_array().unwrap() {
+        for scope_spans in resource_spans["scopeSpans"].as_array().unwrap() {
+            spans.extend(scope_spans["spans"].as_array().unwrap().iter().cloned());
+        }
+    }
+    spans
+}
+
+impl Collector {
+    #[allow(dead_code)]
+    pub async fn new() -> Collector {
+        let address = free_local_address();
+        let bind_addr = address.parse().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let server_spans = spans.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_spans = server_spans.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| collect(server_spans.clone(), req)))
+                }
+            });
+            let server = hyper::Server::bind(&bind_addr)
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            if let Err(e) = server.await {
+                log::error!("Error in Collector: {}", e);
+            }
+        });
+        Collector {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            address,
+            spans,
+        }
+    }
+
+    /// The URL to export spans to
+    #[allow(dead_code)]
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/v1/traces", self.address)
+    }
+
+    /// Waits (for up to a few seconds) until at least n spans have been exported, and returns
+    /// them
+    #[allow(dead_code)]
+    pub async fn wait_for_spans(&self, n: usize) -> Vec<serde_json::Value> {
+        for _ in 0..50 {
+            let spans = self.spans.lock().unwrap().clone();
+            if spans.len() >= n {
+                return spans;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!(
+            "Expected {} spans, but the collector only got {:?}",
+            n,
+            self.spans.lock().unwrap()
+        );
+    }
+
+    #[allow(dead_code)]
+    pub async fn stop(self) {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task.await.expect("Collector task panicked");
+    }
+}
@@ -1,16 +1,18 @@
+use crate::common::free_local_address;
 use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
 }
 
+#[allow(dead_code)]
 async fn echo(
     server_state: Arc<ServerState>,
     req: Request<Body>,
@@ -32,6 +34,7 @@ async fn echo(
     Ok(Response::new(Body::from(req_as_bytes)))
 }
 
+#[allow(dead_code)]
 pub struct EchoServer {
     shutdown_signal_sender: oneshot::Sender<()>,
     server_task: tokio::task::JoinHandle<()>,
@@ -40,11 +43,12 @@ pub struct EchoServer {
 }
 
 impl EchoServer {
+    #[allow(dead_code)]
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024..65535))).await
+        EchoServer::new_at_address(free_local_address()).await
     }
 
+    #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
@@ -83,6 +87,49 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// An echo server listening on a Unix domain socket at the given path. Its address is
+    /// unix:<path>, as balancebeam takes it.
+    #[allow(dead_code)]
+    pub async fn new_unix(path: &str) -> EchoServer {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |req| {
+                        let server_task_state = server_task_state.clone();
+                        echo(server_task_state, req)
+                    }))
+                }
+            });
+            let incoming = hyper::server::accept::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+            });
+            let server = hyper::Server::builder(incoming)
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            if let Err(e) = server.await {
+                log::error!("Error in EchoServer: {}", e);
+            }
+        });
+
+        EchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: format!("unix:{}", path),
+        }
+    }
 }
 
 #[async_trait]
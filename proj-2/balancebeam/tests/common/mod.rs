@@ -1,14 +1,35 @@
 mod balancebeam;
+mod collector;
 mod echo_server;
 mod error_server;
+mod named_server;
+mod raw_http;
+mod resp_server;
 mod server;
+mod tcp_echo_server;
+mod temp_file;
 
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use collector::{spans_in_export, Collector};
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use named_server::NamedServer;
+#[allow(unused_imports)]
+pub use raw_http::{read_response, send_raw_request};
+#[allow(unused_imports)]
+pub use resp_server::RespServer;
+#[allow(unused_imports)]
 pub use server::Server;
+#[allow(unused_imports)]
+pub use tcp_echo_server::TcpEchoServer;
+#[allow(unused_imports)]
+pub use temp_file::{TempDir, TempFile};
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -20,3 +41,11 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Returns a local address with a port that is currently free. Picking ports at random collides
+/// with other tests (or ephemeral client ports) every so often.
+pub fn free_local_address() -> String {
+    let listener =
+        std::net::TcpListener::bind("127.0.0.1:0").expect("Could not find a free local port");
+    listener.local_addr().unwrap().to_string()
+}
@@ -0,0 +1,106 @@
+use crate::common::free_local_address;
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// A server that answers every request with its own name, optionally after a delay. Useful for
+/// telling which upstream served a request.
+#[allow(dead_code)]
+pub struct NamedServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl NamedServer {
+    #[allow(dead_code)]
+    pub async fn new(name: &str) -> NamedServer {
+        NamedServer::new_at_address(name, Duration::ZERO, free_local_address()).await
+    }
+
+    /// A server that takes `delay` to answer each request
+    #[allow(dead_code)]
+    pub async fn new_slow(name: &str, delay: Duration) -> NamedServer {
+        NamedServer::new_at_address(name, delay, free_local_address()).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(
+        name: &str,
+        delay: Duration,
+        bind_addr_string: String,
+    ) -> NamedServer {
+        let bind_addr = bind_addr_string.parse().unwrap();
+        let name = name.to_string();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            let service = make_service_fn(|_| {
+                let server_task_state = server_task_state.clone();
+                let name = name.clone();
+                async move {
+                    Ok::<_, hyper::Error>(service_fn(move |_req| {
+                        server_task_state
+                            .requests_received
+                            .fetch_add(1, atomic::Ordering::SeqCst);
+                        let name = name.clone();
+                        async move {
+                            tokio::time::sleep(delay).await;
+                            Ok::<_, hyper::Error>(Response::new(Body::from(name)))
+                        }
+                    }))
+                }
+            });
+            let server = hyper::Server::bind(&bind_addr)
+                .serve(service)
+                .with_graceful_shutdown(async {
+                    shutdown_rx.await.ok();
+                });
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in NamedServer: {}", e);
+            }
+        });
+
+        NamedServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for NamedServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("NamedServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
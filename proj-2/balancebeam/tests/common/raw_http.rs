@@ -0,0 +1,55 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Reads one HTTP response (status line, headers and a Content-Length delimited body) from a raw
+/// connection and returns it as text. Used by tests that need to send requests reqwest won't
+/// produce (or can't send, e.g. over a Unix domain socket).
+#[allow(dead_code)]
+pub async fn read_response<S: AsyncRead + Unpin>(conn: &mut S) -> String {
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 512];
+    let headers_len = loop {
+        if let Some(pos) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let bytes_read = conn
+            .read(&mut buffer)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(
+            bytes_read > 0,
+            "balancebeam hung up before sending a response"
+        );
+        response.extend_from_slice(&buffer[..bytes_read]);
+    };
+    let headers = String::from_utf8_lossy(&response[..headers_len]).to_lowercase();
+    let content_length = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map(|value| value.trim().parse::<usize>().unwrap())
+        .unwrap_or(0);
+    while response.len() < headers_len + content_length {
+        let bytes_read = conn
+            .read(&mut buffer)
+            .await
+            .expect("Error reading response body from balancebeam");
+        assert!(
+            bytes_read > 0,
+            "balancebeam hung up in the middle of a response"
+        );
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Opens a connection to the given address, writes the raw bytes and returns the response
+#[allow(dead_code)]
+pub async fn send_raw_request(address: &str, raw_request: &[u8]) -> String {
+    let mut conn = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(raw_request)
+        .await
+        .expect("Could not write to balancebeam");
+    read_response(&mut conn).await
+}
@@ -0,0 +1,146 @@
+use crate::common::free_local_address;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A stand-in for Redis that keeps counters in memory. It understands just enough of the protocol
+/// (RESP) for balancebeam's shared rate limiting: PING, GET, INCR, DECR, EXPIRE (which is
+/// accepted, but ignored) and EVAL of balancebeam's rate limiting script, which it can't run, but
+/// carries out the same way.
+#[allow(dead_code)]
+pub struct RespServer {
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    values: Arc<Mutex<HashMap<String, i64>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl RespServer {
+    #[allow(dead_code)]
+    pub async fn new() -> RespServer {
+        let address = free_local_address();
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let values = Arc::new(Mutex::new(HashMap::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let server_values = values.clone();
+        let server_stopped = stopped.clone();
+        let server_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, server_values.clone(), server_stopped.clone()));
+            }
+        });
+        RespServer {
+            server_task,
+            address,
+            values,
+            stopped,
+        }
+    }
+
+    /// Returns the sum of all counters whose keys contain the given text
+    #[allow(dead_code)]
+    pub fn total(&self, key_part: &str) -> i64 {
+        self.values
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.contains(key_part))
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// Stops accepting connections. Open connections are closed as soon as they send a command.
+    #[allow(dead_code)]
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        self.server_task.abort();
+    }
+}
+
+impl Drop for RespServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[allow(dead_code)]
+async fn serve(
+    stream: TcpStream,
+    values: Arc<Mutex<HashMap<String, i64>>>,
+    stopped: Arc<AtomicBool>,
+) {
+    let mut stream = BufReader::new(stream);
+    while let Some(command) = read_command(&mut stream).await {
+        if stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let reply = {
+            let mut values = values.lock().unwrap();
+            match command
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .as_slice()
+            {
+                ["PING"] => "+PONG\r\n".to_string(),
+                ["GET", key] => match values.get(*key) {
+                    Some(value) => {
+                        let value = value.to_string();
+                        format!("${}\r\n{}\r\n", value.len(), value)
+                    }
+                    None => "$-1\r\n".to_string(),
+                },
+                ["INCR", key] => {
+                    let value = values.entry(key.to_string()).or_insert(0);
+                    *value += 1;
+                    format!(":{}\r\n", value)
+                }
+                ["DECR", key] => {
+                    let value = values.entry(key.to_string()).or_insert(0);
+                    *value -= 1;
+                    format!(":{}\r\n", value)
+                }
+                ["EXPIRE", _, _] => ":1\r\n".to_string(),
+                ["EVAL", _, "2", current_key, previous_key, _, elapsed, capacity] => {
+                    let previous = values.get(*previous_key).copied().unwrap_or(0);
+                    let current = values.entry(current_key.to_string()).or_insert(0);
+                    *current += 1;
+                    let elapsed: f64 = elapsed.parse().unwrap();
+                    let capacity: f64 = capacity.parse().unwrap();
+                    let estimate = previous as f64 * (1.0 - elapsed) + (*current - 1) as f64;
+                    if estimate < capacity {
+                        ":0\r\n".to_string()
+                    } else {
+                        *current -= 1;
+                        ":1\r\n".to_string()
+                    }
+                }
+                _ => "-ERR unknown command\r\n".to_string(),
+            }
+        };
+        if stream.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads a command (an array of bulk strings). Returns None once the client hangs up.
+#[allow(dead_code)]
+async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        stream.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        command.push(String::from_utf8(arg).ok()?);
+    }
+    Some(command)
+}
@@ -1,6 +1,7 @@
 use async_trait::async_trait;
 
 #[async_trait]
+#[allow(dead_code)]
 pub trait Server {
     async fn stop(self: Box<Self>) -> usize;
     fn address(&self) -> String;
@@ -0,0 +1,112 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub connections_received: atomic::AtomicUsize,
+}
+
+/// A server that speaks no protocol at all: it writes back every byte it receives. Used to test
+/// balancebeam's TCP mode. stop() returns the number of connections received, rather than the
+/// number of requests.
+#[allow(dead_code)]
+pub struct TcpEchoServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl TcpEchoServer {
+    #[allow(dead_code)]
+    pub async fn new() -> TcpEchoServer {
+        // Let the OS pick a free port, so that we don't collide with other tests
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("TcpEchoServer could not bind");
+        TcpEchoServer::with_listener(listener)
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(bind_addr_string: String) -> TcpEchoServer {
+        let listener = TcpListener::bind(&bind_addr_string)
+            .await
+            .expect("TcpEchoServer could not bind");
+        TcpEchoServer::with_listener(listener)
+    }
+
+    fn with_listener(listener: TcpListener) -> TcpEchoServer {
+        let bind_addr_string = listener.local_addr().unwrap().to_string();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            connections_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => {
+                        let (mut conn, _) = match accepted {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                log::error!("Error in TcpEchoServer: {}", e);
+                                continue;
+                            }
+                        };
+                        server_task_state
+                            .connections_received
+                            .fetch_add(1, atomic::Ordering::SeqCst);
+                        tokio::spawn(async move {
+                            let mut buffer = [0_u8; 512];
+                            loop {
+                                match conn.read(&mut buffer).await {
+                                    Ok(0) | Err(_) => break,
+                                    Ok(n) => {
+                                        if conn.write_all(&buffer[..n]).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                    }
+                }
+            }
+        });
+
+        TcpEchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for TcpEchoServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("TcpEchoServer server task panicked");
+
+        self.state
+            .connections_received
+            .load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
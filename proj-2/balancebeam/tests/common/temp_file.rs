@@ -0,0 +1,75 @@
+use rand::Rng;
+use std::path::PathBuf;
+
+/// A file in the system temp directory (e.g. a configuration file for balancebeam) that is deleted
+/// when dropped
+#[allow(dead_code)]
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    #[allow(dead_code)]
+    pub fn new(contents: &str) -> TempFile {
+        let mut rng = rand::thread_rng();
+        let path =
+            std::env::temp_dir().join(format!("balancebeam-test-{}", rng.gen_range(0..u64::MAX)));
+        let file = TempFile { path };
+        file.write(contents);
+        file
+    }
+
+    /// Replaces the contents of the file
+    #[allow(dead_code)]
+    pub fn write(&self, contents: &str) {
+        std::fs::write(&self.path, contents).expect("Could not write temp file");
+    }
+
+    #[allow(dead_code)]
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A directory in the system temp directory (e.g. for balancebeam to serve files from) that is
+/// deleted, along with everything in it, when dropped
+#[allow(dead_code)]
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    #[allow(dead_code)]
+    pub fn new() -> TempDir {
+        let mut rng = rand::thread_rng();
+        let path =
+            std::env::temp_dir().join(format!("balancebeam-test-{}", rng.gen_range(0..u64::MAX)));
+        std::fs::create_dir(&path).expect("Could not create temp directory");
+        TempDir { path }
+    }
+
+    /// Writes a file at the given path (relative to the directory), creating directories as needed
+    #[allow(dead_code)]
+    pub fn write(&self, relative_path: &str, contents: &[u8]) {
+        let path = self.path.join(relative_path);
+        std::fs::create_dir_all(path.parent().unwrap()).expect("Could not create temp directory");
+        std::fs::write(path, contents).expect("Could not write temp file");
+    }
+
+    #[allow(dead_code)]
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}